@@ -0,0 +1,233 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+//! Fee computations for bumping fees of unconfirmed transactions, either by
+//! replacing them (BIP125) or by spending their outputs with a child
+//! transaction (CPFP).
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bitcoin::policy::{DEFAULT_INCREMENTAL_RELAY_FEE, DEFAULT_MIN_RELAY_TX_FEE};
+use bitcoin::{Transaction, Txid};
+
+/// Minimal value of the change output; smaller change is added to the fee.
+pub const CHANGE_DUST_LIMIT: u64 = 546;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum BumpError {
+    /// transaction {0} is not known to the wallet.
+    UnknownTransaction(Txid),
+
+    /// transaction does not signal replaceability.
+    NotReplaceable,
+
+    /// transaction has unconfirmed descendants.
+    HasDescendants,
+
+    /// fee of the unconfirmed transaction {0} is unknown.
+    UnknownFee(Txid),
+}
+
+/// Checks that the transaction may be replaced: it must signal replaceability
+/// (BIP125 rule 1) and must not have descendants among the known transactions.
+/// Replacement evicts descendants from the mempool and must pay for them as
+/// well (BIP125 rule 3), which is not supported.
+pub fn check_replaceable(
+    tx: &Transaction,
+    transactions: &BTreeMap<Txid, Transaction>,
+) -> Result<(), BumpError> {
+    if !tx.is_explicitly_rbf() {
+        return Err(BumpError::NotReplaceable);
+    }
+    let txid = tx.txid();
+    if transactions.values().any(|other| {
+        other
+            .input
+            .iter()
+            .any(|txin| txin.previous_output.txid == txid)
+    }) {
+        return Err(BumpError::HasDescendants);
+    }
+    Ok(())
+}
+
+/// Returns fee rate of the replacement transaction: the requested one, raised
+/// to the fee rate of the original transaction plus the incremental relay fee
+/// rate if it is lower.
+pub fn replacement_fee_rate(fee_rate: f32, orig_fee: u64, orig_vsize: usize) -> f32 {
+    let incremental_rate = DEFAULT_INCREMENTAL_RELAY_FEE as f32 / 1000.0;
+    fee_rate.max(orig_fee as f32 / orig_vsize as f32 + incremental_rate)
+}
+
+/// Returns fee of the replacement transaction of the given virtual size. The
+/// replacement must pay more than the original, and the difference must pay
+/// for its own relay with the incremental relay fee (BIP125 rules 3 and 4).
+pub fn replacement_fee(fee_rate: f32, vsize: f32, orig_fee: u64) -> u64 {
+    let incremental_rate = DEFAULT_INCREMENTAL_RELAY_FEE as f32 / 1000.0;
+    ((fee_rate * vsize).ceil() as u64).max(orig_fee + (incremental_rate * vsize).ceil() as u64)
+}
+
+/// Returns the fee of a transaction with the given input value and payments,
+/// adding change below [`CHANGE_DUST_LIMIT`] to the fee; `None` if the inputs
+/// do not cover the payments and the fee.
+pub fn fee_with_change(input_value: u64, payment_value: u64, fee: u64) -> Option<u64> {
+    match input_value.checked_sub(payment_value.checked_add(fee)?)? {
+        change if change < CHANGE_DUST_LIMIT => Some(fee + change),
+        _ => Some(fee),
+    }
+}
+
+/// Returns total virtual size and fee of the unconfirmed transaction together
+/// with all its ancestors from the `mempool` set. Fees of the unconfirmed
+/// transactions are taken from `fees`.
+pub fn unconfirmed_ancestry(
+    txid: Txid,
+    transactions: &BTreeMap<Txid, Transaction>,
+    mempool: &BTreeSet<Txid>,
+    fees: &BTreeMap<Txid, u64>,
+) -> Result<(usize, u64), BumpError> {
+    let mut visited = bset! {};
+    let mut queue = vec![txid];
+    let (mut vsize, mut fee) = (0usize, 0u64);
+    while let Some(txid) = queue.pop() {
+        if !visited.insert(txid) {
+            continue;
+        }
+        let tx = transactions
+            .get(&txid)
+            .ok_or(BumpError::UnknownTransaction(txid))?;
+        vsize += tx.vsize();
+        fee += fees.get(&txid).ok_or(BumpError::UnknownFee(txid))?;
+        queue.extend(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output.txid)
+                .filter(|txid| mempool.contains(txid)),
+        );
+    }
+    Ok((vsize, fee))
+}
+
+/// Returns fee of the child transaction of the given virtual size, which
+/// brings fee rate of the package consisting of the child and its unconfirmed
+/// ancestors to `fee_rate`. The child pays at least the minimum relay fee, so
+/// it is relayed on its own even if the ancestors pay a higher fee rate.
+pub fn cpfp_fee(fee_rate: f32, ancestors_vsize: usize, ancestors_fee: u64, vsize: f32) -> u64 {
+    let package_fee = (fee_rate * (ancestors_vsize as f32 + vsize)).ceil() as u64;
+    package_fee
+        .saturating_sub(ancestors_fee)
+        .max((DEFAULT_MIN_RELAY_TX_FEE as f32 / 1000.0 * vsize).ceil() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness};
+
+    use super::*;
+
+    fn tx(parents: &[Txid], sequence: Sequence, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: parents
+                .iter()
+                .map(|txid| TxIn {
+                    previous_output: OutPoint::new(*txid, 0),
+                    script_sig: Script::new(),
+                    sequence,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn txmap(txs: &[&Transaction]) -> BTreeMap<Txid, Transaction> {
+        txs.iter().map(|tx| (tx.txid(), (*tx).clone())).collect()
+    }
+
+    #[test]
+    fn replaceable() {
+        let parent = tx(&[], Sequence::MAX, 10_000);
+        let rbf = tx(&[parent.txid()], Sequence::ENABLE_RBF_NO_LOCKTIME, 9_000);
+        let final_tx = tx(&[parent.txid()], Sequence::MAX, 9_000);
+        let child = tx(&[rbf.txid()], Sequence::MAX, 8_000);
+
+        assert_eq!(check_replaceable(&rbf, &txmap(&[&parent, &rbf])), Ok(()));
+        assert_eq!(
+            check_replaceable(&final_tx, &txmap(&[&parent, &final_tx])),
+            Err(BumpError::NotReplaceable)
+        );
+        assert_eq!(
+            check_replaceable(&rbf, &txmap(&[&parent, &rbf, &child])),
+            Err(BumpError::HasDescendants)
+        );
+    }
+
+    #[test]
+    fn incremental_relay_fee() {
+        // Original transaction pays 5 sat/vbyte
+        assert_eq!(replacement_fee_rate(3.0, 1000, 200), 6.0);
+        assert_eq!(replacement_fee_rate(10.0, 1000, 200), 10.0);
+        // Fee rate applies when it pays for the relay of the replacement
+        assert_eq!(replacement_fee(6.0, 250.0, 1000), 1500);
+        // The replacement pays the original fee plus its own relay
+        assert_eq!(replacement_fee(5.0, 200.0, 1000), 1200);
+        assert_eq!(replacement_fee(5.5, 100.5, 1000), 1101);
+    }
+
+    #[test]
+    fn dust_change() {
+        assert_eq!(fee_with_change(10_000, 8_000, 1_500), Some(1_500 + 500));
+        assert_eq!(fee_with_change(10_000, 8_000, 1_454), Some(1_454));
+        assert_eq!(fee_with_change(10_000, 8_000, 2_000), Some(2_000));
+        assert_eq!(fee_with_change(10_000, 8_000, 2_001), None);
+        assert_eq!(fee_with_change(10_000, u64::MAX, 1), None);
+    }
+
+    #[test]
+    fn ancestry() {
+        let confirmed = tx(&[], Sequence::MAX, 100_000);
+        let grandparent = tx(&[confirmed.txid()], Sequence::MAX, 90_000);
+        let other = tx(&[], Sequence::MAX, 50_000);
+        let parent = tx(&[grandparent.txid(), other.txid()], Sequence::MAX, 80_000);
+        let transactions = txmap(&[&confirmed, &grandparent, &other, &parent]);
+        let mempool = bset! { grandparent.txid(), parent.txid() };
+        let fees = bmap! { grandparent.txid() => 1_000, parent.txid() => 500 };
+
+        assert_eq!(
+            unconfirmed_ancestry(parent.txid(), &transactions, &mempool, &fees),
+            Ok((parent.vsize() + grandparent.vsize(), 1_500))
+        );
+        assert_eq!(
+            unconfirmed_ancestry(grandparent.txid(), &transactions, &mempool, &fees),
+            Ok((grandparent.vsize(), 1_000))
+        );
+        let fees = bmap! { parent.txid() => 500 };
+        assert_eq!(
+            unconfirmed_ancestry(parent.txid(), &transactions, &mempool, &fees),
+            Err(BumpError::UnknownFee(grandparent.txid()))
+        );
+    }
+
+    #[test]
+    fn cpfp_package_fee_rate() {
+        // Package of 410 vbytes at 10 sat/vbyte, of which ancestors paid 600
+        assert_eq!(cpfp_fee(10.0, 300, 600, 110.0), 3500);
+        // Ancestors already pay more than the package fee rate requires
+        assert_eq!(cpfp_fee(1.0, 300, 10_000, 110.0), 110);
+        assert_eq!(cpfp_fee(2.5, 300, 0, 110.5), 1027);
+    }
+}
@@ -0,0 +1,229 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bitcoin::{BlockHash, BlockHeader, Script, Transaction, Txid};
+use chrono::{DateTime, Utc};
+use electrum_client::{GetHistoryRes, ListUnspentRes};
+use serde_crate::{Deserialize, Serialize};
+
+use super::{FeeEstimates, HeaderChain, Sidecar};
+
+/// Status assigned to the scripts which history has to be re-queried; it never
+/// matches a status reported by a server.
+const STALE_STATUS: Option<[u8; 32]> = Some([0u8; 32]);
+
+/// History entry for a single script, as reported by the blockchain backend.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct HistoryItem {
+    pub txid: Txid,
+    /// Block height; zero or negative values mean mempool transaction.
+    pub height: i32,
+    pub fee: Option<u64>,
+}
+
+impl From<&GetHistoryRes> for HistoryItem {
+    fn from(res: &GetHistoryRes) -> Self {
+        HistoryItem {
+            txid: res.tx_hash,
+            height: res.height,
+            fee: res.fee,
+        }
+    }
+}
+
+impl From<HistoryItem> for GetHistoryRes {
+    fn from(item: HistoryItem) -> Self {
+        GetHistoryRes {
+            height: item.height,
+            tx_hash: item.txid,
+            fee: item.fee,
+        }
+    }
+}
+
+/// Unspent output of a single script, as reported by the blockchain backend.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct UnspentItem {
+    pub txid: Txid,
+    pub vout: u32,
+    pub value: u64,
+    /// Block height; zero value means mempool transaction.
+    pub height: u32,
+}
+
+impl From<&ListUnspentRes> for UnspentItem {
+    fn from(res: &ListUnspentRes) -> Self {
+        UnspentItem {
+            txid: res.tx_hash,
+            vout: res.tx_pos as u32,
+            value: res.value,
+            height: res.height as u32,
+        }
+    }
+}
+
+impl From<UnspentItem> for ListUnspentRes {
+    fn from(item: UnspentItem) -> Self {
+        ListUnspentRes {
+            height: item.height as usize,
+            tx_pos: item.vout as usize,
+            value: item.value,
+            tx_hash: item.txid,
+        }
+    }
+}
+
+/// Last known state of a single wallet script.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct ScriptCache {
+    /// Electrum status hash of the script history; `None` for scripts without
+    /// history.
+    pub status: Option<[u8; 32]>,
+    pub history: Vec<HistoryItem>,
+    pub unspent: Vec<UnspentItem>,
+}
+
+impl ScriptCache {
+    pub fn is_used(&self) -> bool { !self.history.is_empty() }
+}
+
+/// Blockchain data already retrieved for the wallet, which allows to query
+/// the server only for the scripts which status has changed since the last
+/// synchronization.
+#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct SyncCache {
+    pub scripts: BTreeMap<Script, ScriptCache>,
+    pub headers: BTreeMap<u32, BlockHeader>,
+    pub transactions: BTreeMap<Txid, Transaction>,
+    #[serde(default)]
+    pub chain: HeaderChain,
+    /// Transactions which inclusion into a block was verified with a merkle
+    /// proof, together with the hash of that block.
+    #[serde(default)]
+    pub proofs: BTreeMap<Txid, BlockHash>,
+    /// Fee estimations retrieved during the last synchronization.
+    #[serde(default)]
+    pub fees: FeeEstimates,
+    /// Time when the last synchronization was completed.
+    #[serde(default)]
+    pub synced_at: Option<DateTime<Utc>>,
+}
+
+impl Sidecar for SyncCache {
+    const FILE_EXT: &'static str = "cache.json";
+}
+
+impl SyncCache {
+    /// Returns set of all transaction ids referenced by the script histories.
+    pub fn known_txids(&self) -> BTreeSet<Txid> {
+        self.scripts
+            .values()
+            .flat_map(|script| script.history.iter().map(|item| item.txid))
+            .collect()
+    }
+
+    /// Reverts confirmations of the transactions mined at or above the given
+    /// height after a chain reorganization. Affected scripts are marked as
+    /// stale, so their history is re-queried by the next synchronization.
+    pub fn revert(&mut self, height: u32) {
+        self.headers.split_off(&height);
+        for script in self.scripts.values_mut() {
+            let mut stale = false;
+            for item in &mut script.history {
+                if item.height > 0 && item.height as u32 >= height {
+                    item.height = 0;
+                    stale = true;
+                }
+            }
+            for item in &mut script.unspent {
+                if item.height >= height {
+                    item.height = 0;
+                    stale = true;
+                }
+            }
+            if stale {
+                script.status = STALE_STATUS;
+            }
+        }
+    }
+
+    /// Returns fees of the unconfirmed transactions referenced by the script
+    /// histories, for the transactions which fee is known.
+    pub fn mempool_fees(&self) -> BTreeMap<Txid, u64> {
+        self.scripts
+            .values()
+            .flat_map(|script| script.history.iter())
+            .filter(|item| item.height <= 0)
+            .filter_map(|item| item.fee.map(|fee| (item.txid, fee)))
+            .collect()
+    }
+
+    /// Returns unconfirmed transactions referenced by the script histories,
+    /// which fee is not known.
+    pub fn unknown_fee_txids(&self) -> BTreeSet<Txid> {
+        self.scripts
+            .values()
+            .flat_map(|script| script.history.iter())
+            .filter(|item| item.height <= 0 && item.fee.is_none())
+            .map(|item| item.txid)
+            .collect()
+    }
+
+    /// Records the fee of the transaction in the script histories.
+    pub fn set_fee(&mut self, txid: Txid, fee: u64) {
+        for item in self
+            .scripts
+            .values_mut()
+            .flat_map(|script| script.history.iter_mut())
+            .filter(|item| item.txid == txid)
+        {
+            item.fee = Some(fee);
+        }
+    }
+
+    /// Returns confirmed transactions referenced by the script histories,
+    /// together with their block heights.
+    pub fn confirmed_txids(&self) -> BTreeSet<(Txid, u32)> {
+        self.scripts
+            .values()
+            .flat_map(|script| script.history.iter())
+            .filter(|item| item.height > 0)
+            .map(|item| (item.txid, item.height as u32))
+            .collect()
+    }
+
+    /// Checks whether the transaction inclusion into the block at the given
+    /// height was verified.
+    pub fn is_verified(&self, txid: Txid, height: u32) -> bool {
+        match (self.proofs.get(&txid), self.headers.get(&height)) {
+            (Some(block_hash), Some(header)) => *block_hash == header.block_hash(),
+            _ => false,
+        }
+    }
+
+    /// Removes transactions which are not referenced by any of the scripts
+    /// anymore (for instance, replaced mempool transactions).
+    pub fn prune_transactions(&mut self) {
+        let known = self.known_txids();
+        self.transactions.retain(|txid, _| known.contains(txid));
+        self.proofs.retain(|txid, _| known.contains(txid));
+    }
+}
@@ -0,0 +1,520 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+
+use bitcoin::blockdata::constants::max_target;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHash, BlockHeader, Network};
+use serde_crate::{Deserialize, Serialize};
+use wallet::onchain::PublicNetwork;
+
+/// Number of the most recent blocks kept in the header chain. Reorganizations
+/// deeper than this can't be located and revert all confirmations.
+pub const CHAIN_DEPTH: u32 = 144;
+
+/// Number of blocks between difficulty adjustments.
+const DIFFCHANGE_INTERVAL: u32 = 2016;
+
+/// Expected duration of a difficulty adjustment period, in seconds.
+const DIFFCHANGE_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// Proof-of-work limit of the signet; it is higher than for other networks.
+const SIGNET_POW_LIMIT: u32 = 0x1e0377ae;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum ChainError {
+    /// block header at height {0} has invalid proof of work.
+    InvalidPow(u32),
+
+    /// block header at height {0} changes difficulty outside of the
+    /// difficulty adjustment period.
+    UnexpectedDifficulty(u32),
+
+    /// block header at height {0} does not connect to the previous block.
+    Disconnected(u32),
+
+    /// block header at height {0} differs from the one in the verified chain.
+    Mismatch(u32),
+}
+
+/// Checks that the header hash satisfies the difficulty target committed in
+/// the header, and that the target is within the network proof-of-work limit.
+pub fn validate_pow(
+    header: &BlockHeader,
+    height: u32,
+    network: PublicNetwork,
+) -> Result<BlockHash, ChainError> {
+    let limit = match network {
+        PublicNetwork::Signet => BlockHeader::u256_from_compact_target(SIGNET_POW_LIMIT),
+        network => max_target(network.into()),
+    };
+    let target = header.target();
+    if target > limit {
+        return Err(ChainError::InvalidPow(height));
+    }
+    header
+        .validate_pow(&target)
+        .map_err(|_| ChainError::InvalidPow(height))
+}
+
+/// Computes difficulty target of the period following the one which starts
+/// with the `first` and ends with the `last` header, according to the mainnet
+/// retargeting rules.
+pub fn next_bits(first: &BlockHeader, last: &BlockHeader) -> u32 {
+    let timespan = last
+        .time
+        .saturating_sub(first.time)
+        .clamp(DIFFCHANGE_TIMESPAN / 4, DIFFCHANGE_TIMESPAN * 4);
+    let target = last.target().mul_u32(timespan)
+        / Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64).expect("u32 always fits u256");
+    let limit = max_target(Network::Bitcoin);
+    BlockHeader::compact_target_from_u256(if target > limit { &limit } else { &target })
+}
+
+/// Most recent part of the blockchain, consisting of the consecutive block
+/// headers with verified proof of work, which is used to detect chain
+/// reorganizations.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct HeaderChain {
+    headers: BTreeMap<u32, BlockHeader>,
+    /// Difficulty targets of the past mainnet difficulty periods, which were
+    /// verified by linking the period boundary headers to the chain tip with
+    /// the retargeting rules. They are used to check headers older than the
+    /// chain.
+    #[serde(default)]
+    periods: BTreeMap<u32, u32>,
+}
+
+impl HeaderChain {
+    pub fn tip_height(&self) -> Option<u32> { self.headers.keys().last().copied() }
+
+    pub fn header(&self, height: u32) -> Option<&BlockHeader> { self.headers.get(&height) }
+
+    pub fn is_empty(&self) -> bool { self.headers.is_empty() }
+
+    pub fn heights(&self) -> Vec<u32> { self.headers.keys().copied().collect() }
+
+    /// Checks that the header at the given height matches the chain, if the
+    /// chain covers that height.
+    pub fn check(&self, height: u32, header: &BlockHeader) -> Result<(), ChainError> {
+        match self.headers.get(&height) {
+            Some(known) if known.block_hash() != header.block_hash() => {
+                Err(ChainError::Mismatch(height))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether a header at the given height may follow the chain, i.e.
+    /// it references the chain header at the previous height as its parent.
+    /// Returns `None` if the chain does not have a header at the previous
+    /// height.
+    pub fn connects(&self, height: u32, header: &BlockHeader) -> Option<bool> {
+        let prev = self.headers.get(&height.checked_sub(1)?)?;
+        Some(prev.block_hash() == header.prev_blockhash)
+    }
+
+    /// Returns difficulty target which headers at the given height must have,
+    /// if it is known: the target of the chain tip period or of a past period
+    /// verified with [`HeaderChain::verify_periods`].
+    pub fn expected_bits(&self, height: u32) -> Option<u32> {
+        let (tip_height, tip) = self.headers.iter().next_back()?;
+        let period = height / DIFFCHANGE_INTERVAL;
+        match period == tip_height / DIFFCHANGE_INTERVAL {
+            true => Some(tip.bits),
+            false => self.periods.get(&period).copied(),
+        }
+    }
+
+    /// Checks that on mainnet the header has the difficulty target expected at
+    /// its height, if the target is known. Other networks have no fixed
+    /// retargeting rules, so only the proof of work of their headers can be
+    /// checked.
+    pub fn check_bits(
+        &self,
+        height: u32,
+        header: &BlockHeader,
+        network: PublicNetwork,
+    ) -> Result<(), ChainError> {
+        match self.expected_bits(height) {
+            Some(bits) if network == PublicNetwork::Mainnet && bits != header.bits => {
+                Err(ChainError::UnexpectedDifficulty(height))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns heights of the first and last headers of the past difficulty
+    /// periods which have to be verified with [`HeaderChain::verify_periods`]
+    /// before difficulty of the headers at the given heights can be checked:
+    /// all periods starting from the oldest one of the heights up to the chain
+    /// tip period, which were not verified yet.
+    pub fn missing_boundaries(&self, heights: &[u32]) -> Vec<u32> {
+        let tip_period = match self.tip_height() {
+            Some(height) => height / DIFFCHANGE_INTERVAL,
+            None => return vec![],
+        };
+        let first = heights
+            .iter()
+            .map(|height| height / DIFFCHANGE_INTERVAL)
+            .filter(|period| *period < tip_period && !self.periods.contains_key(period))
+            .min();
+        match first {
+            Some(first) => (first..tip_period)
+                .filter(|period| !self.periods.contains_key(period))
+                .flat_map(|period| {
+                    let start = period * DIFFCHANGE_INTERVAL;
+                    [start, start + DIFFCHANGE_INTERVAL - 1]
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Verifies difficulty targets of the past difficulty periods, going back
+    /// from the chain tip as long as the first and last headers of the periods
+    /// are provided: the headers of each period must have the target from
+    /// which the retargeting rules produce the target of the next period.
+    /// Verified targets are kept in the chain.
+    ///
+    /// This links old headers to the verified chain without retrieving all
+    /// headers between them, so a forged old header has to have at least the
+    /// difficulty which the blockchain had at its height.
+    pub fn verify_periods(
+        &mut self,
+        boundaries: &BTreeMap<u32, BlockHeader>,
+        network: PublicNetwork,
+    ) -> Result<(), ChainError> {
+        let (tip_height, tip) = match self.headers.iter().next_back() {
+            Some((height, header)) => (*height, header),
+            None => return Ok(()),
+        };
+        let mut next = tip.bits;
+        for period in (0..tip_height / DIFFCHANGE_INTERVAL).rev() {
+            if let Some(bits) = self.periods.get(&period) {
+                next = *bits;
+                continue;
+            }
+            let start = period * DIFFCHANGE_INTERVAL;
+            let end = start + DIFFCHANGE_INTERVAL - 1;
+            let (first, last) = match (boundaries.get(&start), boundaries.get(&end)) {
+                (Some(first), Some(last)) => (first, last),
+                _ => break,
+            };
+            validate_pow(first, start, network)?;
+            validate_pow(last, end, network)?;
+            self.check(start, first)?;
+            self.check(end, last)?;
+            if first.bits != last.bits || next_bits(first, last) != next {
+                return Err(ChainError::UnexpectedDifficulty(end));
+            }
+            self.periods.insert(period, first.bits);
+            next = first.bits;
+        }
+        Ok(())
+    }
+
+    /// Compares the chain with the headers at the same heights, returning the
+    /// height of the first chain header which was replaced, or `None` if the
+    /// chain matches. If the very first chain header was replaced, the fork
+    /// point is below the chain and zero height is returned.
+    pub fn fork_height(
+        &self,
+        headers: impl IntoIterator<Item = (u32, BlockHeader)>,
+    ) -> Option<u32> {
+        let first = *self.headers.keys().next()?;
+        let height = headers
+            .into_iter()
+            .filter(|(height, header)| self.check(*height, header).is_err())
+            .map(|(height, _)| height)
+            .min()?;
+        Some(if height == first { 0 } else { height })
+    }
+
+    /// Replaces chain headers starting from the height of the first of the
+    /// provided headers. The headers must be consecutive and connect to the
+    /// chain, unless the chain has no header right before them; in the latter
+    /// case the chain is restarted from the provided headers.
+    ///
+    /// Returns height of the first header which was replaced, if any.
+    pub fn extend(
+        &mut self,
+        start: u32,
+        headers: Vec<BlockHeader>,
+        network: PublicNetwork,
+    ) -> Result<Option<u32>, ChainError> {
+        let mut prev = start
+            .checked_sub(1)
+            .and_then(|height| self.headers.get(&height))
+            .copied();
+        for (height, header) in (start..).zip(&headers) {
+            validate_pow(header, height, network)?;
+            if let Some(prev) = prev {
+                if header.prev_blockhash != prev.block_hash() {
+                    return Err(ChainError::Disconnected(height));
+                }
+                if network == PublicNetwork::Mainnet
+                    && height % DIFFCHANGE_INTERVAL != 0
+                    && header.bits != prev.bits
+                {
+                    return Err(ChainError::UnexpectedDifficulty(height));
+                }
+            }
+            prev = Some(*header);
+        }
+
+        if !self.headers.contains_key(&start.saturating_sub(1)) {
+            self.headers.clear();
+        }
+        let replaced = self
+            .headers
+            .range(start..)
+            .zip(&headers)
+            .find(|((_, known), new)| known.block_hash() != new.block_hash())
+            .map(|((height, _), _)| *height)
+            .or_else(|| {
+                // The chain became shorter
+                let end = start + headers.len() as u32;
+                self.headers.range(end..).next().map(|(height, _)| *height)
+            });
+        self.headers.split_off(&start);
+        self.headers.extend((start..).zip(headers));
+
+        if let Some(tip) = self.tip_height() {
+            self.headers = self.headers.split_off(&tip.saturating_sub(CHAIN_DEPTH - 1));
+        }
+        Ok(replaced)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+
+    use super::*;
+
+    /// Headers of the first mainnet blocks.
+    const HEADERS: [&str; 3] = [
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27a\
+         c72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bb\
+         be680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a\
+         5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+    ];
+
+    /// Header with valid proof of work competing with the mainnet block 2.
+    const FORK_2: &str = "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000\
+                          2222222222222222222222222222222222222222222222222222222222222222b2bc6649\
+                          ffff001d30357e63";
+
+    /// Header with valid proof of work following the mainnet block 2, which
+    /// has a lower difficulty target than its parent.
+    const EASY_3: &str = "01000000bddd99ccfda39da1b108ce1a5d70038d0a967bacb68b6b63065f626a00000000\
+                          333333333333333333333333333333333333333333333333333333333333333309bf6649\
+                          feff001d0f9b142b";
+
+    fn header(hex: &str) -> BlockHeader { deserialize(&Vec::from_hex(hex).unwrap()).unwrap() }
+
+    fn headers() -> Vec<BlockHeader> { HEADERS.iter().map(|hex| header(hex)).collect() }
+
+    fn chain() -> HeaderChain {
+        let mut chain = HeaderChain::default();
+        assert_eq!(chain.extend(0, headers(), PublicNetwork::Mainnet), Ok(None));
+        chain
+    }
+
+    #[test]
+    fn linked_extension() {
+        let headers = headers();
+        let mut chain = HeaderChain::default();
+        assert_eq!(
+            chain.extend(0, headers[..1].to_vec(), PublicNetwork::Mainnet),
+            Ok(None)
+        );
+        assert_eq!(chain.connects(1, &headers[1]), Some(true));
+        assert_eq!(chain.connects(1, &headers[2]), Some(false));
+        assert_eq!(
+            chain.extend(1, headers[1..].to_vec(), PublicNetwork::Mainnet),
+            Ok(None)
+        );
+        assert_eq!(chain.tip_height(), Some(2));
+        assert_eq!(chain.heights(), vec![0, 1, 2]);
+        assert_eq!(chain.header(2), Some(&headers[2]));
+        assert_eq!(chain.check(1, &headers[1]), Ok(()));
+        assert_eq!(chain.check(1, &headers[2]), Err(ChainError::Mismatch(1)));
+        // Headers must be linked to each other and to the chain
+        assert_eq!(
+            chain.extend(1, vec![headers[2]], PublicNetwork::Mainnet),
+            Err(ChainError::Disconnected(1))
+        );
+        assert_eq!(
+            HeaderChain::default().extend(0, vec![headers[0], headers[2]], PublicNetwork::Mainnet),
+            Err(ChainError::Disconnected(1))
+        );
+        assert_eq!(chain, self::chain());
+    }
+
+    #[test]
+    fn fork_replaces_tip() {
+        let fork = header(FORK_2);
+        let mut chain = chain();
+        assert_eq!(chain.connects(2, &fork), Some(true));
+        assert_eq!(chain.fork_height([(1, headers()[1]), (2, fork)]), Some(2));
+        assert_eq!(
+            chain.fork_height([(1, headers()[1]), (2, headers()[2])]),
+            None
+        );
+        assert_eq!(
+            chain.extend(2, vec![fork], PublicNetwork::Mainnet),
+            Ok(Some(2))
+        );
+        assert_eq!(chain.tip_height(), Some(2));
+        assert_eq!(chain.header(2), Some(&fork));
+        // Switching back to the original chain replaces the tip again
+        assert_eq!(
+            chain.extend(1, headers()[1..].to_vec(), PublicNetwork::Mainnet),
+            Ok(Some(2))
+        );
+        assert_eq!(chain, self::chain());
+        // Shorter chain replaces the headers it does not have
+        assert_eq!(
+            chain.extend(1, headers()[1..2].to_vec(), PublicNetwork::Mainnet),
+            Ok(Some(2))
+        );
+        assert_eq!(chain.tip_height(), Some(1));
+        // Replacement of the first chain header means the fork point is below
+        // the chain
+        assert_eq!(chain.fork_height([(0, headers()[1])]), Some(0));
+    }
+
+    #[test]
+    fn invalid_pow() {
+        let mut headers = headers();
+        headers[2].nonce ^= 1;
+        assert_eq!(
+            validate_pow(&headers[2], 2, PublicNetwork::Mainnet),
+            Err(ChainError::InvalidPow(2))
+        );
+        let mut chain = HeaderChain::default();
+        assert_eq!(
+            chain.extend(0, headers.clone(), PublicNetwork::Mainnet),
+            Err(ChainError::InvalidPow(2))
+        );
+        assert!(chain.is_empty());
+        // Target above the proof-of-work limit of the network
+        headers[2].bits = 0x1d01ffff;
+        assert_eq!(
+            validate_pow(&headers[2], 2, PublicNetwork::Mainnet),
+            Err(ChainError::InvalidPow(2))
+        );
+        assert_eq!(
+            validate_pow(&headers[1], 1, PublicNetwork::Mainnet),
+            Ok(headers[1].block_hash())
+        );
+    }
+
+    #[test]
+    fn wrong_difficulty() {
+        let easy = header(EASY_3);
+        assert_eq!(
+            validate_pow(&easy, 3, PublicNetwork::Mainnet),
+            Ok(easy.block_hash())
+        );
+        let mut chain = chain();
+        assert_eq!(chain.connects(3, &easy), Some(true));
+        assert_eq!(
+            chain.extend(3, vec![easy], PublicNetwork::Mainnet),
+            Err(ChainError::UnexpectedDifficulty(3))
+        );
+        assert_eq!(chain, self::chain());
+        // Difficulty of testnets may change at any block
+        let mut chain = HeaderChain::default();
+        assert_eq!(chain.extend(0, headers(), PublicNetwork::Testnet), Ok(None));
+        assert_eq!(
+            chain.extend(3, vec![easy], PublicNetwork::Testnet),
+            Ok(None)
+        );
+        assert_eq!(chain.tip_height(), Some(3));
+    }
+
+    #[test]
+    fn retarget() {
+        let mut first = headers()[0];
+        let mut last = first;
+        first.bits = 0x1b0404cb;
+        last.bits = 0x1b0404cb;
+        last.time = first.time + DIFFCHANGE_TIMESPAN;
+        assert_eq!(next_bits(&first, &last), 0x1b0404cb);
+        last.time = first.time + DIFFCHANGE_TIMESPAN / 2;
+        assert_eq!(next_bits(&first, &last), 0x1b020265);
+        // Adjustment is limited to the factor of four
+        last.time = first.time;
+        assert_eq!(next_bits(&first, &last), 0x1b010132);
+        last.time = first.time + DIFFCHANGE_TIMESPAN * 10;
+        assert_eq!(next_bits(&first, &last), 0x1b10132c);
+        // Target never exceeds the proof-of-work limit
+        first.bits = 0x1d00ffff;
+        last.bits = 0x1d00ffff;
+        assert_eq!(next_bits(&first, &last), 0x1d00ffff);
+    }
+
+    #[test]
+    fn old_header_difficulty() {
+        let mut chain = HeaderChain::default();
+        let start = DIFFCHANGE_INTERVAL * 2;
+        assert_eq!(
+            chain.extend(start, headers(), PublicNetwork::Mainnet),
+            Ok(None)
+        );
+        assert_eq!(chain.expected_bits(start + 5), Some(0x1d00ffff));
+        assert_eq!(chain.expected_bits(10), None);
+        assert_eq!(chain.missing_boundaries(&[start + 1]), Vec::<u32>::new());
+        assert_eq!(
+            chain.missing_boundaries(&[10, DIFFCHANGE_INTERVAL + 10]),
+            vec![
+                0,
+                DIFFCHANGE_INTERVAL - 1,
+                DIFFCHANGE_INTERVAL,
+                DIFFCHANGE_INTERVAL * 2 - 1
+            ]
+        );
+        assert_eq!(chain.missing_boundaries(&[DIFFCHANGE_INTERVAL + 10]), vec![
+            DIFFCHANGE_INTERVAL,
+            DIFFCHANGE_INTERVAL * 2 - 1
+        ]);
+
+        let mut header = headers()[1];
+        assert_eq!(
+            chain.check_bits(start + 1, &header, PublicNetwork::Mainnet),
+            Ok(())
+        );
+        // Difficulty of unverified periods is not known
+        header.bits = 0x1c00ffff;
+        assert_eq!(
+            chain.check_bits(10, &header, PublicNetwork::Mainnet),
+            Ok(())
+        );
+        assert_eq!(
+            chain.check_bits(start + 1, &header, PublicNetwork::Mainnet),
+            Err(ChainError::UnexpectedDifficulty(start + 1))
+        );
+        assert_eq!(
+            chain.check_bits(start + 1, &header, PublicNetwork::Testnet),
+            Ok(())
+        );
+    }
+}
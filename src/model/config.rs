@@ -0,0 +1,81 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::path::PathBuf;
+use std::{fs, io};
+
+use gtk::glib;
+use serde_crate::{Deserialize, Serialize};
+
+use super::sidecar::{self, Error};
+
+pub const DEFAULT_PROXY_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PROXY_PORT: u16 = 9050;
+
+/// SOCKS5 proxy used for all network connections made by the application.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct ProxyConfig {
+    pub enabled: bool,
+
+    pub host: String,
+
+    pub port: u16,
+
+    /// Whether each wallet should use its own Tor circuits. Tor isolates
+    /// streams which use different SOCKS5 credentials, so this is achieved by
+    /// providing a wallet-specific user name to the proxy.
+    pub isolation: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            enabled: false,
+            host: DEFAULT_PROXY_HOST.to_owned(),
+            port: DEFAULT_PROXY_PORT,
+            isolation: true,
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn addr(&self) -> String { format!("{}:{}", self.host, self.port) }
+}
+
+/// Application settings shared by all wallets.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct AppConfig {
+    pub proxy: ProxyConfig,
+}
+
+impl AppConfig {
+    pub fn path() -> PathBuf {
+        glib::user_config_dir()
+            .join("mycitadel")
+            .join("config.json")
+    }
+
+    /// Reads application settings; returns default settings if they were never
+    /// saved.
+    pub fn read() -> Result<Self, Error> {
+        match fs::File::open(Self::path()) {
+            Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self) -> Result<(), Error> { sidecar::write_atomic(&Self::path(), self) }
+}
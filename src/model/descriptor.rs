@@ -0,0 +1,71 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+use std::iter;
+use std::ops::RangeInclusive;
+
+use bitcoin::secp256k1::SECP256K1;
+use bitcoin_scripts::PubkeyScript;
+use bpro::{AddressSource, WalletSettings};
+use miniscript::Descriptor;
+use wallet::descriptors::derive::Descriptor as DeriveScript;
+use wallet::hd::{DerivationAccount, DeriveError, SegmentIndexes, UnhardenedIndex};
+
+/// Returns descriptors for all descriptor classes used by the wallet. The
+/// first descriptor is the primary one, which is used for new addresses.
+pub fn wallet_descriptors(
+    settings: &WalletSettings,
+) -> Result<Vec<Descriptor<DerivationAccount>>, miniscript::Error> {
+    let (primary, other) = settings.descriptors_all()?;
+    Ok(iter::once(primary).chain(other).collect())
+}
+
+/// Derives script pubkey at the given index of the receiving or change
+/// terminal of the descriptor.
+///
+/// Multi-class wallets use additional wildcard derivation steps in front of
+/// the change and index steps; these are always derived at zero index. The
+/// wallet checks that change outputs of the PSBTs it constructs match the
+/// scripts derived here.
+pub fn derive_script(
+    descriptor: &Descriptor<DerivationAccount>,
+    change: bool,
+    index: UnhardenedIndex,
+) -> Result<PubkeyScript, DeriveError> {
+    let len = DeriveScript::derive_pattern_len(descriptor)?;
+    let pattern = iter::repeat(UnhardenedIndex::zero())
+        .take(len.saturating_sub(2))
+        .chain([UnhardenedIndex::from(change as u8), index])
+        .collect::<Vec<_>>();
+    let address = DeriveScript::address(descriptor, SECP256K1, pattern, false)?;
+    Ok(address.script_pubkey())
+}
+
+/// Derives script pubkeys for a range of indexes of the receiving or change
+/// terminal of the descriptor.
+pub fn script_pubkeys(
+    descriptor: &Descriptor<DerivationAccount>,
+    change: bool,
+    indexes: RangeInclusive<u16>,
+) -> Result<BTreeMap<UnhardenedIndex, PubkeyScript>, DeriveError> {
+    indexes
+        .map(UnhardenedIndex::from)
+        .map(|index| Ok((index, derive_script(descriptor, change, index)?)))
+        .collect()
+}
+
+/// Checks whether the address was derived from the descriptor.
+pub fn descriptor_owns(descriptor: &Descriptor<DerivationAccount>, source: &AddressSource) -> bool {
+    derive_script(descriptor, source.change.first_index() == 1, source.index)
+        .map(|script| script == source.address.script_pubkey())
+        .unwrap_or_default()
+}
@@ -0,0 +1,221 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeSet;
+
+use serde_crate::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub enum Exchange {
+    #[display("Kraken")]
+    Kraken,
+
+    #[display("Bitstamp")]
+    Bitstamp,
+
+    #[display("Coinbase")]
+    Coinbase,
+
+    #[display("CoinGecko")]
+    CoinGecko,
+
+    #[display("Bitfinex")]
+    Bitfinex,
+}
+
+impl Default for Exchange {
+    fn default() -> Self { Exchange::Kraken }
+}
+
+impl Exchange {
+    pub const ALL: [Exchange; 5] = [
+        Exchange::Kraken,
+        Exchange::Bitstamp,
+        Exchange::Coinbase,
+        Exchange::CoinGecko,
+        Exchange::Bitfinex,
+    ];
+
+    /// Returns this exchange followed by all other exchanges, which are used
+    /// when this one fails.
+    pub fn with_fallbacks(self) -> impl Iterator<Item = Exchange> {
+        let pos = Exchange::ALL
+            .iter()
+            .position(|exchange| *exchange == self)
+            .unwrap_or_default();
+        Exchange::ALL
+            .into_iter()
+            .cycle()
+            .skip(pos)
+            .take(Exchange::ALL.len())
+    }
+}
+
+/// Source of the exchange rate.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", rename_all = "lowercase")]
+pub enum RateSource {
+    /// Rate from a single exchange; other exchanges are used as a fallback
+    /// when it is not available.
+    Exchange(Exchange),
+
+    /// Median of the rates from several exchanges, which are queried in
+    /// parallel. Rates deviating from the median by more than `max_deviation`
+    /// basis points are rejected as outliers. Empty set of exchanges means all
+    /// known exchanges.
+    Median {
+        exchanges: BTreeSet<Exchange>,
+        max_deviation: u16,
+    },
+}
+
+/// Fiat currency, identified by its ISO 4217 code.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+#[display(Debug)]
+pub enum Fiat {
+    USD,
+    EUR,
+    CHF,
+    GBP,
+    JPY,
+    CAD,
+    AUD,
+    NZD,
+    CNY,
+    HKD,
+    SGD,
+    TWD,
+    KRW,
+    INR,
+    IDR,
+    MYR,
+    PHP,
+    THB,
+    VND,
+    AED,
+    SAR,
+    ILS,
+    TRY,
+    ZAR,
+    NGN,
+    BRL,
+    MXN,
+    ARS,
+    CLP,
+    SEK,
+    NOK,
+    DKK,
+    PLN,
+    CZK,
+    HUF,
+    UAH,
+}
+
+impl Default for Fiat {
+    fn default() -> Self { Fiat::CHF }
+}
+
+impl Fiat {
+    pub const ALL: [Fiat; 36] = [
+        Fiat::USD,
+        Fiat::EUR,
+        Fiat::CHF,
+        Fiat::GBP,
+        Fiat::JPY,
+        Fiat::CAD,
+        Fiat::AUD,
+        Fiat::NZD,
+        Fiat::CNY,
+        Fiat::HKD,
+        Fiat::SGD,
+        Fiat::TWD,
+        Fiat::KRW,
+        Fiat::INR,
+        Fiat::IDR,
+        Fiat::MYR,
+        Fiat::PHP,
+        Fiat::THB,
+        Fiat::VND,
+        Fiat::AED,
+        Fiat::SAR,
+        Fiat::ILS,
+        Fiat::TRY,
+        Fiat::ZAR,
+        Fiat::NGN,
+        Fiat::BRL,
+        Fiat::MXN,
+        Fiat::ARS,
+        Fiat::CLP,
+        Fiat::SEK,
+        Fiat::NOK,
+        Fiat::DKK,
+        Fiat::PLN,
+        Fiat::CZK,
+        Fiat::HUF,
+        Fiat::UAH,
+    ];
+
+    /// Returns currency symbol and name.
+    fn info(self) -> (&'static str, &'static str) {
+        match self {
+            Fiat::USD => ("$", "US dollar"),
+            Fiat::EUR => ("€", "Euro"),
+            Fiat::CHF => ("₣", "Swiss franc"),
+            Fiat::GBP => ("£", "British pound"),
+            Fiat::JPY => ("¥", "Japanese yen"),
+            Fiat::CAD => ("C$", "Canadian dollar"),
+            Fiat::AUD => ("A$", "Australian dollar"),
+            Fiat::NZD => ("NZ$", "New Zealand dollar"),
+            Fiat::CNY => ("CN¥", "Chinese yuan"),
+            Fiat::HKD => ("HK$", "Hong Kong dollar"),
+            Fiat::SGD => ("S$", "Singapore dollar"),
+            Fiat::TWD => ("NT$", "New Taiwan dollar"),
+            Fiat::KRW => ("₩", "South Korean won"),
+            Fiat::INR => ("₹", "Indian rupee"),
+            Fiat::IDR => ("Rp", "Indonesian rupiah"),
+            Fiat::MYR => ("RM", "Malaysian ringgit"),
+            Fiat::PHP => ("₱", "Philippine peso"),
+            Fiat::THB => ("฿", "Thai baht"),
+            Fiat::VND => ("₫", "Vietnamese dong"),
+            Fiat::AED => ("AED", "UAE dirham"),
+            Fiat::SAR => ("SAR", "Saudi riyal"),
+            Fiat::ILS => ("₪", "Israeli shekel"),
+            Fiat::TRY => ("₺", "Turkish lira"),
+            Fiat::ZAR => ("R", "South African rand"),
+            Fiat::NGN => ("₦", "Nigerian naira"),
+            Fiat::BRL => ("R$", "Brazilian real"),
+            Fiat::MXN => ("Mex$", "Mexican peso"),
+            Fiat::ARS => ("ARS", "Argentine peso"),
+            Fiat::CLP => ("CLP", "Chilean peso"),
+            Fiat::SEK => ("kr", "Swedish krona"),
+            Fiat::NOK => ("kr", "Norwegian krone"),
+            Fiat::DKK => ("kr", "Danish krone"),
+            Fiat::PLN => ("zł", "Polish złoty"),
+            Fiat::CZK => ("Kč", "Czech koruna"),
+            Fiat::HUF => ("Ft", "Hungarian forint"),
+            Fiat::UAH => ("₴", "Ukrainian hryvnia"),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str { self.info().0 }
+
+    pub fn name(self) -> &'static str { self.info().1 }
+
+    /// ISO 4217 currency code.
+    pub fn fiat(self) -> String { self.to_string() }
+
+    pub fn pair(self) -> String { format!("{}/BTC", self) }
+}
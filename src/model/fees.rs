@@ -0,0 +1,182 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+
+use serde_crate::{Deserialize, Serialize};
+
+/// Confirmation targets, in blocks, for which fee rates are estimated.
+pub const FEE_TARGETS: [usize; 8] = [1, 2, 3, 6, 12, 24, 144, 1008];
+
+/// Maximal virtual size of transactions fitting into a block.
+const BLOCK_VSIZE: u64 = 1_000_000;
+
+/// Fee rate estimations and the state of the mempool, as reported by the
+/// blockchain backend. All fee rates are in sat/vbyte.
+#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct FeeEstimates {
+    /// Estimated fee rates by the confirmation target. Targets which the
+    /// backend was unable to estimate are absent.
+    pub targets: BTreeMap<u32, f32>,
+
+    /// Mempool fee histogram: total virtual size of the mempool transactions
+    /// paying each of the fee rates, in descending order of the fee rates.
+    pub histogram: Vec<(f32, u64)>,
+}
+
+impl FeeEstimates {
+    /// Constructs estimates from the values returned by the backend, where
+    /// fee rates for the `targets` are given in BTC/kvbyte, and negative values
+    /// mean absence of an estimate.
+    pub fn with(targets: &[usize], estimates: Vec<f64>, histogram: Vec<(f64, u64)>) -> Self {
+        FeeEstimates {
+            targets: targets
+                .iter()
+                .zip(estimates)
+                .filter(|(_, rate)| *rate > 0.0)
+                .map(|(target, rate)| (*target as u32, (rate * 100_000.0) as f32))
+                .collect(),
+            histogram: histogram
+                .into_iter()
+                .map(|(rate, vsize)| (rate as f32, vsize))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.targets.is_empty() }
+
+    /// Returns estimated fee rate for the confirmation target; if it is not
+    /// known, uses the estimate for the closest longer target.
+    pub fn fee_rate(&self, target: u32) -> Option<f32> {
+        self.targets
+            .range(target..)
+            .next()
+            .or_else(|| self.targets.iter().next_back())
+            .map(|(_, rate)| *rate)
+    }
+
+    /// Returns fee rate estimations for 1, 2 and 3 blocks in BTC/kvbyte, as
+    /// they are kept in the wallet ephemerals.
+    pub fn ephemerals(&self) -> Option<(f64, f64, f64)> {
+        let rate = |target| self.fee_rate(target).map(|rate| rate as f64 / 100_000.0);
+        Some((rate(1)?, rate(2)?, rate(3)?))
+    }
+
+    /// Returns the expected number of blocks before a transaction paying the
+    /// given fee rate gets confirmed.
+    ///
+    /// The estimate is the higher of the one made from the fee estimations and
+    /// the one made from the mempool contents: it is better to overestimate
+    /// the waiting time than to promise a confirmation which does not happen.
+    /// The mempool-based estimate does not account for transactions which will
+    /// arrive into the mempool later, so it is used alone only if the fee rate
+    /// is below all of the estimations.
+    pub fn expected_blocks(&self, fee_rate: f32) -> Option<u32> {
+        let by_estimate = self
+            .targets
+            .iter()
+            .find(|(_, rate)| fee_rate >= **rate)
+            .map(|(target, _)| *target);
+        let by_mempool = if self.histogram.is_empty() {
+            None
+        } else {
+            let ahead = self
+                .histogram
+                .iter()
+                .filter(|(rate, _)| *rate >= fee_rate)
+                .map(|(_, vsize)| vsize)
+                .sum::<u64>();
+            Some((ahead / BLOCK_VSIZE) as u32 + 1)
+        };
+        match (by_estimate, by_mempool) {
+            (Some(by_estimate), Some(by_mempool)) => Some(by_estimate.max(by_mempool)),
+            (by_estimate, by_mempool) => by_estimate.or(by_mempool),
+        }
+    }
+}
+
+/// Formats expected confirmation time for the number of blocks, assuming ten
+/// minutes per block.
+pub fn format_blocks_time(blocks: u32) -> String {
+    match blocks as u64 * 10 {
+        minutes if minutes < 60 => format!("~{} minutes", minutes),
+        minutes if minutes < 60 * 24 => format!("~{} hour(s)", minutes / 60),
+        minutes if minutes < 60 * 24 * 7 => format!("~{} day(s)", minutes / 60 / 24),
+        minutes => format!("~{} week(s)", minutes / 60 / 24 / 7),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn estimates() -> FeeEstimates {
+        FeeEstimates {
+            targets: bmap! { 1 => 20.0, 3 => 10.0, 6 => 5.0 },
+            histogram: vec![(30.0, 3_000_000), (15.0, 1_000_000), (8.0, 2_000_000)],
+        }
+    }
+
+    #[test]
+    fn with() {
+        let estimates = FeeEstimates::with(&[1, 2, 3], vec![0.0002, -1.0, 0.0001], vec![
+            (12.5, 1000),
+            (1.0, 50),
+        ]);
+        assert_eq!(estimates.targets, bmap! { 1 => 20.0, 3 => 10.0 });
+        assert_eq!(estimates.histogram, vec![(12.5, 1000), (1.0, 50)]);
+        assert!(!estimates.is_empty());
+        assert!(FeeEstimates::with(&[1], vec![-1.0], vec![]).is_empty());
+    }
+
+    #[test]
+    fn fee_rate() {
+        let estimates = estimates();
+        assert_eq!(estimates.fee_rate(1), Some(20.0));
+        // Missing target uses the closest longer one
+        assert_eq!(estimates.fee_rate(2), Some(10.0));
+        // Targets longer than all the known ones use the longest
+        assert_eq!(estimates.fee_rate(144), Some(5.0));
+        assert_eq!(FeeEstimates::default().fee_rate(1), None);
+    }
+
+    #[test]
+    fn expected_blocks() {
+        let mut estimates = estimates();
+        // 3 MvB of the mempool pays more, so the mempool estimate is longer
+        assert_eq!(estimates.expected_blocks(25.0), Some(4));
+        assert_eq!(estimates.expected_blocks(12.0), Some(5));
+        // Fee rate below all the estimations
+        assert_eq!(estimates.expected_blocks(4.0), Some(7));
+
+        estimates.histogram = vec![(30.0, 100_000)];
+        assert_eq!(estimates.expected_blocks(25.0), Some(1));
+        assert_eq!(estimates.expected_blocks(9.0), Some(6));
+        assert_eq!(estimates.expected_blocks(4.0), Some(1));
+
+        estimates.histogram = vec![];
+        assert_eq!(estimates.expected_blocks(9.0), Some(6));
+        assert_eq!(estimates.expected_blocks(4.0), None);
+        assert_eq!(FeeEstimates::default().expected_blocks(10.0), None);
+    }
+
+    #[test]
+    fn blocks_time() {
+        assert_eq!(format_blocks_time(1), "~10 minutes");
+        assert_eq!(format_blocks_time(5), "~50 minutes");
+        assert_eq!(format_blocks_time(6), "~1 hour(s)");
+        assert_eq!(format_blocks_time(143), "~23 hour(s)");
+        assert_eq!(format_blocks_time(144), "~1 day(s)");
+        assert_eq!(format_blocks_time(1008), "~1 week(s)");
+    }
+}
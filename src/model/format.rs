@@ -10,6 +10,7 @@
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 use bpro::{OnchainStatus, OnchainTxid};
+use chrono::{DateTime, Local, Utc};
 use gtk::prelude::LabelExt;
 use gtk::Label;
 
@@ -29,6 +30,27 @@ impl FormatDate for OnchainTxid {
     }
 }
 
+/// Formats age of the data, like "3 day(s) ago".
+pub fn format_age(time: DateTime<Utc>) -> String {
+    let age = Utc::now().signed_duration_since(time);
+    match (age.num_days(), age.num_hours(), age.num_minutes()) {
+        (days, ..) if days > 0 => format!("{} day(s) ago", days),
+        (_, hours, _) if hours > 0 => format!("{} hour(s) ago", hours),
+        (.., minutes) if minutes > 0 => format!("{} minute(s) ago", minutes),
+        _ => s!("just now"),
+    }
+}
+
+/// Formats time of the data retrieval together with the data age, like
+/// "2022-10-01 12:00, 3 day(s) ago".
+pub fn format_data_age(time: DateTime<Utc>) -> String {
+    format!(
+        "{}, {}",
+        time.with_timezone(&Local).format("%F %H:%M"),
+        format_age(time)
+    )
+}
+
 pub fn display_accounting_amount(
     amount: u64,
     precision: impl Into<u8>,
@@ -9,8 +9,29 @@
 // a copy of the AGPL-3.0 License along with this software. If not, see
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+pub mod bump;
+mod cache;
+mod chain;
+mod config;
+mod descriptor;
+mod exchange;
+mod fees;
 mod format;
+mod prefs;
+pub mod sidecar;
 mod ui;
 
-pub use format::{display_accounting_amount, FormatDate};
+pub use bump::CHANGE_DUST_LIMIT;
+pub use cache::{HistoryItem, ScriptCache, SyncCache, UnspentItem};
+pub use chain::{validate_pow, ChainError, HeaderChain, CHAIN_DEPTH};
+pub use config::{AppConfig, ProxyConfig, DEFAULT_PROXY_HOST, DEFAULT_PROXY_PORT};
+pub use descriptor::{derive_script, descriptor_owns, script_pubkeys, wallet_descriptors};
+pub use exchange::{Exchange, Fiat, RateSource};
+pub use fees::{format_blocks_time, FeeEstimates, FEE_TARGETS};
+pub use format::{display_accounting_amount, format_age, format_data_age, FormatDate};
+pub use prefs::{
+    BackendType, CorePrefs, WalletPrefs, DEFAULT_GAP_LIMIT, DEFAULT_MAX_RATE_DEVIATION,
+    DEFAULT_RATE_STALE_PERIOD,
+};
+pub use sidecar::Sidecar;
 pub use ui::{Notification, UI};
@@ -0,0 +1,246 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bitcoin::OutPoint;
+use chrono::NaiveDate;
+use serde_crate::{Deserialize, Serialize};
+use wallet::onchain::PublicNetwork;
+
+use super::{Exchange, Fiat, RateSource, Sidecar};
+
+pub const DEFAULT_GAP_LIMIT: u16 = 20;
+pub const DEFAULT_MAX_RATE_DEVIATION: u16 = 200;
+pub const DEFAULT_RATE_STALE_PERIOD: u32 = 60;
+
+/// Type of the service used to retrieve blockchain data.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", rename_all = "lowercase")]
+pub enum BackendType {
+    #[display("Electrum")]
+    Electrum,
+
+    #[display("Esplora")]
+    Esplora,
+
+    #[display("Bitcoin Core")]
+    #[serde(rename = "bitcoind")]
+    BitcoinCore,
+}
+
+impl Default for BackendType {
+    fn default() -> Self { BackendType::Electrum }
+}
+
+impl BackendType {
+    /// Interval between checks for new blocks and transactions, in seconds.
+    /// Electrum servers notify about changes, so checking them is cheap;
+    /// other backends are polled for each wallet script, so they are checked
+    /// less often.
+    pub fn pull_interval(self) -> u64 {
+        match self {
+            BackendType::Electrum => 15,
+            BackendType::Esplora | BackendType::BitcoinCore => 60,
+        }
+    }
+
+    pub fn default_esplora_url(network: PublicNetwork) -> &'static str {
+        match network {
+            PublicNetwork::Mainnet => "https://blockstream.info/api",
+            PublicNetwork::Testnet => "https://blockstream.info/testnet/api",
+            PublicNetwork::Signet => "https://mempool.space/signet/api",
+        }
+    }
+
+    pub fn default_core_url(network: PublicNetwork) -> &'static str {
+        match network {
+            PublicNetwork::Mainnet => "http://127.0.0.1:8332",
+            PublicNetwork::Testnet => "http://127.0.0.1:18332",
+            PublicNetwork::Signet => "http://127.0.0.1:38332",
+        }
+    }
+}
+
+/// Connection to Bitcoin Core JSON-RPC interface.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct CorePrefs {
+    /// RPC URL; empty string means a local node on the default port for the
+    /// wallet network.
+    pub url: String,
+
+    /// RPC user name; if empty, authentication with a cookie file is used.
+    pub user: String,
+
+    pub password: String,
+
+    /// Path to the RPC cookie file; empty string means the cookie file in the
+    /// default Bitcoin Core data directory.
+    pub cookie_file: String,
+
+    /// Name of the watch-only wallet created in Bitcoin Core for tracking
+    /// wallet addresses; empty string means a name derived from the wallet
+    /// file name.
+    pub wallet: String,
+
+    /// Date, in `YYYY-MM-DD` format, before which the wallet had no
+    /// transactions. Bitcoin Core rescans the blockchain for the wallet
+    /// transactions starting from this date; empty string means rescanning
+    /// the whole blockchain.
+    pub birthday: String,
+}
+
+impl CorePrefs {
+    pub fn url(&self, network: PublicNetwork) -> &str {
+        if self.url.is_empty() {
+            BackendType::default_core_url(network)
+        } else {
+            &self.url
+        }
+    }
+
+    /// Name of the Bitcoin Core wallet for the wallet with the given
+    /// identifier, which is the wallet file name without the extension.
+    pub fn wallet(&self, wallet_id: &str) -> String {
+        if self.wallet.is_empty() {
+            CorePrefs::default_wallet(wallet_id)
+        } else {
+            self.wallet.clone()
+        }
+    }
+
+    pub fn default_wallet(wallet_id: &str) -> String { format!("mycitadel-{}", wallet_id) }
+
+    /// Wallet birthday as a UNIX timestamp; zero if the birthday is not set or
+    /// can't be parsed.
+    pub fn birthday_timestamp(&self) -> i64 {
+        NaiveDate::parse_from_str(self.birthday.trim(), "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|datetime| datetime.timestamp())
+            .unwrap_or_default()
+    }
+}
+
+/// Wallet preferences which are not a part of the wallet descriptor and are
+/// specific to this application.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct WalletPrefs {
+    /// Number of consecutive unused addresses after which the wallet stops
+    /// looking for new transactions.
+    pub gap_limit: u16,
+
+    /// Service used for the wallet synchronization. Electrum server
+    /// configuration is a part of the wallet settings.
+    pub backend: BackendType,
+
+    /// Base URL of Esplora HTTP API; empty string means a default public
+    /// server for the wallet network.
+    pub esplora_url: String,
+
+    pub core: CorePrefs,
+
+    /// Electrum servers used when the server from the wallet settings is not
+    /// available, in the order of their priority. Each server is given by its
+    /// URL in `tcp://host:port` or `ssl://host:port` form.
+    pub electrum_fallback: Vec<String>,
+
+    /// SHA-256 fingerprints of the TLS certificates trusted for Electrum
+    /// servers, by the server `host:port`. Certificates of these servers are
+    /// not validated against system root certificates; instead a connection is
+    /// refused if the server presents a certificate with a different
+    /// fingerprint.
+    pub electrum_certs: BTreeMap<String, String>,
+
+    /// Whether the wallet must never connect to the network. Offline wallets
+    /// show the data known from the last synchronization.
+    pub offline: bool,
+
+    /// Fiat currency in which the wallet balance is shown.
+    pub fiat: Fiat,
+
+    /// Exchange providing the rate for the fiat currency. If it is not
+    /// available, other exchanges are used as a fallback.
+    pub exchange: Exchange,
+
+    /// Whether the exchange rate is the median of the rates from multiple
+    /// exchanges instead of the rate from the selected exchange.
+    pub median_rate: bool,
+
+    /// Exchanges used for the median rate; empty set means all known
+    /// exchanges.
+    pub median_exchanges: BTreeSet<Exchange>,
+
+    /// Maximal deviation of an exchange rate from the median, in basis points,
+    /// above which the rate is rejected as an outlier.
+    pub max_rate_deviation: u16,
+
+    /// Age of the exchange rate, in minutes, after which it is shown as
+    /// outdated.
+    pub rate_stale_period: u32,
+
+    /// Coins which must not be spent by the wallet. Spent coins are removed
+    /// after each synchronization.
+    ///
+    /// Not a part of the wallet file (see [`Sidecar`]), so copying the wallet
+    /// file alone does not preserve frozen coins.
+    pub frozen_coins: BTreeSet<OutPoint>,
+}
+
+impl Default for WalletPrefs {
+    fn default() -> Self {
+        WalletPrefs {
+            gap_limit: DEFAULT_GAP_LIMIT,
+            backend: BackendType::Electrum,
+            esplora_url: empty!(),
+            core: default!(),
+            electrum_fallback: empty!(),
+            electrum_certs: empty!(),
+            offline: false,
+            fiat: Fiat::default(),
+            exchange: Exchange::default(),
+            median_rate: false,
+            median_exchanges: empty!(),
+            max_rate_deviation: DEFAULT_MAX_RATE_DEVIATION,
+            rate_stale_period: DEFAULT_RATE_STALE_PERIOD,
+            frozen_coins: empty!(),
+        }
+    }
+}
+
+impl WalletPrefs {
+    pub fn rate_source(&self) -> RateSource {
+        match self.median_rate {
+            true => RateSource::Median {
+                exchanges: self.median_exchanges.clone(),
+                max_deviation: self.max_rate_deviation,
+            },
+            false => RateSource::Exchange(self.exchange),
+        }
+    }
+
+    pub fn esplora_url(&self, network: PublicNetwork) -> &str {
+        if self.esplora_url.is_empty() {
+            BackendType::default_esplora_url(network)
+        } else {
+            &self.esplora_url
+        }
+    }
+}
+
+impl Sidecar for WalletPrefs {
+    const FILE_EXT: &'static str = "prefs.json";
+}
@@ -0,0 +1,71 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::path::{Path, PathBuf};
+use std::{fs, io, process};
+
+use serde_crate::de::DeserializeOwned;
+use serde_crate::Serialize;
+
+#[derive(Debug, Display, From, Error)]
+#[display(inner)]
+pub enum Error {
+    #[from]
+    File(io::Error),
+
+    #[from]
+    Json(serde_json::Error),
+}
+
+/// Writes JSON data to the file, replacing it atomically, such that readers
+/// never see a partially written file and an interrupted write leaves the
+/// previous file version intact.
+pub fn write_atomic(path: &Path, data: &impl Serialize) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Each process uses its own temporary file
+    let tmp = path.with_extension(format!("{}.tmp", process::id()));
+    let file = fs::File::create(&tmp)?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer(&mut writer, data)?;
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Data kept in a separate file next to the wallet `*.mcw` file.
+///
+/// The wallet file format is defined by `bpro` and is not extendable from the
+/// application, so all application-specific wallet data go into sidecar files
+/// sharing the wallet file name and differing by extension.
+pub trait Sidecar: Serialize + DeserializeOwned + Default {
+    const FILE_EXT: &'static str;
+
+    fn path_for(wallet_path: &Path) -> PathBuf { wallet_path.with_extension(Self::FILE_EXT) }
+
+    /// Reads sidecar data for the wallet; returns default data if the sidecar
+    /// file does not exist yet.
+    fn read_for(wallet_path: &Path) -> Result<Self, Error> {
+        match fs::File::open(Self::path_for(wallet_path)) {
+            Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_for(&self, wallet_path: &Path) -> Result<(), Error> {
+        write_atomic(&Self::path_for(wallet_path), self)
+    }
+}
@@ -170,14 +170,14 @@ impl Update for Component {
                         .emit(settings::Msg::New(template, path));
                 }
             }
-            Msg::Duplicate(settings, path) => {
+            Msg::Duplicate(settings, prefs, path) => {
                 if let Some(path) =
                     file_create_dlg(None, "Copy wallet", "MyCitadel wallet", "*.mcw", &path)
                 {
                     self.wallet_count += 1;
                     self.widgets.hide();
                     self.wallet_settings
-                        .emit(settings::Msg::Duplicate(settings, path));
+                        .emit(settings::Msg::Duplicate(settings, prefs, path));
                 }
             }
             Msg::Import => {}
@@ -218,6 +218,11 @@ impl Update for Component {
                 }
             }
             Msg::CreatePsbt(psbt, network) => self.create_psbt(psbt, network),
+            Msg::ProxyChanged => {
+                for wallet in &self.wallets {
+                    wallet.emit(wallet::Msg::ProxyChanged);
+                }
+            }
         }
     }
 }
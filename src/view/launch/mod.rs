@@ -20,6 +20,8 @@ use wallet::onchain::PublicNetwork;
 use wallet::psbt::Psbt;
 pub(self) use widget::Widgets;
 
+use crate::model::WalletPrefs;
+
 pub struct ViewModel {}
 
 #[derive(Msg)]
@@ -28,7 +30,7 @@ pub enum Msg {
     ShowPage(Page),
     Close,
     Template(i32),
-    Duplicate(WalletSettings, String),
+    Duplicate(WalletSettings, WalletPrefs, String),
     Import,
     Wallet,
     Psbt(Option<PublicNetwork>),
@@ -38,6 +40,9 @@ pub enum Msg {
     WalletClosed,
     CreatePsbt(Psbt, PublicNetwork),
     PsbtClosed,
+    /// Application-wide proxy settings have changed, so all wallets must
+    /// reconnect.
+    ProxyChanged,
 }
 
 #[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
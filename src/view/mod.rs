@@ -20,8 +20,8 @@ use std::path::PathBuf;
 
 use gtk::prelude::*;
 use gtk::{
-    ButtonsType, DialogFlags, FileChooserAction, FileChooserDialog, FileFilter, MessageDialog,
-    MessageType, ResponseType,
+    Adjustment, ButtonsType, DialogFlags, FileChooserAction, FileChooserDialog, FileFilter,
+    MessageDialog, MessageType, ResponseType, SpinButton,
 };
 
 pub trait NotificationBoxExt {
@@ -97,6 +97,61 @@ pub fn error_dlg(
     msg_dlg(parent, MessageType::Error, title, message, details);
 }
 
+/// Asks the user a yes/no question; returns whether the user has agreed.
+pub fn confirm_dlg(
+    parent: &impl IsA<gtk::Window>,
+    title: &str,
+    message: &str,
+    details: Option<&str>,
+) -> bool {
+    let dlg = MessageDialog::new(
+        Some(parent),
+        DialogFlags::all(),
+        MessageType::Warning,
+        ButtonsType::YesNo,
+        message,
+    );
+    dlg.set_title(title);
+    dlg.set_secondary_text(details);
+    let response = dlg.run();
+    dlg.close();
+    response == ResponseType::Yes
+}
+
+/// Asks the user for a fee rate in sat/vbyte, suggesting the provided one;
+/// returns `None` if the user has cancelled.
+pub fn fee_rate_dlg(
+    parent: &impl IsA<gtk::Window>,
+    title: &str,
+    message: &str,
+    details: &str,
+    fee_rate: f32,
+) -> Option<f32> {
+    let dlg = MessageDialog::new(
+        Some(parent),
+        DialogFlags::all(),
+        MessageType::Question,
+        ButtonsType::OkCancel,
+        message,
+    );
+    dlg.set_title(title);
+    dlg.set_secondary_text(Some(details));
+    let adj = Adjustment::new(fee_rate as f64, 1.0, 10000.0, 1.0, 10.0, 0.0);
+    let spin = SpinButton::new(Some(&adj), 1.0, 1);
+    spin.set_numeric(true);
+    spin.set_activates_default(true);
+    dlg.message_area()
+        .downcast::<gtk::Box>()
+        .expect("message dialog area is a box")
+        .pack_start(&spin, false, false, 0);
+    spin.show();
+    dlg.set_default_response(ResponseType::Ok);
+    let response = dlg.run();
+    let fee_rate = spin.value() as f32;
+    dlg.close();
+    (response == ResponseType::Ok).then(|| fee_rate)
+}
+
 pub fn file_dlg(
     parent: Option<&impl IsA<gtk::Window>>,
     title: &str,
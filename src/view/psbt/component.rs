@@ -15,7 +15,6 @@ use bitcoin::consensus::Encodable;
 use bitcoin::psbt::PartiallySignedTransaction;
 use bitcoin::secp256k1::SECP256K1;
 use bpro::{ElectrumPreset, ElectrumServer};
-use electrum_client::ElectrumApi;
 use gladis::Gladis;
 use gtk::prelude::ListModelExt;
 use gtk::{ApplicationWindow, MessageType};
@@ -27,7 +26,7 @@ use super::sign_row::Signing;
 use super::{xpriv_dlg, ModelParam, Msg, SignMsg, ViewModel, Widgets};
 use crate::view::psbt::PublishMsg;
 use crate::view::{error_dlg, file_save_dlg, launch, msg_dlg};
-use crate::worker::electrum::electrum_connect;
+use crate::worker::{backend, ChainBackend, ElectrumBackend, Socks5};
 
 pub struct Component {
     model: ViewModel,
@@ -114,8 +113,12 @@ impl Component {
             // TODO: Allow selecting Electrum server
             let electrum = ElectrumServer::tls(ElectrumPreset::Blockstream, self.model.network());
             thread::spawn(move || {
-                let _ = match electrum_connect(&electrum.to_string())
-                    .and_then(|client| client.transaction_broadcast(&tx))
+                // Each transaction is published over a separate Tor circuit,
+                // so the server can't link it to other activity of the wallet
+                let _ = match Socks5::configured(&format!("publish-{}", tx.txid()))
+                    .map_err(backend::Error::from)
+                    .and_then(|proxy| ElectrumBackend::connect(&electrum, proxy.as_ref()))
+                    .and_then(|mut backend| backend.broadcast(&tx))
                 {
                     Err(err) => sender.send(PublishMsg::Declined(err.to_string())),
                     Ok(_txid) => sender.send(PublishMsg::Published),
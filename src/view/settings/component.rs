@@ -22,7 +22,8 @@ use relm::{init, Channel, Relm, StreamHandle, Update, Widget};
 
 use super::spending_row::Condition;
 use super::{xpub_dlg, Msg, ViewModel, Widgets};
-use crate::view::{devices, error_dlg, launch, wallet, NotificationBoxExt};
+use crate::model::{AppConfig, Sidecar};
+use crate::view::{confirm_dlg, devices, error_dlg, launch, wallet, NotificationBoxExt};
 
 pub struct Component {
     model: ViewModel,
@@ -64,10 +65,25 @@ impl Component {
         self.widgets.set_remove_condition(removable);
     }
 
+    /// Warns the user that the application settings can't be read and thus
+    /// the wallets do not connect to the network.
+    fn check_app_config(&self) {
+        if let Some(ref err) = self.model.app_config_error {
+            error_dlg(
+                self.widgets.as_root(),
+                "Error reading application settings",
+                &AppConfig::path().display().to_string(),
+                Some(&format!(
+                    "{err}\n\nNetwork connections are disabled until the proxy settings are \
+                     edited and saved."
+                )),
+            );
+        }
+    }
+
     fn sync(&mut self) {
         let res = self.model.update_descriptor();
-        self.widgets
-            .update_descriptor(self.model.descriptor.as_ref());
+        self.widgets.update_descriptors(&self.model.descriptors);
         if let Err(err) = res {
             return self.widgets.show_error(&err.to_string());
         }
@@ -190,14 +206,102 @@ impl Update for Component {
                 self.model.test_electrum();
                 return;
             }
-            Msg::ElectrumTestOk => {
+            Msg::ElectrumTestOk(None) => {
+                self.widgets.complete_electrum_test(None);
+                return;
+            }
+            Msg::ElectrumTestOk(Some((server, fingerprint))) => {
                 self.widgets.complete_electrum_test(None);
+                let msg = match self.model.prefs.electrum_certs.get(&server) {
+                    Some(pin) if pin == &fingerprint => {
+                        self.widgets.show_info(&format!(
+                            "Certificate of {server} is trusted.\nSHA-256 fingerprint: \
+                             {fingerprint}"
+                        ));
+                        return;
+                    }
+                    Some(_) => {
+                        // Changed certificate may indicate a man-in-the-middle
+                        // attack, so it is never trusted silently
+                        let trusted = confirm_dlg(
+                            self.widgets.as_root(),
+                            "Server certificate has changed",
+                            &format!(
+                                "Certificate of {server} has CHANGED since it was trusted. Do you \
+                                 trust the new certificate?"
+                            ),
+                            Some(&format!(
+                                "New SHA-256 fingerprint: {fingerprint}\nCheck it against the \
+                                 server configuration before trusting it."
+                            )),
+                        );
+                        if !trusted {
+                            self.widgets.show_warning(&format!(
+                                "Certificate of {server} is not trusted; connections to the \
+                                 server will be refused."
+                            ));
+                            return;
+                        }
+                        format!(
+                            "New certificate of {server} with SHA-256 fingerprint {fingerprint} \
+                             will be trusted once the settings are saved."
+                        )
+                    }
+                    None => format!(
+                        "Certificate of {server} has SHA-256 fingerprint {fingerprint}\nCheck it \
+                         against the server configuration; saving the settings will trust this \
+                         certificate."
+                    ),
+                };
+                self.model.prefs.electrum_certs.insert(server, fingerprint);
+                self.widgets.show_warning(&msg);
                 return;
             }
             Msg::ElectrumTestFailed(failure) => {
                 self.widgets.complete_electrum_test(Some(failure));
                 return;
             }
+            Msg::GapLimitChange => {
+                self.model.prefs.gap_limit = self.widgets.gap_limit();
+                return;
+            }
+            Msg::RateSourceEdit => {
+                self.model.prefs.median_exchanges = self.widgets.median_exchanges();
+                self.model.prefs.max_rate_deviation = self.widgets.max_rate_deviation();
+                return;
+            }
+            Msg::BackendSelect(backend) if self.model.prefs.backend != backend => {
+                self.model.prefs.backend = backend;
+                self.widgets.update_backend(&self.model.prefs, false);
+                return;
+            }
+            Msg::EsploraEdit if self.model.prefs.esplora_url != self.widgets.esplora_url() => {
+                self.model.prefs.esplora_url = self.widgets.esplora_url();
+                self.widgets.update_backend(&self.model.prefs, false);
+                return;
+            }
+            Msg::FallbackEdit
+                if self.model.prefs.electrum_fallback != self.widgets.fallback_servers() =>
+            {
+                self.model.prefs.electrum_fallback = self.widgets.fallback_servers();
+                return;
+            }
+            Msg::CoreEdit if self.model.prefs.core != self.widgets.core_prefs() => {
+                self.model.prefs.core = self.widgets.core_prefs();
+                self.widgets.update_backend(&self.model.prefs, false);
+                return;
+            }
+            Msg::ProxyEdit if self.model.app_config.proxy != self.widgets.proxy_config() => {
+                self.model.app_config.proxy = self.widgets.proxy_config();
+                self.model.app_config_error = None;
+                self.widgets
+                    .update_proxy(&self.model.app_config.proxy, false);
+                return;
+            }
+            Msg::OfflineToggle => {
+                self.model.prefs.offline = self.widgets.is_offline();
+                return;
+            }
             Msg::SetWallet(stream) => {
                 self.wallet_stream = Some(stream);
                 return;
@@ -219,7 +323,39 @@ impl Update for Component {
                     }
                     Ok(descr) => descr,
                 };
+                // Proxy settings are application-wide, so the workers of all
+                // wallets reconnect once they change. Unreadable settings are
+                // kept until the user edits them.
+                let proxy_changed = self.model.app_config_error.is_none()
+                    && AppConfig::read()
+                        .map(|config| config.proxy != self.model.app_config.proxy)
+                        .unwrap_or(true);
+                if proxy_changed {
+                    if let Err(err) = self.model.app_config.write() {
+                        error_dlg(
+                            self.widgets.as_root(),
+                            "Error saving application settings",
+                            &AppConfig::path().display().to_string(),
+                            Some(&err.to_string()),
+                        );
+                        return;
+                    }
+                    if let Some(stream) = &self.launcher_stream {
+                        stream.emit(launch::Msg::ProxyChanged);
+                    } else if let Some(stream) = &self.wallet_stream {
+                        stream.emit(wallet::Msg::Launch(launch::Msg::ProxyChanged));
+                    }
+                }
                 if let Some(path) = self.new_wallet_path() {
+                    if let Err(err) = self.model.prefs.write_for(path) {
+                        error_dlg(
+                            self.widgets.as_root(),
+                            "Error saving wallet",
+                            &self.model.filename(),
+                            Some(&err.to_string()),
+                        );
+                        return;
+                    }
                     self.launcher_stream.as_ref().map(|stream| {
                         stream.emit(launch::Msg::WalletCreated(path.to_owned()));
                     });
@@ -229,6 +365,7 @@ impl Update for Component {
                             settings.signers().clone(),
                             settings.descriptor_classes().clone(),
                             settings.electrum().clone(),
+                            self.model.prefs.clone(),
                         ));
                     });
                 }
@@ -263,18 +400,21 @@ impl Update for Component {
                 self.devices
                     .emit(devices::Msg::SetNetwork(self.model.network));
                 self.widgets.reset_ui(&self.model);
+                self.check_app_config();
             }
-            Msg::Duplicate(settings, path) => {
+            Msg::Duplicate(settings, prefs, path) => {
                 self.model
-                    .replace_from_settings(self.model.stream(), settings, path, true);
+                    .replace_from_settings(self.model.stream(), settings, prefs, path, true);
                 self.devices
                     .emit(devices::Msg::SetNetwork(self.model.network));
                 self.widgets.reset_ui(&self.model);
+                self.check_app_config();
             }
-            Msg::View(settings, path) => {
+            Msg::View(settings, prefs, path) => {
                 self.model
-                    .replace_from_settings(self.model.stream(), settings, path, false);
+                    .replace_from_settings(self.model.stream(), settings, prefs, path, false);
                 self.widgets.reset_ui(&self.model);
+                self.check_app_config();
             }
             Msg::SignerAddDevice(fingerprint, device) => {
                 self.model.devices.insert(fingerprint, device);
@@ -411,6 +551,7 @@ impl Update for Component {
                 self.widgets.update_network();
                 self.widgets
                     .update_electrum(&mut self.model.electrum_model, false, false);
+                self.widgets.update_backend(&self.model.prefs, false);
             }
             _ => {}
         }
@@ -27,13 +27,14 @@ use relm::StreamHandle;
 pub(self) use view_model::{ElectrumModel, ViewModel};
 pub(self) use widget::Widgets;
 
+use crate::model::{BackendType, WalletPrefs};
 use crate::view::{launch, wallet};
 
 #[derive(Msg)]
 pub enum Msg {
     New(WalletTemplate, PathBuf),
-    Duplicate(WalletSettings, PathBuf),
-    View(WalletSettings, PathBuf),
+    Duplicate(WalletSettings, WalletPrefs, PathBuf),
+    View(WalletSettings, WalletPrefs, PathBuf),
     AddDevices,
     AddReadOnly,
     RemoveSigner,
@@ -56,8 +57,16 @@ pub enum Msg {
     ElectrumPortChange,
     ElectrumSecChange(ElectrumSec),
     ElectrumTest,
-    ElectrumTestOk,
+    ElectrumTestOk(Option<(String, String)>),
     ElectrumTestFailed(String),
+    GapLimitChange,
+    BackendSelect(BackendType),
+    EsploraEdit,
+    CoreEdit,
+    FallbackEdit,
+    ProxyEdit,
+    OfflineToggle,
+    RateSourceEdit,
     Response(ResponseType),
     SetWallet(StreamHandle<wallet::Msg>),
     SetLauncher(StreamHandle<launch::Msg>),
@@ -18,7 +18,6 @@ use bpro::{
     file, DescriptorError, ElectrumPreset, ElectrumSec, ElectrumServer, FileDocument, HardwareList,
     Signer, Wallet, WalletSettings, WalletTemplate,
 };
-use electrum_client::{Client as ElectrumClient, ElectrumApi};
 use miniscript::Descriptor;
 use relm::{Channel, StreamHandle};
 use wallet::descriptors::DescriptorClass;
@@ -27,6 +26,8 @@ use wallet::onchain::PublicNetwork;
 
 use super::spending_row::SpendingModel;
 use super::Msg;
+use crate::model::{wallet_descriptors, AppConfig, BackendType, WalletPrefs};
+use crate::worker::{backend, ChainBackend, ElectrumBackend, Socks5};
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub struct ElectrumModel {
@@ -109,6 +110,12 @@ pub struct ViewModel {
     pub signers: Vec<Signer>,
     pub spending_model: SpendingModel,
     pub electrum_model: ElectrumModel,
+    pub prefs: WalletPrefs,
+    pub app_config: AppConfig,
+    /// Error reading the application settings. Default settings are shown in
+    /// this case, but they are not saved unless the user edits the proxy, so
+    /// the configured proxy is never replaced silently.
+    pub app_config_error: Option<String>,
 
     // Data provided by the parent window
     pub new_wallet: bool,
@@ -117,7 +124,7 @@ pub struct ViewModel {
     // Non-persisting / dynamic data for this window
     pub active_signer: Option<Signer>,
     pub devices: HardwareList,
-    pub descriptor: Option<Descriptor<DerivationAccount>>,
+    pub descriptors: Vec<Descriptor<DerivationAccount>>,
 }
 
 impl TryFrom<&ViewModel> for WalletSettings {
@@ -137,7 +144,7 @@ impl TryFrom<&ViewModel> for WalletSettings {
 
 impl ViewModel {
     pub fn new(stream: StreamHandle<Msg>) -> Self {
-        ViewModel {
+        let mut model = ViewModel {
             path: PathBuf::default(),
             stream,
             devices: none!(),
@@ -145,12 +152,30 @@ impl ViewModel {
             active_signer: None,
             spending_model: SpendingModel::new(),
             electrum_model: ElectrumModel::new(PublicNetwork::Mainnet),
+            prefs: none!(),
+            app_config: default!(),
+            app_config_error: None,
             network: PublicNetwork::Mainnet,
-            descriptor: None,
+            descriptors: empty!(),
             template: None,
             descriptor_classes: bset![DescriptorClass::SegwitV0],
             support_multiclass: false,
             new_wallet: true,
+        };
+        model.read_app_config();
+        model
+    }
+
+    fn read_app_config(&mut self) {
+        match AppConfig::read() {
+            Ok(config) => {
+                self.app_config = config;
+                self.app_config_error = None;
+            }
+            Err(err) => {
+                self.app_config = default!();
+                self.app_config_error = Some(err.to_string());
+            }
         }
     }
 
@@ -169,11 +194,13 @@ impl ViewModel {
         self.signers = empty!();
         self.spending_model.reset_conditions(&template.conditions);
         self.electrum_model = ElectrumModel::new(template.network);
+        self.prefs = none!();
+        self.read_app_config();
         self.template = Some(template);
 
         self.active_signer = None;
         self.devices = empty!();
-        self.descriptor = None;
+        self.descriptors = empty!();
 
         self.save()?;
         Ok(())
@@ -183,6 +210,7 @@ impl ViewModel {
         &mut self,
         stream: StreamHandle<Msg>,
         settings: WalletSettings,
+        prefs: WalletPrefs,
         path: PathBuf,
         new_wallet: bool,
     ) {
@@ -198,11 +226,13 @@ impl ViewModel {
         self.spending_model
             .reset_conditions(settings.spending_conditions());
         self.electrum_model = settings.electrum().clone().into();
+        self.prefs = prefs;
+        self.read_app_config();
 
         self.template = None;
         self.active_signer = None;
         self.devices = empty!();
-        self.descriptor = None;
+        self.descriptors = empty!();
     }
 
     pub fn stream(&self) -> StreamHandle<Msg> { self.stream.clone() }
@@ -225,6 +255,15 @@ impl ViewModel {
     pub fn path(&self) -> &Path { &self.path }
     pub fn filename(&self) -> String { self.path.display().to_string() }
 
+    /// Key used for Tor stream isolation, matching the one used by the wallet
+    /// synchronization worker.
+    pub fn isolation_key(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
     pub fn is_new_wallet(&self) -> bool { self.new_wallet }
 
     pub fn bip43(&self) -> Bip43 {
@@ -311,45 +350,66 @@ impl ViewModel {
     }
 
     pub fn update_descriptor(&mut self) -> Result<(), String> {
-        self.descriptor = None;
+        self.descriptors = empty!();
         if self.signers.is_empty() {
             return Err(s!("You need to add at least one signer"));
         }
         let settings = WalletSettings::try_from(self as &Self).map_err(|err| err.to_string())?;
-        // TODO: Support multiple descriptors
-        let (descriptor, _) = settings.descriptors_all().map_err(|err| err.to_string())?;
-        self.descriptor = Some(descriptor);
+        self.descriptors = wallet_descriptors(&settings).map_err(|err| err.to_string())?;
         Ok(())
     }
 
     pub fn test_electrum(&self) {
         enum ElectrumMsg {
-            Ok,
+            Ok(Option<(String, String)>),
             Failure(String),
         }
         let stream = self.stream.clone();
-        let url = self.electrum_model.to_string();
+        let electrum = ElectrumServer::from(&self.electrum_model);
+        let prefs = self.prefs.clone();
+        let network = self.network;
+        let wallet_id = self.isolation_key();
+        // Connection is never made directly if proxy settings are unknown
+        let proxy = match self.app_config_error {
+            Some(ref err) => Err(backend::Error::Message(format!(
+                "unable to read proxy settings: {err}"
+            ))),
+            None => Ok(Socks5::with(&self.app_config.proxy, &wallet_id)),
+        };
         let (_channel, sender) = Channel::new(move |msg| match msg {
-            ElectrumMsg::Ok => stream.emit(Msg::ElectrumTestOk),
+            ElectrumMsg::Ok(cert) => stream.emit(Msg::ElectrumTestOk(cert)),
             ElectrumMsg::Failure(err) => stream.emit(Msg::ElectrumTestFailed(err)),
         });
-        eprint!("Testing connection to {} ... ", url);
-        let config = electrum_client::ConfigBuilder::new()
-            .timeout(Some(5))
-            .build();
+        let server = backend::servers(&electrum, &prefs, network)
+            .into_iter()
+            .next()
+            .expect("backend always has a server");
+        // Custom servers often use self-signed certificates, so instead of
+        // validating them we show the certificate fingerprint to the user
+        // and pin it once the settings are saved
+        let tofu = prefs.backend == BackendType::Electrum
+            && self.electrum_model.electrum_preset == ElectrumPreset::Custom
+            && backend::cert_key(&server).is_some();
         std::thread::spawn(move || {
-            match ElectrumClient::from_config(&url, config).and_then(|client| client.ping()) {
-                Err(err) => {
-                    eprintln!("failure: {err}");
-                    sender
-                        .send(ElectrumMsg::Failure(err.to_string()))
-                        .expect("channel is broken");
+            let res = proxy.and_then(|proxy| {
+                if tofu {
+                    ElectrumBackend::connect_pinned(&server, proxy.as_ref(), None).and_then(
+                        |(mut backend, fingerprint)| {
+                            backend.ping()?;
+                            let key = backend::cert_key(&server).unwrap_or_default().to_owned();
+                            Ok(Some((key, fingerprint)))
+                        },
+                    )
+                } else {
+                    backend::test(&server, &prefs, network, &wallet_id, proxy.as_ref())
+                        .map(|_| None)
                 }
-                Ok(_) => {
-                    eprintln!("success");
-                    sender.send(ElectrumMsg::Ok).expect("channel is broken");
-                }
-            }
+            });
+            // Settings may be closed before the test is complete
+            let _ = match res {
+                Err(err) => sender.send(ElectrumMsg::Failure(err.to_string())),
+                Ok(cert) => sender.send(ElectrumMsg::Ok(cert)),
+            };
         });
     }
 }
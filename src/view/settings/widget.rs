@@ -21,9 +21,9 @@ use bpro::{
 use gladis::Gladis;
 use gtk::prelude::*;
 use gtk::{
-    gdk, glib, Adjustment, Box, Button, ButtonBox, ComboBoxText, Dialog, Entry, Grid, HeaderBar,
-    Image, Label, ListBox, ListBoxRow, ListStore, Notebook, ResponseType, SpinButton, Spinner,
-    TextBuffer, ToggleButton, ToolButton, Toolbar, TreePath, TreeView,
+    gdk, glib, Adjustment, Box, Button, ButtonBox, CheckButton, ComboBoxText, Dialog, Entry, Grid,
+    HeaderBar, Image, Label, ListBox, ListBoxRow, ListStore, Notebook, ResponseType, SpinButton,
+    Spinner, TextBuffer, ToggleButton, ToolButton, Toolbar, TreePath, TreeView,
 };
 use miniscript::Descriptor;
 use relm::{Relm, Sender};
@@ -33,7 +33,11 @@ use wallet::onchain::PublicNetwork;
 
 use super::spending_row::SpendingModel;
 use super::{spending_row, ElectrumModel, Msg, ViewModel};
+use crate::model::{
+    BackendType, CorePrefs, Exchange, ProxyConfig, WalletPrefs, DEFAULT_PROXY_HOST,
+};
 use crate::view::NotificationBoxExt;
+use crate::worker::backend::default_cookie_file;
 
 // Create the structure that holds the widgets used in the view.
 #[derive(Clone, Gladis)]
@@ -100,6 +104,31 @@ pub struct Widgets {
     test_btn: Button,
     connection_img: Image,
     connection_spin: Spinner,
+
+    gap_adj: Adjustment,
+    backend_electrum_tgl: ToggleButton,
+    backend_esplora_tgl: ToggleButton,
+    backend_core_tgl: ToggleButton,
+    esplora_fld: Entry,
+    fallback_fld: Entry,
+    core_url_fld: Entry,
+    core_user_fld: Entry,
+    core_password_fld: Entry,
+    core_cookie_fld: Entry,
+    core_wallet_fld: Entry,
+    core_birthday_fld: Entry,
+    proxy_chk: CheckButton,
+    proxy_host_fld: Entry,
+    proxy_port_stp: SpinButton,
+    proxy_port_adj: Adjustment,
+    proxy_isolation_chk: CheckButton,
+    offline_chk: CheckButton,
+    median_kraken_chk: CheckButton,
+    median_bitstamp_chk: CheckButton,
+    median_coinbase_chk: CheckButton,
+    median_coingecko_chk: CheckButton,
+    median_bitfinex_chk: CheckButton,
+    deviation_adj: Adjustment,
 }
 
 impl Widgets {
@@ -158,11 +187,25 @@ impl Widgets {
 
         self.update_electrum(&mut model.electrum_model.clone(), true, true);
         self.update_network();
+        self.gap_adj.set_value(model.prefs.gap_limit as f64);
+        self.core_wallet_fld
+            .set_placeholder_text(Some(&CorePrefs::default_wallet(&model.isolation_key())));
+        self.update_backend(&model.prefs, true);
+        self.update_proxy(&model.app_config.proxy, true);
+        self.offline_chk.set_active(model.prefs.offline);
+        for exchange in Exchange::ALL {
+            self.median_chk(exchange).set_active(
+                model.prefs.median_exchanges.is_empty()
+                    || model.prefs.median_exchanges.contains(&exchange),
+            );
+        }
+        self.deviation_adj
+            .set_value(model.prefs.max_rate_deviation as f64);
 
         self.update_signers(&model.signers);
         self.update_signer_details(None, model.network, model.bip43());
         self.update_descr_classes(&model.descriptor_classes);
-        self.update_descriptor(model.descriptor.as_ref());
+        self.update_descriptors(&model.descriptors);
 
         self.dialog.show();
     }
@@ -359,6 +402,87 @@ impl Widgets {
         );
         connect!(relm, self.test_btn, connect_clicked(_), Msg::ElectrumTest);
 
+        connect!(
+            relm,
+            self.gap_adj,
+            connect_value_changed(_),
+            Msg::GapLimitChange
+        );
+        connect!(
+            relm,
+            self.backend_electrum_tgl,
+            connect_clicked(_),
+            Msg::BackendSelect(BackendType::Electrum)
+        );
+        connect!(
+            relm,
+            self.backend_esplora_tgl,
+            connect_clicked(_),
+            Msg::BackendSelect(BackendType::Esplora)
+        );
+        connect!(
+            relm,
+            self.backend_core_tgl,
+            connect_clicked(_),
+            Msg::BackendSelect(BackendType::BitcoinCore)
+        );
+        connect!(relm, self.esplora_fld, connect_changed(_), Msg::EsploraEdit);
+        connect!(
+            relm,
+            self.fallback_fld,
+            connect_changed(_),
+            Msg::FallbackEdit
+        );
+        for fld in [
+            &self.core_url_fld,
+            &self.core_user_fld,
+            &self.core_password_fld,
+            &self.core_cookie_fld,
+            &self.core_wallet_fld,
+            &self.core_birthday_fld,
+        ] {
+            connect!(relm, fld, connect_changed(_), Msg::CoreEdit);
+        }
+        connect!(relm, self.proxy_chk, connect_toggled(_), Msg::ProxyEdit);
+        connect!(
+            relm,
+            self.proxy_host_fld,
+            connect_changed(_),
+            Msg::ProxyEdit
+        );
+        connect!(
+            relm,
+            self.proxy_port_adj,
+            connect_value_changed(_),
+            Msg::ProxyEdit
+        );
+        connect!(
+            relm,
+            self.proxy_isolation_chk,
+            connect_toggled(_),
+            Msg::ProxyEdit
+        );
+        connect!(
+            relm,
+            self.offline_chk,
+            connect_toggled(_),
+            Msg::OfflineToggle
+        );
+        for exchange in Exchange::ALL {
+            connect!(
+                relm,
+                self.median_chk(exchange),
+                connect_toggled(_),
+                Msg::RateSourceEdit
+            );
+        }
+        connect!(
+            relm,
+            self.deviation_adj,
+            connect_value_changed(_),
+            Msg::RateSourceEdit
+        );
+
         connect!(
             relm,
             self.dialog,
@@ -435,6 +559,129 @@ impl Widgets {
 
     pub fn electrum_port(&self) -> u16 { self.port_adj.value() as u16 }
 
+    pub fn gap_limit(&self) -> u16 { self.gap_adj.value() as u16 }
+
+    pub fn is_offline(&self) -> bool { self.offline_chk.is_active() }
+
+    fn median_chk(&self, exchange: Exchange) -> &CheckButton {
+        match exchange {
+            Exchange::Kraken => &self.median_kraken_chk,
+            Exchange::Bitstamp => &self.median_bitstamp_chk,
+            Exchange::Coinbase => &self.median_coinbase_chk,
+            Exchange::CoinGecko => &self.median_coingecko_chk,
+            Exchange::Bitfinex => &self.median_bitfinex_chk,
+        }
+    }
+
+    /// Exchanges selected for the median rate; empty set, meaning all
+    /// exchanges, is returned if all or none of them are selected.
+    pub fn median_exchanges(&self) -> BTreeSet<Exchange> {
+        let exchanges = Exchange::ALL
+            .into_iter()
+            .filter(|exchange| self.median_chk(*exchange).is_active())
+            .collect::<BTreeSet<_>>();
+        match exchanges.len() == Exchange::ALL.len() {
+            true => empty!(),
+            false => exchanges,
+        }
+    }
+
+    pub fn max_rate_deviation(&self) -> u16 { self.deviation_adj.value() as u16 }
+
+    pub fn esplora_url(&self) -> String { self.esplora_fld.text().trim().to_string() }
+
+    pub fn fallback_servers(&self) -> Vec<String> {
+        self.fallback_fld
+            .text()
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    pub fn core_prefs(&self) -> CorePrefs {
+        CorePrefs {
+            url: self.core_url_fld.text().trim().to_string(),
+            user: self.core_user_fld.text().trim().to_string(),
+            password: self.core_password_fld.text().to_string(),
+            cookie_file: self.core_cookie_fld.text().trim().to_string(),
+            wallet: self.core_wallet_fld.text().trim().to_string(),
+            birthday: self.core_birthday_fld.text().trim().to_string(),
+        }
+    }
+
+    pub fn proxy_config(&self) -> ProxyConfig {
+        let host = self.proxy_host_fld.text().trim().to_string();
+        ProxyConfig {
+            enabled: self.proxy_chk.is_active(),
+            host: if host.is_empty() {
+                DEFAULT_PROXY_HOST.to_owned()
+            } else {
+                host
+            },
+            port: self.proxy_port_adj.value() as u16,
+            isolation: self.proxy_isolation_chk.is_active(),
+        }
+    }
+
+    pub fn update_proxy(&self, proxy: &ProxyConfig, update_fields: bool) {
+        if update_fields {
+            self.proxy_chk.set_active(proxy.enabled);
+            self.proxy_host_fld.set_text(&proxy.host);
+            self.proxy_port_adj.set_value(proxy.port as f64);
+            self.proxy_isolation_chk.set_active(proxy.isolation);
+        }
+        self.proxy_host_fld.set_sensitive(proxy.enabled);
+        self.proxy_port_stp.set_sensitive(proxy.enabled);
+        self.proxy_isolation_chk.set_sensitive(proxy.enabled);
+        self.connection_img.set_icon_name(None);
+    }
+
+    pub fn update_backend(&self, prefs: &WalletPrefs, update_fields: bool) {
+        let network = self.network();
+        self.backend_electrum_tgl
+            .set_active(prefs.backend == BackendType::Electrum);
+        self.backend_esplora_tgl
+            .set_active(prefs.backend == BackendType::Esplora);
+        self.backend_core_tgl
+            .set_active(prefs.backend == BackendType::BitcoinCore);
+        if update_fields {
+            self.esplora_fld.set_text(&prefs.esplora_url);
+            self.fallback_fld
+                .set_text(&prefs.electrum_fallback.join(", "));
+            self.core_url_fld.set_text(&prefs.core.url);
+            self.core_user_fld.set_text(&prefs.core.user);
+            self.core_password_fld.set_text(&prefs.core.password);
+            self.core_cookie_fld.set_text(&prefs.core.cookie_file);
+            self.core_wallet_fld.set_text(&prefs.core.wallet);
+            self.core_birthday_fld.set_text(&prefs.core.birthday);
+        }
+        self.esplora_fld
+            .set_placeholder_text(Some(BackendType::default_esplora_url(network)));
+        self.esplora_fld
+            .set_sensitive(prefs.backend == BackendType::Esplora);
+        self.fallback_fld
+            .set_sensitive(prefs.backend == BackendType::Electrum);
+        self.core_url_fld
+            .set_placeholder_text(Some(BackendType::default_core_url(network)));
+        self.core_cookie_fld
+            .set_placeholder_text(Some(&default_cookie_file(network).display().to_string()));
+        let core = prefs.backend == BackendType::BitcoinCore;
+        for fld in [
+            &self.core_url_fld,
+            &self.core_user_fld,
+            &self.core_password_fld,
+            &self.core_wallet_fld,
+            &self.core_birthday_fld,
+        ] {
+            fld.set_sensitive(core);
+        }
+        self.core_cookie_fld
+            .set_sensitive(core && prefs.core.user.is_empty());
+        self.connection_img.set_icon_name(None);
+    }
+
     pub fn update_electrum(
         &self,
         model: &mut ElectrumModel,
@@ -728,11 +975,12 @@ impl Widgets {
         }
     }
 
-    pub fn update_descriptor(&mut self, descriptor: Option<&Descriptor<DerivationAccount>>) {
-        let text = match descriptor {
-            Some(descriptor) => format!("{:#}", descriptor),
-            None => s!(""),
-        };
+    pub fn update_descriptors(&mut self, descriptors: &[Descriptor<DerivationAccount>]) {
+        let text = descriptors
+            .iter()
+            .map(|descriptor| format!("{:#}", descriptor))
+            .collect::<Vec<_>>()
+            .join("\n\n");
         self.descriptor_buf.set_text(&text);
     }
 
@@ -9,30 +9,37 @@
 // a copy of the AGPL-3.0 License along with this software. If not, see
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::path::PathBuf;
 
 use ::wallet::descriptors::InputDescriptor;
 use ::wallet::psbt::Psbt;
+use amplify::Wrapper;
 use bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR;
 use bitcoin::policy::DUST_RELAY_TX_FEE;
-use bitcoin::{EcdsaSighashType, Sequence, Transaction, TxIn, TxOut};
+use bitcoin::{EcdsaSighashType, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid};
 use bitcoin_blockchain::locks::{LockTime, SeqNo};
 use bitcoin_scripts::PubkeyScript;
 use bpro::psbt::McKeys;
-use bpro::{AddressSource, Prevout, TxidMeta, Wallet};
+use bpro::{AddressSource, OnchainStatus, Prevout, TxidMeta, UtxoTxid, Wallet};
+use chrono::{NaiveDate, NaiveDateTime};
 use gladis::Gladis;
 use gtk::prelude::*;
 use gtk::{ApplicationWindow, ResponseType};
+use miniscript::Descriptor;
 use relm::{init, Channel, Relm, StreamHandle, Update, Widget};
-use wallet::hd::{SegmentIndexes, UnhardenedIndex};
+use wallet::hd::{DerivationAccount, SegmentIndexes, UnhardenedIndex};
 use wallet::lex_order::lex_order::LexOrder;
 
 use super::pay::beneficiary_row::Beneficiary;
 use super::pay::FeeRate;
 use super::{pay, ElectrumState, Msg, ViewModel, Widgets};
-use crate::view::{error_dlg, launch, settings, NotificationBoxExt};
+use crate::model::{
+    bump, derive_script, descriptor_owns, format_data_age, wallet_descriptors, CHANGE_DUST_LIMIT,
+};
+use crate::view::{error_dlg, fee_rate_dlg, launch, settings, NotificationBoxExt};
 use crate::worker::{electrum, exchange, ElectrumWorker, ExchangeWorker};
 
 pub struct Component {
@@ -46,13 +53,23 @@ pub struct Component {
     electrum_worker: ElectrumWorker,
     tx_buffer: Vec<Transaction>,
     addr_buffer: BTreeMap<AddressSource, BTreeSet<TxidMeta>>,
+    utxo_buffer: BTreeSet<UtxoTxid>,
+    /// Confirmed transactions which inclusion into a block was not verified.
+    unverified: BTreeSet<Txid>,
+    invalid_proofs: BTreeSet<Txid>,
+    /// Date of the earliest wallet transaction, for which price history was
+    /// requested.
+    prices_since: Option<NaiveDate>,
 
     settings: relm::Component<settings::Component>,
     launcher_stream: Option<StreamHandle<launch::Msg>>,
 }
 
 impl Component {
-    fn close(&self) {
+    fn close(&mut self) {
+        // Closed wallets must not keep accessing the network
+        self.electrum_worker.shutdown();
+        self.exchange_worker.shutdown();
         self.widgets.close();
         self.launcher_stream
             .as_ref()
@@ -71,12 +88,22 @@ impl Component {
         }
     }
 
-    pub fn compose_psbt(&mut self) -> Result<(Psbt, UnhardenedIndex, u64, u32, f32), pay::Error> {
-        let wallet = self.model.wallet();
+    fn save_prefs(&mut self) {
+        if let Err(err) = self.model.save_prefs() {
+            error_dlg(
+                self.widgets.as_root(),
+                "Error saving wallet",
+                "It was impossible to save changes to the wallet preferences due to an error",
+                Some(&err.to_string()),
+            )
+        }
+    }
 
+    pub fn compose_psbt(
+        &mut self,
+    ) -> Result<(Psbt, usize, UnhardenedIndex, u64, u32, f32), pay::Error> {
         let output_count = self.model.beneficiaries().n_items();
         let mut txouts = Vec::with_capacity(output_count as usize);
-        let mut output_value = 0u64;
         let mut output_max = None;
         for no in 0..output_count {
             let beneficiary = self
@@ -100,7 +127,6 @@ impl Component {
                 }
                 value
             };
-            output_value += value;
             txouts.push(TxOut {
                 script_pubkey,
                 value,
@@ -108,9 +134,75 @@ impl Component {
         }
 
         // TODO: Support constructing PSBTs from multiple descriptors (at descriptor-wallet lib)
-        let (descriptor, _) = self.model.as_settings().descriptors_all()?;
+        //       Until then each transaction spends coins of a single descriptor class: the first
+        //       class able to fund the payment, or the one with the largest balance when
+        //       spending the maximum amount (which is the maximum shown in the payment dialog).
+        let manual = !self.model.coin_selection().is_empty();
+        let mut classes = self
+            .model
+            .spendable_classes()?
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        // Wallets with a single descriptor class and no restrictions on the coins use the coin
+        // selection of the wallet itself
+        let wallet_select = classes.len() == 1 && !manual && self.model.frozen_value() == 0;
+        classes.retain(|(_, (_, utxos))| !utxos.is_empty());
+        if manual && classes.len() > 1 {
+            // Manually selected coins are spent all together
+            return Err(pay::Error::MixedCoinClasses);
+        }
+        let split = classes.len() > 1;
+        if output_max.is_some() {
+            classes.sort_by_key(|(_, (_, utxos))| {
+                Reverse(utxos.iter().map(|prevout| prevout.amount).sum::<u64>())
+            });
+        }
+        let selection = match (manual || output_max.is_some(), wallet_select) {
+            (true, _) => CoinSelection::All,
+            (false, true) => CoinSelection::Wallet,
+            (false, false) => CoinSelection::LargestFirst,
+        };
+        let mut error = None;
+        for (class_no, (descriptor, utxos)) in classes {
+            let change_index = self.model.next_change_index(class_no, &descriptor);
+            match self.compose_class_psbt(
+                &descriptor,
+                &utxos,
+                selection,
+                change_index,
+                txouts.clone(),
+                output_max,
+            ) {
+                Ok((psbt, output_value, fee, vsize)) => {
+                    return Ok((psbt, class_no, change_index, output_value, fee, vsize))
+                }
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        match error {
+            Some(pay::Error::InsufficientFunds) if split => {
+                Err(pay::Error::SplitFunds(self.model.max_payable()))
+            }
+            Some(err) => Err(err),
+            None => Err(pay::Error::InsufficientFunds),
+        }
+    }
+
+    fn compose_class_psbt(
+        &self,
+        descriptor: &Descriptor<DerivationAccount>,
+        utxos: &BTreeSet<Prevout>,
+        selection: CoinSelection,
+        change_index: UnhardenedIndex,
+        mut txouts: Vec<TxOut>,
+        output_max: Option<u32>,
+    ) -> Result<(Psbt, u64, u32, f32), pay::Error> {
+        let wallet = self.model.wallet();
+        let mut output_value = txouts.iter().map(|txout| txout.value).sum::<u64>();
         let lock_time = LockTime::from_height(734438).expect("hardcoded height");
-        let change_index = wallet.next_change_index();
 
         let fee_rate = self.model.fee_rate();
         let mut fee = DUST_RELAY_TX_FEE;
@@ -121,18 +213,19 @@ impl Component {
         let mut vsize = 0.0f32;
         while fee != prev_fee {
             prev_fee = fee;
-            if output_max.is_some() {
-                prevouts = wallet
-                    .utxos()
-                    .iter()
-                    .map(Prevout::from)
-                    .collect::<BTreeSet<_>>();
-            } else {
-                prevouts = wallet
-                    .coinselect(output_value + fee as u64)
-                    .ok_or(pay::Error::InsufficientFunds)?
-                    .0;
-            }
+            let value = output_value + fee as u64;
+            prevouts = match selection {
+                CoinSelection::All => utxos.clone(),
+                CoinSelection::Wallet => {
+                    wallet
+                        .coinselect(value)
+                        .ok_or(pay::Error::InsufficientFunds)?
+                        .0
+                }
+                CoinSelection::LargestFirst => {
+                    coinselect(utxos, value).ok_or(pay::Error::InsufficientFunds)?
+                }
+            };
             let txins = prevouts
                 .iter()
                 .map(|p| TxIn {
@@ -165,6 +258,8 @@ impl Component {
             let max_value = input_value - output_value - fee as u64;
             txouts[vout as usize].value = max_value;
             output_value += max_value;
+        } else if output_value + fee as u64 > input_value {
+            return Err(pay::Error::InsufficientCoins);
         }
         if output_value == 0 {
             return Err(pay::Error::NoBeneficiaries);
@@ -186,13 +281,14 @@ impl Component {
             .collect::<Vec<_>>();
 
         let mut psbt = Psbt::construct(
-            &descriptor,
+            descriptor,
             &inputs,
             &outputs,
             change_index,
             fee as u64,
             wallet,
         )?;
+        check_change(&psbt, outputs.len(), descriptor, change_index)?;
         psbt.fallback_locktime = Some(LockTime::from_consensus(lock_time.into_consensus()));
         psbt.lex_order();
 
@@ -200,19 +296,348 @@ impl Component {
             psbt.set_signer_name(signer.master_fp, &signer.name);
         }
 
-        Ok((psbt, change_index, output_value, fee, vsize))
+        Ok((psbt, output_value, fee, vsize))
+    }
+
+    /// Composes a transaction replacing the unconfirmed outgoing transaction,
+    /// which pays the same beneficiaries with the given fee rate, raised to the
+    /// minimum required for the replacement if needed. The fee is paid by
+    /// reducing the change or, if it is not enough, by adding more confirmed
+    /// coins of the same descriptor class.
+    pub fn compose_bump_psbt(
+        &self,
+        txid: Txid,
+        fee_rate: f32,
+    ) -> Result<(Psbt, usize, UnhardenedIndex), pay::Error> {
+        let wallet = self.model.wallet();
+        let transactions = self.model.transactions();
+        let tx = transactions
+            .get(&txid)
+            .ok_or(pay::Error::UnknownTransaction)?;
+        if !wallet.history().iter().any(|item| {
+            item.onchain.txid == txid && matches!(item.onchain.status, OnchainStatus::Mempool)
+        }) {
+            return Err(pay::Error::NotInMempool);
+        }
+        // Only descendants known to the wallet can be detected here
+        bump::check_replaceable(tx, transactions)?;
+
+        let addresses = wallet
+            .address_info(true)
+            .into_iter()
+            .map(|info| (info.addr_src.address.script_pubkey(), info.addr_src))
+            .collect::<BTreeMap<_, _>>();
+
+        // All inputs of the original transaction must be ours
+        let mut sources = Vec::with_capacity(tx.input.len());
+        let mut orig_input_value = 0u64;
+        for txin in &tx.input {
+            let prev = txin.previous_output;
+            let txout = transactions
+                .get(&prev.txid)
+                .and_then(|prev_tx| prev_tx.output.get(prev.vout as usize))
+                .ok_or(pay::Error::ForeignInputs)?;
+            let source = addresses
+                .get(&txout.script_pubkey)
+                .ok_or(pay::Error::ForeignInputs)?;
+            orig_input_value += txout.value;
+            sources.push((prev, *source, txout.value));
+        }
+        let (class_no, descriptor) = wallet_descriptors(self.model.as_settings())?
+            .into_iter()
+            .enumerate()
+            .find(|(_, descriptor)| {
+                sources
+                    .iter()
+                    .all(|(_, source, _)| descriptor_owns(descriptor, source))
+            })
+            .ok_or(pay::Error::MixedCoinClasses)?;
+
+        // Change is returned to the same change address, all other outputs are
+        // kept intact
+        let (change, payments): (Vec<_>, Vec<_>) = tx.output.iter().partition(|txout| {
+            addresses
+                .get(&txout.script_pubkey)
+                .map(|source| source.change.first_index() == 1)
+                .unwrap_or_default()
+        });
+        let change_index = change
+            .first()
+            .and_then(|txout| addresses.get(&txout.script_pubkey))
+            .map(|source| source.index)
+            .unwrap_or_else(|| self.model.next_change_index(class_no, &descriptor));
+        let payments = payments.into_iter().cloned().collect::<Vec<_>>();
+        let payment_value = payments.iter().map(|txout| txout.value).sum::<u64>();
+        let change_script = derive_script(&descriptor, true, change_index)?;
+
+        let orig_fee = orig_input_value
+            .checked_sub(tx.output.iter().map(|txout| txout.value).sum())
+            .ok_or(pay::Error::ForeignInputs)?;
+        let fee_rate = bump::replacement_fee_rate(fee_rate, orig_fee, tx.vsize());
+
+        // BIP125 rule 2: coins added to the replacement must be confirmed
+        let mut extra = wallet
+            .utxos()
+            .iter()
+            .filter(|utxo| matches!(utxo.onchain.status, OnchainStatus::Blockchain(_)))
+            .filter(|utxo| descriptor_owns(&descriptor, &utxo.addr_src))
+            .map(Prevout::from)
+            .filter(|prevout| !self.model.is_frozen(prevout.outpoint))
+            .collect::<Vec<_>>();
+        extra.sort_by_key(|prevout| prevout.amount);
+
+        let satisfaction_vsize =
+            descriptor.max_satisfaction_weight()? as f32 / WITNESS_SCALE_FACTOR as f32;
+        let mut added = vec![];
+        let fee = loop {
+            let input_value =
+                orig_input_value + added.iter().map(|p: &Prevout| p.amount).sum::<u64>();
+            let mut output = payments.clone();
+            output.push(TxOut {
+                script_pubkey: change_script.clone().into(),
+                value: 0,
+            });
+            let draft = Transaction {
+                version: tx.version,
+                lock_time: tx.lock_time,
+                input: sources
+                    .iter()
+                    .map(|(outpoint, ..)| *outpoint)
+                    .chain(added.iter().map(|prevout| prevout.outpoint))
+                    .map(|previous_output| TxIn {
+                        previous_output,
+                        script_sig: none!(),
+                        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                        witness: none!(),
+                    })
+                    .collect(),
+                output,
+            };
+            let vsize = draft.vsize() as f32 + satisfaction_vsize * draft.input.len() as f32;
+            let fee = bump::replacement_fee(fee_rate, vsize, orig_fee);
+            match bump::fee_with_change(input_value, payment_value, fee) {
+                Some(fee) => break fee,
+                None => added.push(extra.pop().ok_or(pay::Error::InsufficientFunds)?),
+            }
+        };
+
+        let inputs = sources
+            .iter()
+            .map(|(outpoint, source, _)| InputDescriptor {
+                outpoint: *outpoint,
+                terminal: [source.change, source.index].into_iter().collect(),
+                seq_no: SeqNo::rbf(),
+                tweak: None,
+                sighash_type: EcdsaSighashType::All,
+            })
+            .chain(added.into_iter().map(|prevout| InputDescriptor {
+                outpoint: prevout.outpoint,
+                terminal: prevout.terminal(),
+                seq_no: SeqNo::rbf(),
+                tweak: None,
+                sighash_type: EcdsaSighashType::All,
+            }))
+            .collect::<Vec<_>>();
+        let outputs = payments
+            .into_iter()
+            .map(|txout| (PubkeyScript::from(txout.script_pubkey), txout.value))
+            .collect::<Vec<_>>();
+
+        let mut psbt = Psbt::construct(&descriptor, &inputs, &outputs, change_index, fee, wallet)?;
+        check_change(&psbt, outputs.len(), &descriptor, change_index)?;
+        psbt.fallback_locktime = Some(LockTime::from_consensus(tx.lock_time.to_u32()));
+        psbt.lex_order();
+
+        for signer in self.model.as_settings().signers() {
+            psbt.set_signer_name(signer.master_fp, &signer.name);
+        }
+
+        Ok((psbt, class_no, change_index))
+    }
+
+    /// Composes a child transaction spending an unconfirmed coin back to the
+    /// wallet, whose fee brings the fee rate of the package consisting of the
+    /// child transaction and all its unconfirmed ancestors to the given rate.
+    ///
+    /// Fees of all ancestors must be known. Only ancestors which belong to the
+    /// wallet are detected; others are not accounted for.
+    pub fn compose_cpfp_psbt(
+        &self,
+        outpoint: OutPoint,
+        fee_rate: f32,
+    ) -> Result<(Psbt, usize, UnhardenedIndex), pay::Error> {
+        let wallet = self.model.wallet();
+        let transactions = self.model.transactions();
+        let utxo = wallet
+            .utxos()
+            .iter()
+            .find(|utxo| Prevout::from(*utxo).outpoint == outpoint)
+            .ok_or(pay::Error::UnknownTransaction)?;
+        if !matches!(utxo.onchain.status, OnchainStatus::Mempool) {
+            return Err(pay::Error::NotInMempool);
+        }
+        let prevout = Prevout::from(utxo);
+        let parent = transactions
+            .get(&outpoint.txid)
+            .ok_or(pay::Error::UnknownTransaction)?;
+        let (ancestors_vsize, ancestors_fee) = self.unconfirmed_ancestry(outpoint.txid)?;
+
+        let (class_no, descriptor) = wallet_descriptors(self.model.as_settings())?
+            .into_iter()
+            .enumerate()
+            .find(|(_, descriptor)| descriptor_owns(descriptor, &utxo.addr_src))
+            .ok_or(pay::Error::ForeignInputs)?;
+        let change_index = self.model.next_change_index(class_no, &descriptor);
+        let change_script = derive_script(&descriptor, true, change_index)?;
+
+        let child = Transaction {
+            version: 1,
+            lock_time: parent.lock_time,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: none!(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: none!(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: change_script.into(),
+                value: 0,
+            }],
+        };
+        let vsize = child.vsize() as f32
+            + descriptor.max_satisfaction_weight()? as f32 / WITNESS_SCALE_FACTOR as f32;
+        let fee = bump::cpfp_fee(fee_rate, ancestors_vsize, ancestors_fee, vsize);
+        if prevout.amount < fee + CHANGE_DUST_LIMIT {
+            return Err(pay::Error::NoFundsForFee);
+        }
+
+        let inputs = [InputDescriptor {
+            outpoint,
+            terminal: prevout.terminal(),
+            seq_no: SeqNo::rbf(),
+            tweak: None,
+            sighash_type: EcdsaSighashType::All,
+        }];
+
+        let mut psbt = Psbt::construct(&descriptor, &inputs, &[], change_index, fee, wallet)?;
+        check_change(&psbt, 0, &descriptor, change_index)?;
+        psbt.fallback_locktime = Some(LockTime::from_consensus(parent.lock_time.to_u32()));
+        psbt.lex_order();
+
+        for signer in self.model.as_settings().signers() {
+            psbt.set_signer_name(signer.master_fp, &signer.name);
+        }
+
+        Ok((psbt, class_no, change_index))
+    }
+
+    /// Returns total virtual size and fee of the unconfirmed wallet transaction
+    /// together with all its unconfirmed ancestors which belong to the wallet.
+    fn unconfirmed_ancestry(&self, txid: Txid) -> Result<(usize, u64), pay::Error> {
+        let mempool = self
+            .model
+            .wallet()
+            .history()
+            .iter()
+            .filter(|item| matches!(item.onchain.status, OnchainStatus::Mempool))
+            .map(|item| item.onchain.txid)
+            .collect::<BTreeSet<_>>();
+        bump::unconfirmed_ancestry(
+            txid,
+            self.model.transactions(),
+            &mempool,
+            self.model.mempool_fees(),
+        )
+        .map_err(pay::Error::from)
     }
 
-    pub fn sync_pay(&mut self) -> Option<(Psbt, UnhardenedIndex)> {
+    /// Describes fee rate estimates for the user choosing a fee rate.
+    fn fee_rate_details(&self) -> String {
+        format!(
+            "Current fee rate estimates are {:.1} sat/vbyte for priority, {:.1} for normal and \
+             {:.1} for economy confirmation.",
+            self.model.target_fee_rate(FeeRate::Priority),
+            self.model.target_fee_rate(FeeRate::Normal),
+            self.model.target_fee_rate(FeeRate::Economy),
+        )
+    }
+
+    fn bump_fee(&mut self, txid: Txid) {
+        let fee_rate = match fee_rate_dlg(
+            self.widgets.as_root(),
+            "Bump fee",
+            "Fee rate of the replacement transaction, sat/vbyte:",
+            &format!(
+                "{}\nThe fee rate is raised if it is below the minimum required to replace the \
+                 transaction.",
+                self.fee_rate_details()
+            ),
+            self.model.target_fee_rate(FeeRate::Priority),
+        ) {
+            Some(fee_rate) => fee_rate,
+            None => return,
+        };
+        match self.compose_bump_psbt(txid, fee_rate) {
+            Ok((psbt, class_no, change_index)) => self.open_psbt(psbt, class_no, change_index),
+            Err(err) => error_dlg(
+                self.widgets.as_root(),
+                "Unable to bump fee",
+                "It was impossible to create a replacement for the transaction",
+                Some(&err.to_string()),
+            ),
+        }
+    }
+
+    fn cpfp(&mut self, outpoint: OutPoint) {
+        let fee_rate = match fee_rate_dlg(
+            self.widgets.as_root(),
+            "Speed up transaction",
+            "Fee rate of the transaction together with its unconfirmed ancestors, sat/vbyte:",
+            &format!(
+                "{}\nThe new child transaction pays the fee required for the whole package to \
+                 reach this fee rate.",
+                self.fee_rate_details()
+            ),
+            self.model.target_fee_rate(FeeRate::Priority),
+        ) {
+            Some(fee_rate) => fee_rate,
+            None => return,
+        };
+        match self.compose_cpfp_psbt(outpoint, fee_rate) {
+            Ok((psbt, class_no, change_index)) => self.open_psbt(psbt, class_no, change_index),
+            Err(err) => error_dlg(
+                self.widgets.as_root(),
+                "Unable to speed up transaction",
+                "It was impossible to create a child transaction spending the coin",
+                Some(&err.to_string()),
+            ),
+        }
+    }
+
+    /// Opens the newly composed PSBT for signing and reserves its change
+    /// address.
+    fn open_psbt(&mut self, psbt: Psbt, class_no: usize, change_index: UnhardenedIndex) {
+        self.launcher_stream.as_ref().map(|stream| {
+            stream.emit(launch::Msg::CreatePsbt(
+                psbt,
+                self.model.as_settings().network(),
+            ))
+        });
+        if self.model.reserve_change_index(class_no, change_index) {
+            self.save();
+        }
+    }
+
+    pub fn sync_pay(&mut self) -> Option<(Psbt, usize, UnhardenedIndex)> {
         match self.compose_psbt() {
-            Ok((psbt, change_index, output_value, fee, vsize)) => {
+            Ok((psbt, class_no, change_index, output_value, fee, vsize)) => {
                 self.pay_widgets.hide_message();
                 self.pay_widgets.update_info(
                     self.model.fee_rate(),
-                    self.model.wallet().ephemerals().fees,
+                    self.model.fees(),
                     Some((output_value, fee, vsize)),
                 );
-                Some((psbt, change_index))
+                Some((psbt, class_no, change_index))
             }
             Err(err) => {
                 self.pay_widgets.show_error(&err.to_string());
@@ -221,25 +646,66 @@ impl Component {
         }
     }
 
+    fn update_history_fiat(&mut self) {
+        self.widgets.update_history_fiat(
+            self.model.wallet().history(),
+            &self.model.price_history,
+            self.model.fiat,
+            self.model.exchange_rate,
+        );
+    }
+
+    /// Requests price history covering all wallet transactions, if it was not
+    /// requested yet.
+    fn request_price_history(&mut self) {
+        let since = self
+            .model
+            .wallet()
+            .history()
+            .iter()
+            .filter_map(|item| item.onchain.date_time())
+            .map(|dt| dt.timestamp())
+            .min()
+            .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0))
+            .map(|dt| dt.date());
+        if let Some(since) = since {
+            if self.prices_since.map(|date| since < date).unwrap_or(true) {
+                self.prices_since = Some(since);
+                self.exchange_worker.fetch_history(since);
+            }
+        }
+    }
+
     fn handle_exchange(&mut self, msg: exchange::Msg) {
         match msg {
-            exchange::Msg::Rate(fiat, exchange, rate) => {
-                self.model.fiat = fiat;
-                self.model.exchange = exchange;
-                self.model.exchange_rate = rate;
-                self.widgets.update_exchange_rate(
-                    fiat,
-                    exchange,
-                    rate,
-                    self.model.wallet().state(),
-                );
-            }
-            exchange::Msg::Error(err) => {
-                self.widgets.update_exchange_error(err);
+            // Rates for the previously selected currency may still arrive
+            exchange::Msg::Rate(rate) if rate.fiat == self.model.fiat => {
+                let stale = rate.is_stale(self.model.prefs().rate_stale_period);
+                self.model.exchange_rate = rate.rate;
+                self.widgets
+                    .update_exchange_rate(&rate, stale, None, self.model.spendable_state());
+                self.model.last_rate = Some(rate);
+                self.update_history_fiat();
             }
-            exchange::Msg::ChannelDisconnected => {
-                panic!("Broken exchange thread")
+            exchange::Msg::Rate(_) => {}
+            exchange::Msg::History(fiat, history) if fiat == self.model.fiat => {
+                self.model.price_history = history;
+                self.update_history_fiat();
             }
+            exchange::Msg::History(..) => {}
+            // Last known rate is kept until it gets outdated
+            exchange::Msg::Error(err) => match self.model.last_rate {
+                Some(ref rate) if rate.fiat == self.model.fiat => {
+                    let stale = rate.is_stale(self.model.prefs().rate_stale_period);
+                    self.widgets.update_exchange_rate(
+                        rate,
+                        stale,
+                        Some(&err),
+                        self.model.spendable_state(),
+                    );
+                }
+                _ => self.widgets.update_exchange_error(err),
+            },
         }
     }
 
@@ -256,61 +722,118 @@ impl Component {
             electrum::Msg::LastBlock(block_info) => {
                 self.widgets
                     .update_electrum_state(ElectrumState::RetrievingFees);
-                self.model.wallet_mut().update_last_block(&block_info);
+                self.model
+                    .wallet_mut()
+                    .update_last_block(&block_info.into());
                 self.widgets.update_last_block(&block_info);
             }
             electrum::Msg::LastBlockUpdate(block_info) => {
-                self.model.wallet_mut().update_last_block(&block_info);
+                self.model
+                    .wallet_mut()
+                    .update_last_block(&block_info.into());
                 self.widgets.update_last_block(&block_info);
             }
-            electrum::Msg::FeeEstimate(f0, f1, f2) => {
+            electrum::Msg::FeeEstimate(fees) => {
                 self.widgets
-                    .update_electrum_state(ElectrumState::RetrievingHistory(0));
-                let wallet = self.model.wallet_mut();
-                wallet.update_fees(f0, f1, f2);
-                wallet.clear_utxos();
+                    .update_electrum_state(ElectrumState::RetrievingHistory(0, 0));
+                self.model.update_fees(fees);
             }
-            electrum::Msg::TxidBatch(batch, no) => {
-                self.widgets
-                    .update_electrum_state(ElectrumState::RetrievingHistory(no as usize * 2));
+            electrum::Msg::CachedFees(fees) => self.model.update_fees(fees),
+            electrum::Msg::TxidBatch(batch) => {
                 self.addr_buffer.extend(batch);
             }
-            electrum::Msg::UtxoBatch(batch, no) => {
-                self.widgets
-                    .update_electrum_state(ElectrumState::RetrievingHistory(no as usize * 2 + 1));
-                let wallet = self.model.wallet_mut();
-                wallet.update_utxos(batch);
-                self.widgets.update_outpoints(&mut self.model);
-                self.widgets.update_balance(&mut self.model);
+            electrum::Msg::UtxoBatch(batch) => {
+                self.utxo_buffer.extend(batch);
             }
-            electrum::Msg::TxBatch(batch, progress) => {
-                self.widgets
-                    .update_electrum_state(ElectrumState::RetrievingTransactions(progress));
+            electrum::Msg::TxBatch(batch) => {
                 self.tx_buffer.extend(batch);
             }
-            electrum::Msg::Complete => {
-                self.model
-                    .wallet_mut()
-                    .update_complete(&self.addr_buffer, &self.tx_buffer);
+            electrum::Msg::Progress(progress) => {
+                self.widgets
+                    .update_electrum_state(match progress.scan_complete {
+                        false => ElectrumState::RetrievingHistory(
+                            progress.scripts_scanned,
+                            progress.txs_total,
+                        ),
+                        true => ElectrumState::RetrievingTransactions(
+                            progress.txs_fetched,
+                            progress.txs_total,
+                        ),
+                    });
+            }
+            electrum::Msg::Cancelled => {
                 self.addr_buffer.clear();
+                self.utxo_buffer.clear();
                 self.tx_buffer.clear();
+                self.widgets.update_electrum_state(ElectrumState::Cancelled);
+            }
+            electrum::Msg::Complete => {
+                let wallet = self.model.wallet_mut();
+                wallet.clear_utxos();
+                wallet.update_utxos(std::mem::take(&mut self.utxo_buffer));
+                wallet.update_complete(&self.addr_buffer, &self.tx_buffer);
+                self.addr_buffer.clear();
+                self.model
+                    .update_transactions(std::mem::take(&mut self.tx_buffer));
                 self.save();
 
+                self.model.prune_coin_selection();
+                if self.model.prune_frozen_coins() {
+                    self.save_prefs();
+                }
+                self.widgets.update_outpoints(&mut self.model);
                 self.widgets.update_balance(&mut self.model);
                 let wallet = self.model.wallet_mut();
-                self.widgets.update_history(&wallet.history());
-                self.widgets.update_addresses(&wallet.address_info(true));
-                self.widgets.update_electrum_state(ElectrumState::Complete(
-                    self.model.as_settings().electrum().sec,
-                ));
+                self.widgets
+                    .update_history(wallet.history(), &self.unverified);
+                self.update_history_fiat();
+                self.request_price_history();
+                let wallet = self.model.wallet_mut();
+                self.widgets
+                    .update_addresses(&wallet.address_info(true), self.model.prefs().gap_limit);
+                self.widgets
+                    .update_electrum_state(match self.invalid_proofs.len() {
+                        0 => ElectrumState::Complete(self.model.backend_sec()),
+                        count => ElectrumState::InvalidProofs(count),
+                    });
+            }
+            electrum::Msg::ServerConnecting(server, retries) => {
+                self.widgets.update_electrum_state(match retries {
+                    0 => ElectrumState::ServerConnecting(server),
+                    _ => ElectrumState::Reconnecting(server, retries),
+                });
+            }
+            electrum::Msg::ServerConnected(server, retries) => {
+                self.widgets.update_active_server(&server);
+                self.widgets.update_electrum_state(match retries {
+                    0 => ElectrumState::ServerConnected(server),
+                    _ => ElectrumState::Reconnected(server, retries),
+                });
+            }
+            electrum::Msg::Verification(unverified, invalid) => {
+                self.unverified = unverified;
+                self.invalid_proofs = invalid;
+            }
+            electrum::Msg::MempoolFees(fees) => self.model.update_mempool_fees(fees),
+            electrum::Msg::Reorg(height) => {
+                self.widgets
+                    .update_electrum_state(ElectrumState::Reorg(height));
+            }
+            electrum::Msg::AddressActivity(addresses) => {
+                self.widgets
+                    .update_electrum_state(ElectrumState::AddressActivity(addresses.len()));
+            }
+            electrum::Msg::Offline(synced_at) => {
+                let age = synced_at
+                    .map(|time| format!("data from {}", format_data_age(time)))
+                    .unwrap_or_else(|| s!("never synchronized"));
+                self.widgets
+                    .update_electrum_state(ElectrumState::Offline(age));
             }
             electrum::Msg::Error(err) => {
                 self.widgets
                     .update_electrum_state(ElectrumState::Error(err.to_string()));
             }
-            electrum::Msg::ChannelDisconnected => {
-                panic!("Broken electrum thread")
-            }
         }
     }
 }
@@ -346,6 +869,7 @@ impl Update for Component {
             }
             Msg::Duplicate => {
                 let settings = self.model.to_settings();
+                let prefs = self.model.prefs().clone();
                 let path: PathBuf = self.model.path().clone();
                 let new_path = format!(
                     "{}-copy.mcw",
@@ -353,7 +877,7 @@ impl Update for Component {
                 );
                 self.launcher_stream
                     .as_ref()
-                    .map(|stream| stream.emit(launch::Msg::Duplicate(settings, new_path)));
+                    .map(|stream| stream.emit(launch::Msg::Duplicate(settings, prefs, new_path)));
             }
             Msg::Import => {
                 self.launcher_stream
@@ -369,16 +893,54 @@ impl Update for Component {
             Msg::Pay(msg) => self.update_pay(msg),
             Msg::Settings => self.settings.emit(settings::Msg::View(
                 self.model.to_settings(),
+                self.model.prefs().clone(),
                 self.model.path().clone(),
             )),
-            Msg::Fiat(fiat) => {
-                self.model.fiat = fiat;
+            Msg::Fiat(fiat) if fiat != self.model.fiat => {
+                self.model.set_fiat(fiat);
+                self.model.exchange_rate = 0.0;
+                self.model.last_rate = None;
+                self.model.price_history = none!();
+                self.update_history_fiat();
                 self.widgets.update_fiat(fiat);
                 self.exchange_worker.set_fiat(fiat);
+                self.save_prefs();
             }
+            Msg::Fiat(_) => {}
+            Msg::Exchange(exchange) if exchange != self.model.exchange => {
+                self.model.set_exchange(exchange);
+                self.widgets.update_fiat(self.model.fiat);
+                self.exchange_worker
+                    .set_source(self.model.prefs().rate_source());
+                self.save_prefs();
+            }
+            Msg::Exchange(_) => {}
+            Msg::MedianRate(median) if median != self.model.prefs().median_rate => {
+                self.model.set_median_rate(median);
+                self.widgets.update_median_rate(median);
+                self.widgets.update_fiat(self.model.fiat);
+                self.exchange_worker
+                    .set_source(self.model.prefs().rate_source());
+                self.save_prefs();
+            }
+            Msg::MedianRate(_) => {}
             Msg::ExchangeRefresh(msg) => {
                 self.handle_exchange(msg);
             }
+            Msg::CoinSelect(outpoint, spend) => {
+                self.model.select_coin(outpoint, spend);
+            }
+            Msg::BumpFee(txid) => self.bump_fee(txid),
+            Msg::Cpfp(outpoint) => self.cpfp(outpoint),
+            Msg::CoinFreeze(outpoint, frozen) => {
+                self.model.freeze_coin(outpoint, frozen);
+                self.save_prefs();
+                self.widgets.update_balance(&mut self.model);
+            }
+            Msg::CoinSelectClear => {
+                self.model.clear_coin_selection();
+                self.widgets.update_outpoints(&mut self.model);
+            }
             Msg::EditLabel(txid, label) => {
                 self.model
                     .wallet_mut()
@@ -389,10 +951,20 @@ impl Update for Component {
             Msg::Refresh => {
                 self.electrum_worker.sync();
             }
-            Msg::Update(signers, descriptor_classes, electrum) => {
+            Msg::CancelSync => {
+                self.electrum_worker.cancel();
+            }
+            Msg::Update(signers, descriptor_classes, electrum, prefs) => {
+                let offline_toggled = prefs.offline != self.model.prefs().offline;
+                let rate_source = self.model.prefs().rate_source();
+                self.electrum_worker.update_prefs(prefs.clone());
+                if offline_toggled {
+                    self.exchange_worker.set_offline(prefs.offline);
+                    self.electrum_worker.sync();
+                }
                 match self
                     .model
-                    .update_descriptor(signers, descriptor_classes, electrum)
+                    .update_descriptor(signers, descriptor_classes, electrum, prefs)
                 {
                     Err(err) => error_dlg(
                         self.widgets.as_root(),
@@ -401,13 +973,22 @@ impl Update for Component {
                         Some(&err.to_string()),
                     ),
                     Ok(new_server) => {
-                        new_server.map(|electrum| self.widgets.update_electrum_server(&electrum));
+                        if let Some(electrum) = new_server {
+                            self.electrum_worker.update(electrum.clone());
+                            self.widgets.update_electrum_server(electrum);
+                        }
                         self.widgets.show();
                         self.settings
                             .emit(settings::Msg::Response(ResponseType::Cancel));
                     }
                 }
+                if self.model.prefs().rate_source() != rate_source {
+                    self.exchange_worker
+                        .set_source(self.model.prefs().rate_source());
+                }
                 self.save();
+                self.save_prefs();
+                self.widgets.update_invoice(&mut self.model);
             }
             Msg::InvoiceAmountToggle(set) => {
                 self.model.as_invoice_mut().amount = match set {
@@ -448,6 +1029,7 @@ impl Update for Component {
             Msg::RegisterLauncher(stream) => {
                 self.launcher_stream = Some(stream);
             }
+            Msg::ProxyChanged => self.electrum_worker.reconnect(),
             Msg::ElectrumWatch(msg) => self.handle_electrum(msg),
         }
     }
@@ -462,31 +1044,17 @@ impl Component {
                     .beneficiaries_mut()
                     .append(&Beneficiary::default());
                 self.model
-                    .set_fee_rate(self.model.wallet().ephemerals().fees.0);
+                    .set_fee_rate(self.model.target_fee_rate(FeeRate::Normal));
                 self.pay_widgets.init_ui(&self.model);
                 self.pay_widgets.show();
             }
             pay::Msg::Response(ResponseType::Ok) => {
-                let (psbt, change_index) = match self.sync_pay() {
+                let (psbt, class_no, change_index) = match self.sync_pay() {
                     Some(data) => data,
                     None => return,
                 };
                 self.pay_widgets.hide();
-                self.launcher_stream.as_ref().map(|stream| {
-                    stream.emit(launch::Msg::CreatePsbt(
-                        psbt,
-                        self.model.as_settings().network(),
-                    ))
-                });
-                // Update latest change index in wallet settings by sending message to the wallet
-                // component
-                if self
-                    .model
-                    .wallet_mut()
-                    .update_next_change_index(change_index)
-                {
-                    self.save();
-                }
+                self.open_psbt(psbt, class_no, change_index);
             }
             pay::Msg::Response(ResponseType::Cancel) => {
                 self.pay_widgets.hide();
@@ -518,13 +1086,7 @@ impl Component {
                 self.model.set_fee_rate(fee_rate as f32);
             }
             pay::Msg::FeeSetBlocks(ty) => {
-                let fees = self.model.wallet().ephemerals().fees;
-                let fee_rate = match ty {
-                    FeeRate::OneBlock => fees.0,
-                    FeeRate::TwoBlocks => fees.1,
-                    FeeRate::ThreeBlocks => fees.2,
-                    FeeRate::Unknown => unreachable!(),
-                };
+                let fee_rate = self.model.target_fee_rate(ty);
                 if fee_rate == self.model.fee_rate() {
                     return;
                 }
@@ -554,19 +1116,45 @@ impl Widget for Component {
         let stream = relm.stream().clone();
         let (electrum_channel, sender) =
             Channel::new(move |msg| stream.emit(Msg::ElectrumWatch(msg)));
-        let electrum_worker = ElectrumWorker::with(sender, model.wallet().to_settings(), 60)
-            .expect("unable to instantiate electrum thread");
+        let electrum_worker = ElectrumWorker::with(
+            sender,
+            model.wallet().to_settings(),
+            model.prefs().clone(),
+            model.path().clone(),
+        )
+        .expect("unable to instantiate electrum thread");
 
         let stream = relm.stream().clone();
         let (exchange_channel, sender) =
             Channel::new(move |msg| stream.emit(Msg::ExchangeRefresh(msg)));
-        let exchange_worker = ExchangeWorker::with(sender, model.exchange(), model.fiat(), 600)
-            .expect("unable to instantiate exchange thread");
+        let exchange_worker = ExchangeWorker::with(
+            sender,
+            model.prefs().rate_source(),
+            model.fiat(),
+            model.prefs().offline,
+            &model
+                .path()
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            600,
+        )
+        .expect("unable to instantiate exchange thread");
 
         widgets.connect(relm);
         widgets.init_ui(&mut model);
         widgets.show();
 
+        if let Some(err) = model.take_prefs_error() {
+            error_dlg(
+                widgets.as_root(),
+                "Wallet preferences",
+                "Unable to read wallet preferences; default preferences are used instead and will \
+                 replace the existing ones once changed",
+                Some(&err.to_string()),
+            );
+        }
+
         let glade_src = include_str!("pay/pay.glade");
         let pay_widgets = pay::Widgets::from_string(glade_src).expect("glade file broken");
         pay_widgets.connect(relm);
@@ -587,8 +1175,61 @@ impl Widget for Component {
             electrum_worker,
             tx_buffer: empty!(),
             addr_buffer: empty!(),
+            utxo_buffer: empty!(),
+            unverified: empty!(),
+            invalid_proofs: empty!(),
+            prices_since: None,
 
             launcher_stream: None,
         }
     }
 }
+
+/// Selects the largest coins until their value reaches the target amount.
+fn coinselect(utxos: &BTreeSet<Prevout>, value: u64) -> Option<BTreeSet<Prevout>> {
+    let mut sorted = utxos.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|prevout| Reverse(prevout.amount));
+    let mut selected = bset! {};
+    let mut total = 0u64;
+    for prevout in sorted {
+        if total >= value {
+            break;
+        }
+        total += prevout.amount;
+        selected.insert(*prevout);
+    }
+    (total >= value).then(|| selected)
+}
+
+/// Way of selecting coins of a descriptor class for a payment.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CoinSelection {
+    /// All coins are spent.
+    All,
+    /// Coins are selected by the wallet.
+    Wallet,
+    /// The largest coins are selected first.
+    LargestFirst,
+}
+
+/// Checks that the change output added by the PSBT constructor after the
+/// payment outputs pays to the same script as the wallet derives for the
+/// change index with [`derive_script`], which derives additional wildcard
+/// steps of multi-class descriptors at zero index.
+fn check_change(
+    psbt: &Psbt,
+    payment_count: usize,
+    descriptor: &Descriptor<DerivationAccount>,
+    change_index: UnhardenedIndex,
+) -> Result<(), pay::Error> {
+    let tx = psbt.to_unsigned_tx();
+    match tx.output.get(payment_count) {
+        Some(txout)
+            if &txout.script_pubkey
+                != derive_script(descriptor, true, change_index)?.as_inner() =>
+        {
+            Err(pay::Error::ChangeMismatch)
+        }
+        _ => Ok(()),
+    }
+}
@@ -16,7 +16,7 @@ mod widget;
 
 use std::collections::BTreeSet;
 
-use bitcoin::Txid;
+use bitcoin::{OutPoint, Txid};
 use bpro::{ElectrumSec, ElectrumServer, Signer};
 use relm::StreamHandle;
 pub(super) use view_model::ViewModel;
@@ -24,8 +24,8 @@ use wallet::descriptors::DescriptorClass;
 pub(self) use widget::Widgets;
 
 pub use self::component::Component;
+use crate::model::{Exchange, Fiat, WalletPrefs};
 use crate::view::launch;
-use crate::worker::exchange::Fiat;
 use crate::worker::{electrum, exchange};
 
 #[derive(Msg)]
@@ -39,11 +39,24 @@ pub enum Msg {
     Import,
     Launch(launch::Msg),
     Settings,
-    Update(Vec<Signer>, BTreeSet<DescriptorClass>, ElectrumServer),
+    Update(
+        Vec<Signer>,
+        BTreeSet<DescriptorClass>,
+        ElectrumServer,
+        WalletPrefs,
+    ),
     Pay(pay::Msg),
     Fiat(Fiat),
+    Exchange(Exchange),
+    MedianRate(bool),
     Refresh,
+    CancelSync,
     EditLabel(Txid, String),
+    BumpFee(Txid),
+    Cpfp(OutPoint),
+    CoinSelect(OutPoint, bool),
+    CoinSelectClear,
+    CoinFreeze(OutPoint, bool),
     InvoiceAmountToggle(bool),
     InvoiceIndexToggle(bool),
     InvoiceAmount(f64),
@@ -51,6 +64,7 @@ pub enum Msg {
     ElectrumWatch(electrum::Msg),
     ExchangeRefresh(exchange::Msg),
     RegisterLauncher(StreamHandle<launch::Msg>),
+    ProxyChanged,
 }
 
 #[derive(Clone, PartialEq, Debug, Display)]
@@ -58,16 +72,34 @@ pub enum Msg {
 pub enum ElectrumState {
     /// Connecting to electrum server...
     Connecting,
+    /// Connecting to {0}...
+    ServerConnecting(String),
+    /// Reconnecting to {0} (attempt {1})...
+    Reconnecting(String, u32),
+    /// Connected to {0}
+    ServerConnected(String),
+    /// Connected to {0} after {1} failed attempt(s)
+    Reconnected(String, u32),
     /// Checking latest blockchain state...
     QueryingBlockchainState,
     /// Retrieving fee information...
     RetrievingFees,
-    /// Getting transaction history (batch {0})...
-    RetrievingHistory(usize),
-    /// Reading transactions: {0:.0}%
-    RetrievingTransactions(f32),
+    /// New transactions for {0} address(es), updating...
+    AddressActivity(usize),
+    /// Chain reorganization starting from block {0}, updating...
+    Reorg(u32),
+    /// Scanning addresses: {0} checked, {1} transaction(s) found...
+    RetrievingHistory(usize, usize),
+    /// Reading transactions: {0} of {1}...
+    RetrievingTransactions(usize, usize),
+    /// Synchronization cancelled
+    Cancelled,
     /// Ready
     Complete(ElectrumSec),
+    /// Server has not proven inclusion of {0} transaction(s) into the blocks
+    InvalidProofs(usize),
+    /// Offline mode; {0}
+    Offline(String),
     /// Electrum error: {0}
     Error(String),
 }
@@ -12,22 +12,52 @@
 pub(super) mod beneficiary_row;
 mod widget;
 
+use ::wallet::hd::DeriveError;
 use ::wallet::psbt;
 use bitcoin::util::address;
+use bitcoin::Txid;
 use gtk::ResponseType;
 pub(super) use widget::Widgets;
 
+use crate::model::bump::BumpError;
+use crate::model::FeeEstimates;
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
 #[display(doc_comments)]
 pub enum FeeRate {
-    /// In one block
-    OneBlock,
-    /// In two blocks
-    TwoBlocks,
-    /// In three blocks
-    ThreeBlocks,
-    /// Unknown
-    Unknown,
+    /// Priority
+    Priority,
+    /// Normal
+    Normal,
+    /// Economy
+    Economy,
+    /// Custom
+    Custom,
+}
+
+impl FeeRate {
+    /// Confirmation target in blocks.
+    pub fn target(self) -> Option<u32> {
+        match self {
+            FeeRate::Priority => Some(1),
+            FeeRate::Normal => Some(6),
+            FeeRate::Economy => Some(144),
+            FeeRate::Custom => None,
+        }
+    }
+
+    /// Detects the fastest confirmation target which the fee rate satisfies.
+    pub fn with(fee_rate: f32, fees: &FeeEstimates) -> FeeRate {
+        [FeeRate::Priority, FeeRate::Normal, FeeRate::Economy]
+            .into_iter()
+            .find(|ty| {
+                ty.target()
+                    .and_then(|target| fees.fee_rate(target))
+                    .map(|rate| fee_rate >= rate)
+                    .unwrap_or_default()
+            })
+            .unwrap_or(FeeRate::Custom)
+    }
 }
 
 #[derive(Msg)]
@@ -48,6 +78,22 @@ pub enum Error {
     /// Available wallet funds are insufficient to cover the transaction
     InsufficientFunds,
 
+    /// Selected coins are insufficient to cover the transaction; please select more coins in the
+    /// list of wallet coins.
+    InsufficientCoins,
+
+    /// Wallet funds are split between descriptor classes and none of them can cover the
+    /// transaction alone; at most {0} sats can be spent in a single transaction.
+    SplitFunds(u64),
+
+    /// Change address composed for the transaction does not match the one derived by the wallet;
+    /// please provide the developer with the wallet descriptor.
+    ChangeMismatch,
+
+    /// Selected coins belong to different descriptor classes, which can't be spent in a single
+    /// transaction yet.
+    MixedCoinClasses,
+
     /// One or more of beneficiaries has incorrect address (please see exclamation marks next to
     /// the addresses).
     #[from(address::Error)]
@@ -71,6 +117,12 @@ pub enum Error {
     #[from]
     PsbtConstruct(psbt::construct::Error),
 
+    /// Internal error in key derivation; please provide the developer with the following details:
+    ///
+    /// {0}
+    #[from]
+    Derive(DeriveError),
+
     /// Unable to compute proper fee.
     FeeFailure,
 
@@ -79,4 +131,35 @@ pub enum Error {
 
     /// Multiple outputs have flag "MAX" set.
     MultipleMaxOutputs,
+
+    /// The transaction is not known to the wallet; please wait for the wallet synchronization to
+    /// complete.
+    UnknownTransaction,
+
+    /// The transaction is not in the mempool anymore.
+    NotInMempool,
+
+    /// The transaction does not signal replaceability (BIP125) and can't be replaced.
+    NotReplaceable,
+
+    /// The transaction has unconfirmed descendants, which would be evicted by its replacement.
+    HasDescendants,
+
+    /// The transaction spends coins which do not belong to the wallet.
+    ForeignInputs,
+
+    /// Fee of the unconfirmed transaction {0} is unknown; the server does not report it and the
+    /// transactions it spends can't be retrieved.
+    UnknownFee(Txid),
+}
+
+impl From<BumpError> for Error {
+    fn from(err: BumpError) -> Self {
+        match err {
+            BumpError::UnknownTransaction(_) => Error::UnknownTransaction,
+            BumpError::NotReplaceable => Error::NotReplaceable,
+            BumpError::HasDescendants => Error::HasDescendants,
+            BumpError::UnknownFee(txid) => Error::UnknownFee(txid),
+        }
+    }
 }
@@ -18,6 +18,7 @@ use gtk::{
 use relm::Relm;
 
 use super::{beneficiary_row, FeeRate, Msg};
+use crate::model::{format_blocks_time, FeeEstimates};
 use crate::view::{wallet, NotificationBoxExt};
 
 // Create the structure that holds the widgets used in the view.
@@ -46,23 +47,33 @@ pub struct Widgets {
     fee_stp: SpinButton,
     fee_menu: Menu,
     time_lbl: Label,
-    block1_mi: MenuItem,
-    block2_mi: MenuItem,
-    block3_mi: MenuItem,
+    priority_mi: MenuItem,
+    normal_mi: MenuItem,
+    economy_mi: MenuItem,
 }
 
 impl Widgets {
     pub fn init_ui(&self, model: &wallet::ViewModel) {
-        self.header_bar.set_subtitle(Some(&format!(
-            "{:.08} BTC available",
-            model.wallet().state().balance as f64 / 100_000_000.0
-        )));
-
-        let fees = model.wallet().ephemerals().fees;
-        self.fee_adj.set_upper(fees.0 as f64 * 2.0);
-        self.fee_adj.set_lower(fees.2 as f64 / 10.0);
+        let balance = model.spendable_state().balance;
+        let max_payable = model.max_payable();
+        let subtitle = match model.selected_coins() {
+            // Coins of different descriptor classes can't be spent together, so the maximum
+            // payment may be below the balance
+            (0, _) if max_payable < balance => format!(
+                "{:.08} BTC available, up to {:.08} BTC in a single payment",
+                balance as f64 / 100_000_000.0,
+                max_payable as f64 / 100_000_000.0
+            ),
+            (0, _) => format!("{:.08} BTC available", balance as f64 / 100_000_000.0),
+            (count, value) => format!(
+                "Spending {} selected coin(s), {:.08} BTC",
+                count,
+                value as f64 / 100_000_000.0
+            ),
+        };
+        self.header_bar.set_subtitle(Some(&subtitle));
 
-        self.update_info(model.fee_rate(), fees, None);
+        self.update_info(model.fee_rate(), model.fees(), None);
     }
 
     pub fn show(&self) { self.dialog.show() }
@@ -119,21 +130,21 @@ impl Widgets {
         );
         connect!(
             relm,
-            self.block1_mi,
+            self.priority_mi,
             connect_activate(_),
-            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::OneBlock))
+            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::Priority))
         );
         connect!(
             relm,
-            self.block2_mi,
+            self.normal_mi,
             connect_activate(_),
-            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::TwoBlocks))
+            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::Normal))
         );
         connect!(
             relm,
-            self.block3_mi,
+            self.economy_mi,
             connect_activate(_),
-            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::ThreeBlocks))
+            wallet::Msg::Pay(Msg::FeeSetBlocks(FeeRate::Economy))
         );
     }
 
@@ -153,13 +164,15 @@ impl Widgets {
     pub fn update_info(
         &self,
         fee_rate: f32,
-        fees: (f32, f32, f32),
+        fees: &FeeEstimates,
         tx_info: Option<(u64, u32, f32)>,
     ) {
         self.compose_btn.set_sensitive(tx_info.is_some());
 
-        self.fee_adj.set_upper(fees.0 as f64 * 5.0);
-        self.fee_adj.set_lower(fees.2 as f64 / 10.0);
+        if let (Some(max), Some(min)) = (fees.fee_rate(1), fees.fee_rate(1008)) {
+            self.fee_adj.set_upper(max as f64 * 5.0);
+            self.fee_adj.set_lower(min as f64 / 10.0);
+        }
 
         if let Some((total, total_fee, vsize)) = tx_info {
             let total_fee = total_fee as f64;
@@ -181,21 +194,20 @@ impl Widgets {
         }
 
         self.fee_scale.clear_marks();
-        self.fee_scale
-            .add_mark(fees.0 as f64, PositionType::Bottom, None);
-        self.fee_scale
-            .add_mark(fees.1 as f64, PositionType::Bottom, None);
-        self.fee_scale
-            .add_mark(fees.2 as f64, PositionType::Bottom, None);
-
-        let ty = match fee_rate {
-            f if f >= fees.0 => FeeRate::OneBlock,
-            f if f >= fees.1 => FeeRate::TwoBlocks,
-            f if f >= fees.2 => FeeRate::ThreeBlocks,
-            _ => FeeRate::Unknown,
-        };
+        for ty in [FeeRate::Priority, FeeRate::Normal, FeeRate::Economy] {
+            if let Some(rate) = ty.target().and_then(|target| fees.fee_rate(target)) {
+                self.fee_scale
+                    .add_mark(rate as f64, PositionType::Bottom, None);
+            }
+        }
 
-        self.time_lbl.set_text(&ty.to_string());
+        let time = fees.expected_blocks(fee_rate).map(format_blocks_time);
+        let text = match (FeeRate::with(fee_rate, fees), time) {
+            (_, None) => s!("Unknown"),
+            (FeeRate::Custom, Some(time)) => format!("In {}", time),
+            (ty, Some(time)) => format!("{}, in {}", ty, time),
+        };
+        self.time_lbl.set_text(&text);
     }
 
     pub fn fee_rate(&self) -> f64 { self.fee_adj.value() }
@@ -9,15 +9,25 @@
 // a copy of the AGPL-3.0 License along with this software. If not, see
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
-use bpro::{file, DescriptorError, ElectrumServer, FileDocument, Signer, Wallet, WalletSettings};
+use bitcoin::{OutPoint, Transaction, Txid};
+use bpro::{
+    file, DescriptorError, ElectrumSec, ElectrumServer, FileDocument, Prevout, Signer, Wallet,
+    WalletSettings, WalletState,
+};
+use miniscript::Descriptor;
 use wallet::descriptors::DescriptorClass;
-use wallet::hd::UnhardenedIndex;
+use wallet::hd::{DerivationAccount, SegmentIndexes, UnhardenedIndex};
 
 use super::pay::beneficiary_row::BeneficiaryModel;
-use crate::worker::exchange::{Exchange, Fiat};
+use super::pay::FeeRate;
+use crate::model::{
+    descriptor_owns, sidecar, wallet_descriptors, BackendType, Exchange, FeeEstimates, Fiat,
+    Sidecar, SyncCache, WalletPrefs,
+};
+use crate::worker::exchange::{PriceHistory, Rate};
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default)]
 pub struct InvoiceModel {
@@ -25,6 +35,9 @@ pub struct InvoiceModel {
     pub index: Option<UnhardenedIndex>,
 }
 
+/// Descriptor of a wallet descriptor class with the coins of that class.
+pub type ClassCoins = (Descriptor<DerivationAccount>, BTreeSet<Prevout>);
+
 #[derive(Getters)]
 pub struct ViewModel {
     #[getter(as_mut)]
@@ -32,15 +45,33 @@ pub struct ViewModel {
 
     path: PathBuf,
 
+    prefs: WalletPrefs,
+
     #[getter(as_mut)]
     beneficiaries: BeneficiaryModel,
 
     #[getter(as_copy)]
     fee_rate: f32, // Used by payment window
 
+    fees: FeeEstimates,
+
     #[getter(skip)]
     invoice: InvoiceModel,
 
+    /// Coins selected by the user for spending in new payments; if empty,
+    /// coins are selected automatically.
+    coin_selection: BTreeSet<OutPoint>,
+
+    /// Change indexes used by transactions composed since the wallet was
+    /// opened, per descriptor class other than the primary one.
+    reserved_change: BTreeMap<usize, UnhardenedIndex>,
+
+    /// Wallet transactions known from the last synchronization.
+    transactions: BTreeMap<Txid, Transaction>,
+
+    /// Fees of the unconfirmed wallet transactions, when known.
+    mempool_fees: BTreeMap<Txid, u64>,
+
     #[getter(as_copy)]
     pub exchange: Exchange,
 
@@ -49,42 +80,302 @@ pub struct ViewModel {
 
     #[getter(as_copy)]
     pub exchange_rate: f64,
+
+    /// Last known exchange rate, which may be taken from the cache.
+    pub last_rate: Option<Rate>,
+
+    /// Daily prices of bitcoin in the selected fiat currency.
+    pub price_history: PriceHistory,
+
+    /// Error reading wallet preferences, which were replaced with the defaults.
+    #[getter(skip)]
+    prefs_error: Option<sidecar::Error>,
 }
 
 impl ViewModel {
     pub fn with(wallet: Wallet, path: PathBuf) -> ViewModel {
+        let (prefs, prefs_error) = match WalletPrefs::read_for(&path) {
+            Ok(prefs) => (prefs, None),
+            Err(err) => (WalletPrefs::default(), Some(err)),
+        };
+        // Transactions from the last synchronization are available right after
+        // opening the wallet and in the offline mode; the cache is re-created by
+        // the next synchronization if it can't be read
+        let (transactions, mempool_fees) = match SyncCache::read_for(&path) {
+            Ok(cache) => {
+                let fees = cache.mempool_fees();
+                (cache.transactions, fees)
+            }
+            Err(_) => (none!(), none!()),
+        };
         ViewModel {
+            exchange: prefs.exchange,
+            fiat: prefs.fiat,
             fee_rate: wallet.ephemerals().fees.0 * 100_000_000.0, // TODO: Update on window opening
             wallet,
             path,
+            prefs,
             beneficiaries: BeneficiaryModel::new(),
+            fees: none!(),
             invoice: none!(),
-            exchange: Exchange::Kraken,
-            fiat: Fiat::CHF,
+            coin_selection: none!(),
+            reserved_change: none!(),
+            transactions,
+            mempool_fees,
             exchange_rate: 0.0,
+            last_rate: None,
+            price_history: none!(),
+            prefs_error,
         }
     }
 
+    /// Returns error which happened while reading wallet preferences, if any;
+    /// the error is returned only once.
+    pub fn take_prefs_error(&mut self) -> Option<sidecar::Error> { self.prefs_error.take() }
+
     pub fn save(&mut self) -> Result<usize, file::Error> { self.wallet.write_file(&self.path) }
 
+    pub fn save_prefs(&self) -> Result<(), sidecar::Error> { self.prefs.write_for(&self.path) }
+
     pub fn as_settings(&self) -> &WalletSettings { self.wallet.as_settings() }
     pub fn to_settings(&self) -> WalletSettings { self.wallet.to_settings() }
 
+    /// Server name of the blockchain backend used by the wallet.
+    pub fn backend_server(&self) -> String {
+        let settings = self.wallet.as_settings();
+        match self.prefs.backend {
+            BackendType::Electrum => settings.electrum().server.clone(),
+            BackendType::Esplora => self.prefs.esplora_url(settings.network()).to_owned(),
+            BackendType::BitcoinCore => self.prefs.core.url(settings.network()).to_owned(),
+        }
+    }
+
+    /// Security of the connection to the blockchain backend used by the wallet.
+    pub fn backend_sec(&self) -> ElectrumSec {
+        let settings = self.wallet.as_settings();
+        match self.prefs.backend {
+            BackendType::Electrum => settings.electrum().sec,
+            BackendType::Esplora
+                if self
+                    .prefs
+                    .esplora_url(settings.network())
+                    .starts_with("https://") =>
+            {
+                ElectrumSec::Tls
+            }
+            BackendType::Esplora | BackendType::BitcoinCore => ElectrumSec::None,
+        }
+    }
+
     pub fn as_invoice(&self) -> &InvoiceModel { &self.invoice }
     pub fn as_invoice_mut(&mut self) -> &mut InvoiceModel { &mut self.invoice }
 
     pub fn set_fee_rate(&mut self, fee_rate: f32) { self.fee_rate = fee_rate; }
 
+    pub fn select_coin(&mut self, outpoint: OutPoint, spend: bool) {
+        match spend {
+            true => self.coin_selection.insert(outpoint),
+            false => self.coin_selection.remove(&outpoint),
+        };
+    }
+
+    pub fn clear_coin_selection(&mut self) { self.coin_selection.clear() }
+
+    pub fn update_transactions(&mut self, transactions: Vec<Transaction>) {
+        self.transactions = transactions.into_iter().map(|tx| (tx.txid(), tx)).collect();
+    }
+
+    pub fn update_mempool_fees(&mut self, fees: BTreeMap<Txid, u64>) { self.mempool_fees = fees; }
+
+    pub fn is_frozen(&self, outpoint: OutPoint) -> bool {
+        self.prefs.frozen_coins.contains(&outpoint)
+    }
+
+    /// Freezes or unfreezes the coin; frozen coins are persisted in the wallet
+    /// preferences.
+    pub fn freeze_coin(&mut self, outpoint: OutPoint, frozen: bool) {
+        match frozen {
+            true => self.prefs.frozen_coins.insert(outpoint),
+            false => self.prefs.frozen_coins.remove(&outpoint),
+        };
+    }
+
+    /// Total value of the frozen unspent coins.
+    pub fn frozen_value(&self) -> u64 {
+        self.wallet
+            .utxos()
+            .iter()
+            .map(Prevout::from)
+            .filter(|prevout| self.is_frozen(prevout.outpoint))
+            .map(|prevout| prevout.amount)
+            .sum()
+    }
+
+    /// Wallet state where the balance excludes frozen coins.
+    pub fn spendable_state(&self) -> WalletState {
+        let mut state = self.wallet.state();
+        state.balance = state.balance.saturating_sub(self.frozen_value());
+        state
+    }
+
+    /// Removes coins which are no longer unspent from the coin selection.
+    pub fn prune_coin_selection(&mut self) {
+        let unspent = self.unspent();
+        prune_coins(&mut self.coin_selection, &unspent);
+    }
+
+    /// Removes coins which are no longer unspent from the frozen coins;
+    /// returns whether any of the coins were removed.
+    pub fn prune_frozen_coins(&mut self) -> bool {
+        let unspent = self.unspent();
+        prune_coins(&mut self.prefs.frozen_coins, &unspent)
+    }
+
+    fn unspent(&self) -> BTreeSet<OutPoint> {
+        self.wallet
+            .utxos()
+            .iter()
+            .map(|utxo| Prevout::from(utxo).outpoint)
+            .collect()
+    }
+
+    /// Number and total value of the coins selected for spending.
+    pub fn selected_coins(&self) -> (usize, u64) {
+        self.wallet
+            .utxos()
+            .iter()
+            .map(Prevout::from)
+            .filter(|prevout| self.coin_selection.contains(&prevout.outpoint))
+            .filter(|prevout| !self.is_frozen(prevout.outpoint))
+            .fold((0, 0), |(count, value), prevout| {
+                (count + 1, value + prevout.amount)
+            })
+    }
+
+    /// Descriptors of all wallet descriptor classes, in the order of
+    /// [`wallet_descriptors`], with the coins of each class available for
+    /// spending: frozen coins are excluded and, if the user has selected
+    /// coins, only the selected ones are included.
+    pub fn spendable_classes(&self) -> Result<Vec<ClassCoins>, miniscript::Error> {
+        let utxos = self.wallet.utxos();
+        Ok(wallet_descriptors(self.wallet.as_settings())?
+            .into_iter()
+            .map(|descriptor| {
+                let prevouts = utxos
+                    .iter()
+                    .filter(|utxo| descriptor_owns(&descriptor, &utxo.addr_src))
+                    .map(Prevout::from);
+                let prevouts =
+                    spendable_coins(prevouts, &self.coin_selection, &self.prefs.frozen_coins);
+                (descriptor, prevouts)
+            })
+            .collect())
+    }
+
+    /// Largest amount which can be spent by a single transaction, i.e. the
+    /// spendable balance of the richest descriptor class (before fees).
+    pub fn max_payable(&self) -> u64 {
+        let classes = self.spendable_classes().unwrap_or_default();
+        max_class_value(classes.iter().map(|(_, prevouts)| prevouts))
+    }
+
+    /// Returns change index for a new transaction spending coins of the
+    /// descriptor class with the given number.
+    ///
+    /// The primary class uses the change index tracked by the wallet. Other
+    /// classes continue after their last used change address, or after the
+    /// change addresses of the transactions composed since the wallet was
+    /// opened, such that the gaps between their change addresses do not
+    /// depend on the transactions of other classes.
+    pub fn next_change_index(
+        &self,
+        class_no: usize,
+        descriptor: &Descriptor<DerivationAccount>,
+    ) -> UnhardenedIndex {
+        if class_no == 0 {
+            return self.wallet.next_change_index();
+        }
+        let used = self
+            .wallet
+            .address_info(true)
+            .into_iter()
+            .filter(|info| info.volume > 0 && info.addr_src.change.first_index() == 1)
+            .filter(|info| descriptor_owns(descriptor, &info.addr_src))
+            .map(|info| info.addr_src.index.first_index() + 1)
+            .max();
+        let reserved = self
+            .reserved_change
+            .get(&class_no)
+            .map(|index| index.first_index() + 1);
+        UnhardenedIndex::from_index(used.max(reserved).unwrap_or_default())
+            .expect("change index overflow")
+    }
+
+    /// Marks the change index as used by a composed transaction. Returns
+    /// whether the wallet was updated and has to be saved.
+    pub fn reserve_change_index(&mut self, class_no: usize, index: UnhardenedIndex) -> bool {
+        if class_no == 0 {
+            return self.wallet.update_next_change_index(index);
+        }
+        let reserved = self.reserved_change.entry(class_no).or_insert(index);
+        *reserved = index.max(*reserved);
+        false
+    }
+
+    /// Sets fiat currency selected by the user, which is persisted in the
+    /// wallet preferences.
+    pub fn set_fiat(&mut self, fiat: Fiat) {
+        self.fiat = fiat;
+        self.prefs.fiat = fiat;
+    }
+
+    /// Sets exchange selected by the user, which is persisted in the wallet
+    /// preferences.
+    pub fn set_exchange(&mut self, exchange: Exchange) {
+        self.exchange = exchange;
+        self.prefs.exchange = exchange;
+    }
+
+    /// Switches between the rate from the selected exchange and the median
+    /// rate from multiple exchanges.
+    pub fn set_median_rate(&mut self, median: bool) { self.prefs.median_rate = median; }
+
+    pub fn update_fees(&mut self, fees: FeeEstimates) {
+        if let Some((f0, f1, f2)) = fees.ephemerals() {
+            self.wallet.update_fees(f0, f1, f2);
+        }
+        self.fees = fees;
+    }
+
+    /// Returns fee rate for the confirmation target, falling back to the fee
+    /// rate stored in the wallet if there are no estimations.
+    pub fn target_fee_rate(&self, ty: FeeRate) -> f32 {
+        ty.target()
+            .and_then(|target| self.fees.fee_rate(target))
+            .unwrap_or(self.wallet.ephemerals().fees.0)
+    }
+
     pub fn update_descriptor(
         &mut self,
         signers: Vec<Signer>,
         descriptor_classes: BTreeSet<DescriptorClass>,
         electrum: ElectrumServer,
+        prefs: WalletPrefs,
     ) -> Result<Option<&ElectrumServer>, DescriptorError> {
         self.wallet.update_signers(signers)?;
         for class in descriptor_classes {
             self.wallet.add_descriptor_class(class);
         }
+        // Fiat, exchange rate source and frozen coins are selected in the wallet
+        // window, and the settings may carry values which were current when they
+        // were opened
+        self.prefs = WalletPrefs {
+            fiat: self.prefs.fiat,
+            exchange: self.prefs.exchange,
+            median_rate: self.prefs.median_rate,
+            frozen_coins: std::mem::take(&mut self.prefs.frozen_coins),
+            ..prefs
+        };
         let electrum_updated = self.wallet.update_electrum(electrum);
         Ok(if electrum_updated {
             Some(self.wallet.as_settings().electrum())
@@ -93,3 +384,91 @@ impl ViewModel {
         })
     }
 }
+
+/// Coins available for spending: frozen coins are excluded and, if the user
+/// has selected coins, only the selected ones are included.
+fn spendable_coins(
+    prevouts: impl IntoIterator<Item = Prevout>,
+    selection: &BTreeSet<OutPoint>,
+    frozen: &BTreeSet<OutPoint>,
+) -> BTreeSet<Prevout> {
+    prevouts
+        .into_iter()
+        .filter(|prevout| selection.is_empty() || selection.contains(&prevout.outpoint))
+        .filter(|prevout| !frozen.contains(&prevout.outpoint))
+        .collect()
+}
+
+/// Total value of the coins of the richest descriptor class.
+fn max_class_value<'a>(classes: impl IntoIterator<Item = &'a BTreeSet<Prevout>>) -> u64 {
+    classes
+        .into_iter()
+        .map(|prevouts| prevouts.iter().map(|prevout| prevout.amount).sum())
+        .max()
+        .unwrap_or_default()
+}
+
+/// Removes coins which are not unspent; returns whether any were removed.
+fn prune_coins(coins: &mut BTreeSet<OutPoint>, unspent: &BTreeSet<OutPoint>) -> bool {
+    let count = coins.len();
+    coins.retain(|outpoint| unspent.contains(outpoint));
+    coins.len() != count
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    fn prevout(vout: u32, amount: u64) -> Prevout {
+        Prevout {
+            outpoint: OutPoint::new(Txid::all_zeros(), vout),
+            amount,
+            change: UnhardenedIndex::zero(),
+            index: UnhardenedIndex::from(vout as u8),
+        }
+    }
+
+    fn coins() -> Vec<Prevout> { vec![prevout(0, 1000), prevout(1, 2000), prevout(2, 5000)] }
+
+    fn outpoints(vouts: &[u32]) -> BTreeSet<OutPoint> {
+        vouts
+            .iter()
+            .map(|vout| OutPoint::new(Txid::all_zeros(), *vout))
+            .collect()
+    }
+
+    #[test]
+    fn frozen_not_spendable() {
+        let frozen = outpoints(&[2]);
+        let spendable = spendable_coins(coins(), &none!(), &frozen);
+        assert_eq!(spendable, bset! { prevout(0, 1000), prevout(1, 2000) });
+        // Selecting a frozen coin does not make it spendable
+        let spendable = spendable_coins(coins(), &outpoints(&[1, 2]), &frozen);
+        assert_eq!(spendable, bset! { prevout(1, 2000) });
+        let spendable = spendable_coins(coins(), &outpoints(&[2]), &frozen);
+        assert!(spendable.is_empty());
+    }
+
+    #[test]
+    fn max_payable_excludes_frozen() {
+        let other = bset! { prevout(3, 4000) };
+        let all = spendable_coins(coins(), &none!(), &none!());
+        assert_eq!(max_class_value([&all, &other]), 8000);
+        let unfrozen = spendable_coins(coins(), &none!(), &outpoints(&[2]));
+        assert_eq!(max_class_value([&unfrozen, &other]), 4000);
+        let unfrozen = spendable_coins(coins(), &none!(), &outpoints(&[0, 1, 2]));
+        assert_eq!(max_class_value([&unfrozen]), 0);
+        assert_eq!(max_class_value([]), 0);
+    }
+
+    #[test]
+    fn prune() {
+        let mut frozen = outpoints(&[0, 2, 5]);
+        assert!(prune_coins(&mut frozen, &outpoints(&[0, 1, 2])));
+        assert_eq!(frozen, outpoints(&[0, 2]));
+        assert!(!prune_coins(&mut frozen, &outpoints(&[0, 1, 2])));
+        assert_eq!(frozen, outpoints(&[0, 2]));
+    }
+}
@@ -9,33 +9,38 @@
 // a copy of the AGPL-3.0 License along with this software. If not, see
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
+use std::str::FromStr;
 
 use baid58::Baid58;
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::hashes::Hash;
-use bitcoin::Txid;
+use bitcoin::{OutPoint, Txid};
 use bpro::{
-    AddressSummary, ElectrumSec, ElectrumServer, HistoryEntry, OnchainStatus, UtxoTxid, WalletState,
+    AddressSummary, ElectrumSec, ElectrumServer, HistoryEntry, OnchainStatus, Prevout, UtxoTxid,
+    WalletState,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
-use electrum_client::HeaderNotification;
 use gladis::Gladis;
 use gtk::prelude::*;
 use gtk::{
-    gdk, Adjustment, ApplicationWindow, Button, CellRendererText, CheckButton, HeaderBar, Image,
-    Label, ListStore, Menu, MenuItem, RadioMenuItem, SortColumn, SortType, SpinButton, Spinner,
-    Statusbar, TextView, TreeView,
+    gdk, Adjustment, ApplicationWindow, Button, CellRendererText, CellRendererToggle, CheckButton,
+    CheckMenuItem, HeaderBar, Image, Label, ListStore, Menu, MenuItem, RadioMenuItem, SortColumn,
+    SortType, SpinButton, Spinner, Statusbar, TextView, TreeView, TreeViewColumn,
 };
 use relm::Relm;
 use wallet::hd::SegmentIndexes;
 
 use super::{ElectrumState, Msg, ViewModel};
-use crate::model::{display_accounting_amount, FormatDate, UI as UIColorTrait};
+use crate::model::{
+    display_accounting_amount, format_age, format_data_age, Exchange, Fiat, FormatDate,
+    UI as UIColorTrait,
+};
 use crate::view::launch;
 use crate::view::wallet::pay;
-use crate::worker::exchange::{Exchange, Fiat};
+use crate::worker::backend::BlockTip;
+use crate::worker::exchange::{PriceHistory, Quotes, Rate};
 
 trait UI {
     fn icon_name(self) -> &'static str;
@@ -92,9 +97,13 @@ pub struct Widgets {
     value_lbl: Label,
 
     exchange_lbl: Label,
-    fiat_usd: RadioMenuItem,
-    fiat_eur: RadioMenuItem,
-    fiat_chf: RadioMenuItem,
+    currency_menu: Menu,
+    provider_kraken: RadioMenuItem,
+    provider_bitstamp: RadioMenuItem,
+    provider_coinbase: RadioMenuItem,
+    provider_coingecko: RadioMenuItem,
+    provider_bitfinex: RadioMenuItem,
+    median_mi: CheckMenuItem,
     fiat_pair_lbl: Label,
 
     history_store: ListStore,
@@ -104,6 +113,8 @@ pub struct Widgets {
     address_list: TreeView,
     utxo_list: TreeView,
     history_list: TreeView,
+    fiat_then_col: TreeViewColumn,
+    fiat_now_col: TreeViewColumn,
 
     history_menu: Menu,
     hist_copy_txid_mi: MenuItem,
@@ -111,6 +122,7 @@ pub struct Widgets {
     hist_copy_amount_mi: MenuItem,
     hist_copy_balance_mi: MenuItem,
     hist_copy_height_mi: MenuItem,
+    hist_bump_fee_mi: MenuItem,
     description: CellRendererText,
 
     address_menu: Menu,
@@ -123,6 +135,11 @@ pub struct Widgets {
     coin_copy_addr_mi: MenuItem,
     coin_copy_amount_mi: MenuItem,
     coin_copy_height_mi: MenuItem,
+    coin_clear_mi: MenuItem,
+    coin_cpfp_mi: MenuItem,
+    coin_spend_toggle: CellRendererToggle,
+    coin_freeze_toggle: CellRendererToggle,
+    frozen_lbl: Label,
 
     status_bar: Statusbar,
     status_lbl: Label,
@@ -133,6 +150,7 @@ pub struct Widgets {
     electrum_lbl: Label,
     connection_img: Image,
     electrum_spin: Spinner,
+    cancel_btn: Button,
 
     // BTC invoice popover
     amount_chk: CheckButton,
@@ -165,6 +183,7 @@ impl Widgets {
             Msg::Pay(pay::Msg::Show)
         );
         connect!(relm, self.refresh_btn, connect_clicked(_), Msg::Refresh);
+        connect!(relm, self.cancel_btn, connect_clicked(_), Msg::CancelSync);
         connect!(relm, self.open_psbt_mi, connect_activate(_), Msg::OpenPsbt);
         connect!(relm, self.redefine_mi, connect_activate(_), Msg::Duplicate);
         connect!(relm, self.import_mi, connect_activate(_), Msg::Import);
@@ -178,6 +197,7 @@ impl Widgets {
         connect!(relm, self.about_mi, connect_activate(_), Msg::About);
 
         let menu = self.history_menu.clone();
+        let bump_mi = self.hist_bump_fee_mi.clone();
         self.history_list
             .connect_button_release_event(move |me, event| {
                 if event.button() == 3 {
@@ -187,12 +207,27 @@ impl Widgets {
                     Inhibit(false)
                 }
             });
-        self.history_list.connect_popup_menu(move |_me| {
+        self.history_list.connect_popup_menu(move |me| {
+            // Only outgoing transactions from the mempool can be replaced
+            let bumpable = me
+                .selection()
+                .selected()
+                .map(|(model, iter)| {
+                    model.value(&iter, 6).get::<u32>().unwrap() == u32::MAX
+                        && model
+                            .value(&iter, 2)
+                            .get::<&str>()
+                            .unwrap()
+                            .starts_with('-')
+                })
+                .unwrap_or_default();
+            bump_mi.set_sensitive(bumpable);
             menu.popup(None::<&Menu>, None::<&MenuItem>, |_, _, _| false, 0, 0);
             true
         });
 
         let menu = self.coin_menu.clone();
+        let cpfp_mi = self.coin_cpfp_mi.clone();
         self.utxo_list
             .connect_button_release_event(move |me, event| {
                 if event.button() == 3 {
@@ -202,7 +237,14 @@ impl Widgets {
                     Inhibit(false)
                 }
             });
-        self.utxo_list.connect_popup_menu(move |_me| {
+        self.utxo_list.connect_popup_menu(move |me| {
+            // Only unconfirmed coins can be used to speed up their transaction
+            let unconfirmed = me
+                .selection()
+                .selected()
+                .map(|(model, iter)| model.value(&iter, 4).get::<u32>().unwrap() == 0)
+                .unwrap_or_default();
+            cpfp_mi.set_sensitive(unconfirmed);
             menu.popup(None::<&Menu>, None::<&MenuItem>, |_, _, _| false, 0, 0);
             true
         });
@@ -259,6 +301,16 @@ impl Widgets {
             }
         });
 
+        let list = self.history_list.clone();
+        let sender = relm.stream().clone();
+        self.hist_bump_fee_mi.connect_activate(move |_| {
+            if let Some(iter) = list.selection().selected().map(|(_, iter)| iter) {
+                let val = list.model().unwrap().value(&iter, 1);
+                let txid = val.get::<&str>().unwrap();
+                sender.emit(Msg::BumpFee(Txid::from_hex(txid).unwrap()));
+            }
+        });
+
         let list = self.utxo_list.clone();
         self.coin_copy_txid_mi.connect_activate(move |_| {
             if let Some(iter) = list.selection().selected().map(|(_, iter)| iter) {
@@ -289,6 +341,16 @@ impl Widgets {
             }
         });
 
+        let list = self.utxo_list.clone();
+        let sender = relm.stream().clone();
+        self.coin_cpfp_mi.connect_activate(move |_| {
+            if let Some(iter) = list.selection().selected().map(|(_, iter)| iter) {
+                let val = list.model().unwrap().value(&iter, 1);
+                let outpoint = OutPoint::from_str(val.get::<&str>().unwrap()).unwrap();
+                sender.emit(Msg::Cpfp(outpoint));
+            }
+        });
+
         let list = self.address_list.clone();
         self.addr_copy_mi.connect_activate(move |_| {
             if let Some(iter) = list.selection().selected().map(|(_, iter)| iter) {
@@ -323,23 +385,58 @@ impl Widgets {
             sender.emit(Msg::EditLabel(Txid::from_hex(txid).unwrap(), s.to_string()));
         });
 
+        let mut group: Option<RadioMenuItem> = None;
+        for fiat in Fiat::ALL {
+            let item = RadioMenuItem::with_label(&format!("{} – {}", fiat, fiat.name()));
+            if let Some(ref group) = group {
+                item.join_group(Some(group));
+            } else {
+                group = Some(item.clone());
+            }
+            connect!(relm, item, connect_activate(_), Msg::Fiat(fiat));
+            self.currency_menu.append(&item);
+        }
+        self.currency_menu.show_all();
+        for exchange in Exchange::ALL {
+            connect!(
+                relm,
+                self.provider_item(exchange),
+                connect_activate(_),
+                Msg::Exchange(exchange)
+            );
+        }
         connect!(
             relm,
-            self.fiat_usd,
-            connect_activate(_),
-            Msg::Fiat(Fiat::USD)
-        );
-        connect!(
-            relm,
-            self.fiat_eur,
-            connect_activate(_),
-            Msg::Fiat(Fiat::EUR)
+            self.median_mi,
+            connect_toggled(mi),
+            Msg::MedianRate(mi.is_active())
         );
+
+        let sender = relm.stream().clone();
+        let store = self.utxo_store.clone();
+        self.coin_spend_toggle.connect_toggled(move |_, path| {
+            let iter = store.iter(&path).unwrap();
+            let spend = !store.value(&iter, 5).get::<bool>().unwrap();
+            store.set_value(&iter, 5, &spend.to_value());
+            let val = store.value(&iter, 1);
+            let outpoint = OutPoint::from_str(val.get::<&str>().unwrap()).unwrap();
+            sender.emit(Msg::CoinSelect(outpoint, spend));
+        });
+        let sender = relm.stream().clone();
+        let store = self.utxo_store.clone();
+        self.coin_freeze_toggle.connect_toggled(move |_, path| {
+            let iter = store.iter(&path).unwrap();
+            let frozen = !store.value(&iter, 6).get::<bool>().unwrap();
+            store.set_value(&iter, 6, &frozen.to_value());
+            let val = store.value(&iter, 1);
+            let outpoint = OutPoint::from_str(val.get::<&str>().unwrap()).unwrap();
+            sender.emit(Msg::CoinFreeze(outpoint, frozen));
+        });
         connect!(
             relm,
-            self.fiat_chf,
+            self.coin_clear_mi,
             connect_activate(_),
-            Msg::Fiat(Fiat::CHF)
+            Msg::CoinSelectClear
         );
 
         connect!(
@@ -393,11 +490,20 @@ impl Widgets {
         let network = settings.network().to_string();
         self.network_lbl
             .set_text(&(network[0..1].to_uppercase() + &network[1..]));
-        self.electrum_lbl.set_text(&settings.electrum().server);
-
-        self.fiat_usd.set_active(model.fiat == Fiat::USD);
-        self.fiat_eur.set_active(model.fiat == Fiat::EUR);
-        self.fiat_chf.set_active(model.fiat == Fiat::CHF);
+        self.electrum_lbl.set_text(&model.backend_server());
+
+        // Currency menu items are created in the order of `Fiat::ALL`
+        if let Some(item) = Fiat::ALL
+            .iter()
+            .position(|fiat| *fiat == model.fiat)
+            .and_then(|pos| self.currency_menu.children().into_iter().nth(pos))
+            .and_then(|item| item.downcast::<RadioMenuItem>().ok())
+        {
+            item.set_active(true);
+        }
+        self.provider_item(model.exchange).set_active(true);
+        self.median_mi.set_active(model.prefs().median_rate);
+        self.update_median_rate(model.prefs().median_rate);
 
         self.history_store
             .set_sort_column_id(SortColumn::Index(6), SortType::Descending);
@@ -413,6 +519,7 @@ impl Widgets {
 
     fn update_btc_invoice(&self, model: &mut ViewModel) {
         let invoice = model.as_invoice();
+        let gap_limit = model.prefs().gap_limit;
         let wallet = model.wallet();
         let next_index = wallet.next_default_index();
         let address = wallet.indexed_address(invoice.index.unwrap_or(next_index));
@@ -424,7 +531,7 @@ impl Widgets {
         self.index_chk.set_active(invoice.index.is_some());
         self.index_stp.set_sensitive(invoice.index.is_some());
         self.index_adj
-            .set_upper((next_index.first_index() + 19) as f64);
+            .set_upper((next_index.first_index() + gap_limit as u32 - 1) as f64);
         self.index_adj
             .set_value(invoice.index.unwrap_or(next_index).first_index() as f64);
         self.index_img.set_visible(!index_reuse);
@@ -452,6 +559,12 @@ impl Widgets {
         self.connection_img.set_visible(true);
     }
 
+    pub fn update_active_server(&self, server: &str) {
+        self.electrum_lbl.set_text(server);
+        self.electrum_lbl
+            .set_tooltip_text(Some("Server currently used for synchronization"));
+    }
+
     pub fn update_electrum_state(&self, state: ElectrumState) {
         self.status_lbl.set_text(&state.to_string());
         match state {
@@ -464,8 +577,10 @@ impl Widgets {
                 self.connection_img.set_visible(false);
                 self.electrum_spin.set_visible(true);
                 self.electrum_spin.set_visible(true);
+                self.cancel_btn.set_visible(true);
             }
             ElectrumState::Complete(sec) => {
+                self.cancel_btn.set_visible(false);
                 self.refresh_btn.set_sensitive(true);
                 self.refresh_img.set_visible(true);
                 self.refresh_spin.set_visible(false);
@@ -476,7 +591,48 @@ impl Widgets {
                 self.connection_img.set_visible(true);
                 self.paybtc_btn.set_sensitive(true);
             }
+            ElectrumState::Cancelled => {
+                self.cancel_btn.set_visible(false);
+                self.refresh_btn.set_sensitive(true);
+                self.refresh_img.set_visible(true);
+                self.refresh_spin.set_visible(false);
+                self.electrum_spin.set_visible(false);
+            }
+            ElectrumState::InvalidProofs(_) => {
+                self.cancel_btn.set_visible(false);
+                self.refresh_btn.set_sensitive(true);
+                self.refresh_img.set_visible(true);
+                self.refresh_spin.set_visible(false);
+
+                self.electrum_spin.set_visible(false);
+                self.connection_img
+                    .set_icon_name(Some("dialog-warning-symbolic"));
+                self.connection_img.set_tooltip_text(Some(
+                    "Server has provided invalid proofs for some of the transaction \
+                     confirmations; these confirmations are not trusted. Consider using other \
+                     server.",
+                ));
+                self.connection_img.set_visible(true);
+                self.paybtc_btn.set_sensitive(true);
+            }
+            ElectrumState::Offline(_) => {
+                self.cancel_btn.set_visible(false);
+                self.refresh_btn.set_sensitive(true);
+                self.refresh_img.set_visible(true);
+                self.refresh_spin.set_visible(false);
+
+                self.electrum_spin.set_visible(false);
+                self.connection_img
+                    .set_icon_name(Some("network-offline-symbolic"));
+                self.connection_img.set_tooltip_text(Some(
+                    "Wallet is in the offline mode and does not connect to the network; it can be \
+                     changed in the wallet settings",
+                ));
+                self.connection_img.set_visible(true);
+                self.paybtc_btn.set_sensitive(true);
+            }
             ElectrumState::Error(err) => {
+                self.cancel_btn.set_visible(false);
                 self.refresh_btn.set_sensitive(true);
                 self.refresh_img.set_visible(true);
                 self.refresh_spin.set_visible(false);
@@ -494,7 +650,7 @@ impl Widgets {
         }
     }
 
-    pub fn update_last_block(&mut self, last_block: &HeaderNotification) {
+    pub fn update_last_block(&mut self, last_block: &BlockTip) {
         let ts = last_block.header.time;
         let naive = NaiveDateTime::from_timestamp_opt(ts as i64, 0).expect("invalid block time");
         let dt =
@@ -505,7 +661,11 @@ impl Widgets {
         self.height_lbl.set_text(&last_block.height.to_string());
     }
 
-    pub fn update_history(&mut self, history: &BTreeSet<HistoryEntry>) {
+    pub fn update_history(
+        &mut self,
+        history: &BTreeSet<HistoryEntry>,
+        unverified: &BTreeSet<Txid>,
+    ) {
         self.history_store.clear();
         let mut balance = 0i64;
         for item in history {
@@ -521,6 +681,10 @@ impl Widgets {
                 OnchainStatus::Mempool => s!("mempool"),
             };
             let txid = item.onchain.txid;
+            let date = match unverified.contains(&txid) {
+                true => format!("{date} (unverified)"),
+                false => date,
+            };
             let baid = Baid58::with("txid", txid.into_inner());
             let mut sort = item.onchain.status.into_u32();
             if sort == 0 {
@@ -540,26 +704,107 @@ impl Widgets {
         }
     }
 
+    /// Updates fiat value of the history transactions at the time they were
+    /// mined and at the current exchange rate. Unconfirmed transactions are
+    /// valued at the current rate.
+    pub fn update_history_fiat(
+        &mut self,
+        history: &BTreeSet<HistoryEntry>,
+        prices: &PriceHistory,
+        fiat: Fiat,
+        rate: f64,
+    ) {
+        self.fiat_then_col
+            .set_title(&format!("Value at tx time, {}", fiat.symbol()));
+        self.fiat_now_col
+            .set_title(&format!("Value today, {}", fiat.symbol()));
+
+        let entries = history
+            .iter()
+            .map(|item| (item.onchain.txid.to_string(), item))
+            .collect::<BTreeMap<_, _>>();
+        let iter = match self.history_store.iter_first() {
+            Some(iter) => iter,
+            None => return,
+        };
+        loop {
+            let txid = self.history_store.value(&iter, 1);
+            if let Some(item) = txid.get::<&str>().ok().and_then(|txid| entries.get(txid)) {
+                let btc = item.balance() as f64 / 100_000_000.0;
+                let price = match item.onchain.status {
+                    OnchainStatus::Blockchain(_) => item
+                        .onchain
+                        .date_time()
+                        .and_then(|dt| prices.price_at(dt.timestamp())),
+                    OnchainStatus::Mempool if rate > 0.0 => Some(rate),
+                    OnchainStatus::Mempool => None,
+                };
+                let then = price
+                    .map(|price| format!("{:+.2}", btc * price))
+                    .unwrap_or_else(|| s!("?"));
+                let now = match rate > 0.0 {
+                    true => format!("{:+.2}", btc * rate),
+                    false => s!("?"),
+                };
+                self.history_store.set(&iter, &[(9, &then), (10, &now)]);
+            }
+            if !self.history_store.iter_next(&iter) {
+                break;
+            }
+        }
+    }
+
     pub fn update_outpoints(&mut self, model: &mut ViewModel) {
-        self.update_utxos(model.wallet().utxos());
+        self.update_utxos(
+            model.wallet().utxos(),
+            model.coin_selection(),
+            &model.prefs().frozen_coins,
+        );
     }
 
-    pub fn update_utxos(&mut self, utxos: &BTreeSet<UtxoTxid>) {
+    pub fn update_utxos(
+        &mut self,
+        utxos: &BTreeSet<UtxoTxid>,
+        selection: &BTreeSet<OutPoint>,
+        frozen: &BTreeSet<OutPoint>,
+    ) {
         self.utxo_store.clear();
         for item in utxos {
+            let outpoint = Prevout::from(item).outpoint;
             self.utxo_store.insert_with_values(None, &[
                 (0, &item.addr_src.address.to_string()),
                 (1, &format!("{}:{}", item.onchain.txid, item.vout)),
                 (2, &format_btc_value(item.value)),
                 (3, &item.onchain.format_date()),
                 (4, &item.onchain.status.into_u32()),
+                (5, &selection.contains(&outpoint)),
+                (6, &frozen.contains(&outpoint)),
             ]);
         }
     }
 
-    pub fn update_addresses(&mut self, address_info: &[AddressSummary]) {
+    pub fn update_addresses(&mut self, address_info: &[AddressSummary], gap_limit: u16) {
+        // Show unused addresses only up to the gap limit after the last used one
+        let mut last_used = bmap! {};
+        for info in address_info.iter().filter(|info| info.volume > 0) {
+            let change = info.addr_src.change.first_index();
+            let index = info.addr_src.index.first_index();
+            let last = last_used.entry(change).or_insert(index);
+            *last = index.max(*last);
+        }
+
         self.address_store.clear();
         for info in address_info {
+            let index = info.addr_src.index.first_index() as u64;
+            let limit = last_used
+                .get(&info.addr_src.change.first_index())
+                .map(|last| *last as u64 + 1)
+                .unwrap_or_default()
+                + gap_limit as u64;
+            if index >= limit {
+                continue;
+            }
+
             let balance = format_btc_value(info.balance);
             let volume = format_btc_value(info.volume);
             let terminal = info.terminal_string();
@@ -588,8 +833,7 @@ impl Widgets {
     pub fn update_balance(&self, model: &mut ViewModel) { self.update_btc_balance(model); }
 
     pub fn update_btc_balance(&self, model: &mut ViewModel) {
-        let wallet = model.wallet();
-        let state = wallet.state();
+        let state = model.spendable_state();
         let exchange_rate = model.exchange_rate;
 
         display_accounting_amount(
@@ -607,29 +851,79 @@ impl Widgets {
 
         self.volume_lbl
             .set_text(&format!("₿ {:.}", (state.volume as f64 / 100_000_000.0)));
+
+        let frozen = model.frozen_value();
+        self.frozen_lbl.set_visible(frozen > 0);
+        self.frozen_lbl
+            .set_text(&format!("Frozen: ₿ {:.}", frozen as f64 / 100_000_000.0));
+    }
+
+    fn provider_item(&self, exchange: Exchange) -> &RadioMenuItem {
+        match exchange {
+            Exchange::Kraken => &self.provider_kraken,
+            Exchange::Bitstamp => &self.provider_bitstamp,
+            Exchange::Coinbase => &self.provider_coinbase,
+            Exchange::CoinGecko => &self.provider_coingecko,
+            Exchange::Bitfinex => &self.provider_bitfinex,
+        }
     }
 
     pub fn update_fiat(&self, fiat: Fiat) {
-        self.fiat_pair_lbl.set_text(fiat.pair());
+        self.fiat_pair_lbl.set_text(&fiat.pair());
         self.fiat_name_lbl.set_text(fiat.symbol());
 
         self.exchange_lbl.set_text(&"...");
         self.balance_fiat_lbl.set_text("?");
         self.balance_cents_lbl.set_text("");
         //self.volume_fiat_lbl.set_text("?");
+        self.update_rate_stale(false);
+    }
+
+    /// Outdated exchange rate and the fiat balance computed from it are dimmed.
+    fn update_rate_stale(&self, stale: bool) {
+        self.exchange_lbl.set_sensitive(!stale);
+        self.balance_fiat_lbl.set_sensitive(!stale);
+        self.balance_cents_lbl.set_sensitive(!stale);
     }
 
+    /// Exchange selection is not used while the median rate is shown.
+    pub fn update_median_rate(&self, median: bool) {
+        for exchange in Exchange::ALL {
+            self.provider_item(exchange).set_sensitive(!median);
+        }
+    }
+
+    /// Shows the exchange rate, which may be outdated if it was not updated for
+    /// a long time; `error` describes why the rate was not updated.
     pub fn update_exchange_rate(
         &self,
-        fiat: Fiat,
-        _exchange: Exchange,
-        exchange_rate: f64,
+        rate: &Rate,
+        stale: bool,
+        error: Option<&str>,
         state: WalletState,
     ) {
-        self.update_fiat(fiat);
+        self.update_fiat(rate.fiat);
 
+        let exchange_rate = rate.rate;
         if exchange_rate > 0.0 {
+            let mut tooltip = format!(
+                "{}\nRetrieved {}",
+                format_quotes(&rate.quotes, exchange_rate),
+                format_data_age(rate.time)
+            );
+            if let Some(err) = error {
+                tooltip += &format!("\nUnable to update the rate: {}", err);
+            }
             self.exchange_lbl.set_text(&format!("{:.0}", exchange_rate));
+            self.exchange_lbl.set_tooltip_text(Some(&tooltip));
+            if stale {
+                self.fiat_pair_lbl.set_text(&format!(
+                    "{}, {}",
+                    rate.fiat.pair(),
+                    format_age(rate.time)
+                ));
+            }
+            self.update_rate_stale(stale);
 
             let s = format!("{:.02}", state.balance_btc() * exchange_rate);
             let (fiat, cents) = s.split_once('.').expect("formatting produces decimal");
@@ -640,14 +934,49 @@ impl Widgets {
         }
     }
 
-    pub fn update_exchange_error(&self, _err: String) {
+    pub fn update_exchange_error(&self, err: String) {
         self.exchange_lbl.set_text(&"n/a");
+        self.exchange_lbl.set_tooltip_text(Some(&err));
         self.balance_fiat_lbl.set_text("n/a");
         self.balance_cents_lbl.set_text("");
         //self.volume_fiat_lbl.set_text("n/a");
     }
 }
 
+/// Describes the exchanges which contributed to the rate.
+fn format_quotes(quotes: &Quotes, rate: f64) -> String {
+    let list = |quotes: &BTreeMap<Exchange, f64>| {
+        quotes
+            .iter()
+            .map(|(exchange, rate)| format!("{} {:.0}", exchange, rate))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if quotes.used.len() == 1 && quotes.outliers.is_empty() && quotes.failed.is_empty() {
+        let exchange = quotes.used.keys().next().expect("single quote");
+        return format!("Exchange rate from {}", exchange);
+    }
+    let mut text = format!(
+        "Median rate from {} exchange(s): {}\nSpread: {:.2}%",
+        quotes.used.len(),
+        list(&quotes.used),
+        quotes.spread(rate)
+    );
+    if !quotes.outliers.is_empty() {
+        text += &format!("\nRejected as outliers: {}", list(&quotes.outliers));
+    }
+    if !quotes.failed.is_empty() {
+        let failed = quotes
+            .failed
+            .keys()
+            .map(Exchange::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        text += &format!("\nNot available: {}", failed);
+    }
+    text
+}
+
 fn format_btc_value(value: u64) -> String {
     if value == 0 {
         s!("0")
@@ -0,0 +1,839 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{BlockHash, BlockHeader, OutPoint, Script, Transaction, Txid};
+use bpro::WalletSettings;
+use gtk::glib;
+use miniscript::{Descriptor, MiniscriptKey, TranslatePk, Translator};
+use serde_crate::de::DeserializeOwned;
+use serde_crate::Deserialize;
+use serde_json::{json, Value};
+use wallet::descriptors::derive::Descriptor as DeriveScript;
+use wallet::hd::DerivationAccount;
+use wallet::onchain::PublicNetwork;
+
+use super::{script_status, sort_history, BlockTip, ChainBackend, Error, MerkleProof};
+use crate::model::{wallet_descriptors, CorePrefs, HistoryItem, UnspentItem};
+use crate::worker::proxy::{http_agent, Socks5};
+
+/// Wallet with the given name is not loaded or does not exist.
+const RPC_WALLET_NOT_FOUND: i64 = -18;
+/// Wallet with the given name is already loaded.
+const RPC_WALLET_ALREADY_LOADED: i64 = -35;
+
+/// Number of addresses of each imported descriptor which Bitcoin Core watches
+/// initially; Bitcoin Core extends the range once it sees some of them used.
+const IMPORT_RANGE: u32 = 1000;
+
+/// Time after which the request importing descriptors is no longer waited
+/// for. Bitcoin Core responds to it only once the blockchain is rescanned for
+/// the imported descriptors, which takes hours on mainnet; the node continues
+/// the rescan after the request times out.
+const IMPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct HeaderInfo {
+    height: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct WalletTx {
+    txid: Txid,
+    confirmations: i64,
+    blockheight: Option<u32>,
+    /// Whether the transaction is a coinbase one.
+    #[serde(default)]
+    generated: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct SinceBlock {
+    transactions: Vec<WalletTx>,
+    removed: Vec<WalletTx>,
+    lastblock: BlockHash,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct WalletTxHex {
+    hex: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct DescriptorInfo {
+    descriptor: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct WalletDescriptor {
+    desc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct WalletDescriptors {
+    descriptors: Vec<WalletDescriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct ImportResult {
+    success: bool,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct WalletInfo {
+    /// Either `false` or an object with the rescan `progress`.
+    scanning: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct FeeEstimate {
+    feerate: Option<f64>,
+}
+
+/// Backend using Bitcoin Core JSON-RPC.
+///
+/// Bitcoin Core does not index transactions by scripts, so the wallet
+/// descriptors are imported into a watch-only descriptor wallet created in the
+/// node. Script histories are then reconstructed from the transactions known
+/// to that wallet.
+pub struct CoreBackend {
+    node_url: String,
+    wallet_url: String,
+    auth: String,
+    agent: ureq::Agent,
+    /// Agent used for importing descriptors, which does not wait for the
+    /// rescan to complete.
+    import_agent: ureq::Agent,
+    last_tip: Option<BlockHash>,
+    /// Time since which the blockchain is rescanned for the transactions of
+    /// the newly imported descriptors.
+    birthday: i64,
+    /// Descriptors already imported into the Bitcoin Core wallet, without
+    /// checksums.
+    imported: BTreeSet<String>,
+    /// Wallet transactions and their heights as of the last refresh.
+    transactions: BTreeMap<Txid, (Transaction, i32)>,
+    /// Chain tip at the moment of the last refresh; transactions changed after
+    /// this block are retrieved by the next refresh.
+    refreshed_at: Option<BlockHash>,
+}
+
+impl CoreBackend {
+    pub fn connect(
+        url: &str,
+        prefs: &CorePrefs,
+        network: PublicNetwork,
+        wallet_id: &str,
+        proxy: Option<&Socks5>,
+    ) -> Result<Self, Error> {
+        let mut backend = Self::connect_node(url, prefs, network, wallet_id, proxy)?;
+        backend.load_wallet(&prefs.wallet(wallet_id))?;
+        backend.imported = backend
+            .call::<WalletDescriptors>("listdescriptors", json!([]))?
+            .descriptors
+            .into_iter()
+            .map(|descriptor| strip_checksum(&descriptor.desc).to_owned())
+            .collect();
+        Ok(backend)
+    }
+
+    /// Prepares connection to the node without loading or creating the wallet,
+    /// so the wallet methods can't be used. Allows to check the connection
+    /// settings without changing the node.
+    pub fn connect_node(
+        url: &str,
+        prefs: &CorePrefs,
+        network: PublicNetwork,
+        wallet_id: &str,
+        proxy: Option<&Socks5>,
+    ) -> Result<Self, Error> {
+        let credentials = if prefs.user.is_empty() {
+            let cookie_file = if prefs.cookie_file.is_empty() {
+                default_cookie_file(network)
+            } else {
+                PathBuf::from(&prefs.cookie_file)
+            };
+            fs::read_to_string(cookie_file)?.trim().to_owned()
+        } else {
+            format!("{}:{}", prefs.user, prefs.password)
+        };
+        let node_url = url.trim_end_matches('/').to_owned();
+        let wallet = prefs.wallet(wallet_id);
+        Ok(CoreBackend {
+            wallet_url: format!("{}/wallet/{}", node_url, urlencoding::encode(&wallet)),
+            node_url,
+            auth: format!("Basic {}", base64::encode(credentials)),
+            agent: http_agent(url, proxy, Duration::from_secs(60))?,
+            import_agent: http_agent(url, proxy, IMPORT_TIMEOUT)?,
+            last_tip: None,
+            birthday: prefs.birthday_timestamp(),
+            imported: empty!(),
+            transactions: empty!(),
+            refreshed_at: None,
+        })
+    }
+
+    fn request<T>(
+        &self,
+        agent: &ureq::Agent,
+        url: &str,
+        method: &str,
+        params: Value,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "mycitadel",
+            "method": method,
+            "params": params,
+        });
+        let response: RpcResponse<T> = match agent
+            .post(url)
+            .set("Authorization", &self.auth)
+            .send_json(request)
+        {
+            Ok(response) => response.into_json()?,
+            Err(ureq::Error::Status(401, _)) => {
+                return Err(Error::Message(s!("Bitcoin Core RPC authentication failed")))
+            }
+            // Bitcoin Core reports RPC errors with HTTP error codes
+            Err(ureq::Error::Status(_, response)) => response.into_json()?,
+            Err(err) => return Err(err.into()),
+        };
+        match (response.result, response.error) {
+            (_, Some(RpcError { code, message })) => Err(Error::Rpc { code, message }),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(Error::Message(format!(
+                "Bitcoin Core returned no result for {}",
+                method
+            ))),
+        }
+    }
+
+    fn call_node<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, Error> {
+        self.request(&self.agent, &self.node_url, method, params)
+    }
+
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, Error> {
+        self.request(&self.agent, &self.wallet_url, method, params)
+    }
+
+    fn load_wallet(&self, name: &str) -> Result<(), Error> {
+        match self.call_node::<Value>("loadwallet", json!([name])) {
+            Ok(_) => Ok(()),
+            Err(Error::Rpc { code, .. }) if code == RPC_WALLET_ALREADY_LOADED => Ok(()),
+            Err(Error::Rpc { code, .. }) if code == RPC_WALLET_NOT_FOUND => {
+                // Watch-only blank descriptor wallet
+                self.call_node::<Value>("createwallet", json!([name, true, true, "", false, true]))
+                    .map(|_| ())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn header(&self, hash: &str) -> Result<BlockHeader, Error> {
+        let hex: String = self.call_node("getblockheader", json!([hash, false]))?;
+        Ok(deserialize(&Vec::<u8>::from_hex(&hex)?)?)
+    }
+
+    /// Returns progress of the blockchain rescan for the wallet transactions,
+    /// from 0 to 1, or `None` if the wallet is not being rescanned.
+    fn rescan_progress(&self) -> Result<Option<f64>, Error> {
+        let info: WalletInfo = self.call("getwalletinfo", json!([]))?;
+        Ok(info.scanning.get("progress").and_then(Value::as_f64))
+    }
+
+    /// Updates the list of the wallet transactions with the ones which have
+    /// changed since the last refresh. Transactions are downloaded only once.
+    /// Fails while the blockchain is rescanned, since the wallet transactions
+    /// are known only partially until the rescan is complete.
+    fn refresh(&mut self) -> Result<(), Error> {
+        if let Some(progress) = self.rescan_progress()? {
+            return Err(Error::Message(format!(
+                "Bitcoin Core is rescanning the blockchain for the wallet transactions, {:.0}% \
+                 complete",
+                progress * 100.0
+            )));
+        }
+        let since: SinceBlock =
+            self.call("listsinceblock", json!([self.refreshed_at, 1, true, true]))?;
+        // Transactions from the blocks removed by a reorg are reported again
+        // if they are back in the mempool or in the new blocks
+        for tx in since.removed {
+            self.transactions.remove(&tx.txid);
+        }
+        for tx in since.transactions {
+            // Transactions with negative confirmations are conflicting with
+            // the blockchain and will never be mined; coinbase transactions
+            // from the blocks removed by a reorg can't return to the mempool
+            if tx.confirmations < 0 || (tx.generated && tx.confirmations == 0) {
+                self.transactions.remove(&tx.txid);
+                continue;
+            }
+            let height = tx.blockheight.unwrap_or_default() as i32;
+            if let Some((_, known_height)) = self.transactions.get_mut(&tx.txid) {
+                *known_height = height;
+                continue;
+            }
+            let hex: WalletTxHex = self.call("gettransaction", json!([tx.txid, true]))?;
+            let transaction = deserialize(&Vec::<u8>::from_hex(&hex.hex)?)?;
+            self.transactions.insert(tx.txid, (transaction, height));
+        }
+        self.refreshed_at = Some(since.lastblock);
+        Ok(())
+    }
+
+    fn script_history(&self, script: &Script) -> Vec<HistoryItem> {
+        let mut history = self
+            .transactions
+            .iter()
+            .filter(|(_, (tx, _))| {
+                tx.output.iter().any(|txout| &txout.script_pubkey == script)
+                    || tx.input.iter().any(|txin| {
+                        self.transactions
+                            .get(&txin.previous_output.txid)
+                            .and_then(|(prev_tx, _)| {
+                                prev_tx.output.get(txin.previous_output.vout as usize)
+                            })
+                            .map(|txout| &txout.script_pubkey == script)
+                            .unwrap_or_default()
+                    })
+            })
+            .map(|(txid, (_, height))| HistoryItem {
+                txid: *txid,
+                height: *height,
+                fee: None,
+            })
+            .collect::<Vec<_>>();
+        sort_history(&mut history);
+        history
+    }
+}
+
+impl ChainBackend for CoreBackend {
+    fn ping(&mut self) -> Result<(), Error> {
+        self.call_node::<Value>("getblockcount", json!([]))
+            .map(|_| ())
+    }
+
+    /// Imports ranged receiving and change descriptors for each of the wallet
+    /// descriptor classes, unless they were imported before. Bitcoin Core
+    /// rescans the blockchain since the wallet birthday once, during the
+    /// import; a long rescan is not waited for, but the wallet is not
+    /// synchronized until it is complete.
+    fn watch_wallet(&mut self, settings: &WalletSettings) -> Result<(), Error> {
+        let descriptors =
+            wallet_descriptors(settings).map_err(|err| Error::Message(err.to_string()))?;
+        let mut requests = vec![];
+        let mut new = vec![];
+        for descriptor in &descriptors {
+            for change in [false, true] {
+                let info: DescriptorInfo = self.call_node(
+                    "getdescriptorinfo",
+                    json!([core_descriptor(descriptor, change)?]),
+                )?;
+                let desc = strip_checksum(&info.descriptor).to_owned();
+                if self.imported.contains(&desc) {
+                    continue;
+                }
+                requests.push(json!({
+                    "desc": info.descriptor,
+                    "range": IMPORT_RANGE - 1,
+                    "active": true,
+                    "internal": change,
+                    "timestamp": self.birthday,
+                }));
+                new.push(desc);
+            }
+        }
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        let results: Vec<ImportResult> = match self.request(
+            &self.import_agent,
+            &self.wallet_url,
+            "importdescriptors",
+            json!([requests]),
+        ) {
+            Ok(results) => results,
+            // Descriptors are added to the wallet before the rescan starts
+            Err(err) if err.is_connection_error() && self.rescan_progress()?.is_some() => {
+                self.imported.extend(new);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        for result in results {
+            if let (false, Some(RpcError { code, message })) = (result.success, result.error) {
+                return Err(Error::Rpc { code, message });
+            }
+        }
+        self.imported.extend(new);
+        Ok(())
+    }
+
+    fn tip(&mut self) -> Result<BlockTip, Error> {
+        let hash: String = self.call_node("getbestblockhash", json!([]))?;
+        let info: HeaderInfo = self.call_node("getblockheader", json!([hash, true]))?;
+        let header = self.header(&hash)?;
+        self.last_tip = Some(header.block_hash());
+        Ok(BlockTip {
+            height: info.height,
+            header,
+        })
+    }
+
+    fn tip_update(&mut self) -> Result<Option<BlockTip>, Error> {
+        let hash: BlockHash = self.call_node("getbestblockhash", json!([]))?;
+        if Some(hash) == self.last_tip {
+            return Ok(None);
+        }
+        self.tip().map(Some)
+    }
+
+    fn script_statuses(&mut self, scripts: &[&Script]) -> Result<Vec<Option<[u8; 32]>>, Error> {
+        self.refresh()?;
+        Ok(scripts
+            .iter()
+            .map(|script| script_status(&self.script_history(script)))
+            .collect())
+    }
+
+    fn history(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<HistoryItem>>, Error> {
+        Ok(scripts
+            .iter()
+            .map(|script| self.script_history(script))
+            .collect())
+    }
+
+    fn unspent(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<UnspentItem>>, Error> {
+        let spent = self
+            .transactions
+            .values()
+            .flat_map(|(tx, _)| tx.input.iter().map(|txin| txin.previous_output))
+            .collect::<BTreeSet<_>>();
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                self.transactions
+                    .iter()
+                    .flat_map(|(txid, (tx, height))| {
+                        tx.output
+                            .iter()
+                            .enumerate()
+                            .map(move |(vout, txout)| (*txid, vout as u32, txout, *height))
+                    })
+                    .filter(|(txid, vout, txout, _)| {
+                        &txout.script_pubkey == *script
+                            && !spent.contains(&OutPoint::new(*txid, *vout))
+                    })
+                    .map(|(txid, vout, txout, height)| UnspentItem {
+                        txid,
+                        vout,
+                        value: txout.value,
+                        height: height as u32,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn headers(&mut self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error> {
+        heights
+            .iter()
+            .map(|height| {
+                let hash: String = self.call_node("getblockhash", json!([height]))?;
+                self.header(&hash)
+            })
+            .collect()
+    }
+
+    fn fee_estimates(&mut self, targets: &[usize]) -> Result<Vec<f64>, Error> {
+        // Bitcoin Core returns fee rates in BTC per kilo-vbyte, matching Electrum
+        targets
+            .iter()
+            .map(|target| {
+                let estimate: FeeEstimate = self.call_node("estimatesmartfee", json!([target]))?;
+                Ok(estimate.feerate.unwrap_or(-1.0))
+            })
+            .collect()
+    }
+
+    fn fee_histogram(&mut self) -> Result<Vec<(f64, u64)>, Error> {
+        // Bitcoin Core RPC does not expose the mempool fee histogram
+        Ok(empty!())
+    }
+
+    fn merkle_proofs(&mut self, txs: &[(Txid, u32)]) -> Result<Vec<MerkleProof>, Error> {
+        txs.iter()
+            .map(|(txid, height)| {
+                let hash: String = self.call_node("getblockhash", json!([height]))?;
+                let hex: String = self.call_node("gettxoutproof", json!([[txid], hash]))?;
+                Ok(MerkleProof::Block(deserialize(&Vec::<u8>::from_hex(
+                    &hex,
+                )?)?))
+            })
+            .collect()
+    }
+
+    fn transactions(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        txids
+            .iter()
+            .map(|txid| match self.transactions.get(txid) {
+                Some((tx, _)) => Ok(tx.clone()),
+                None => {
+                    let hex: String = self.call_node("getrawtransaction", json!([txid]))?;
+                    Ok(deserialize(&Vec::<u8>::from_hex(&hex)?)?)
+                }
+            })
+            .collect()
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid: String = self.call_node("sendrawtransaction", json!([serialize(tx).to_hex()]))?;
+        Ok(Txid::from_str(&txid)?)
+    }
+}
+
+fn strip_checksum(descriptor: &str) -> &str {
+    descriptor
+        .split_once('#')
+        .map(|(descriptor, _)| descriptor)
+        .unwrap_or(descriptor)
+}
+
+/// Replaces terminal derivation path of each descriptor key with the receiving
+/// or change terminal in the form used by Bitcoin Core.
+struct CoreTerminal {
+    /// Additional wildcard steps of multi-class wallets, which are always
+    /// derived at zero index (see [`crate::model::derive_script`]).
+    extra_steps: usize,
+    change: bool,
+}
+
+impl Translator<DerivationAccount, String, Error> for CoreTerminal {
+    fn pk(&mut self, key: &DerivationAccount) -> Result<String, Error> {
+        let xpub = key.account_xpub.to_string();
+        let key = key.to_string();
+        let origin_len = key
+            .find(&xpub)
+            .ok_or_else(|| Error::Message(format!("unsupported descriptor key {}", key)))?;
+        let mut core_key = key[..origin_len + xpub.len()].to_owned();
+        for _ in 0..self.extra_steps {
+            core_key.push_str("/0");
+        }
+        core_key.push_str(if self.change { "/1/*" } else { "/0/*" });
+        Ok(core_key)
+    }
+
+    fn sha256(
+        &mut self,
+        hash: &<DerivationAccount as MiniscriptKey>::Sha256,
+    ) -> Result<String, Error> {
+        Ok(hash.to_string())
+    }
+
+    fn hash256(
+        &mut self,
+        hash: &<DerivationAccount as MiniscriptKey>::Hash256,
+    ) -> Result<String, Error> {
+        Ok(hash.to_string())
+    }
+
+    fn ripemd160(
+        &mut self,
+        hash: &<DerivationAccount as MiniscriptKey>::Ripemd160,
+    ) -> Result<String, Error> {
+        Ok(hash.to_string())
+    }
+
+    fn hash160(
+        &mut self,
+        hash: &<DerivationAccount as MiniscriptKey>::Hash160,
+    ) -> Result<String, Error> {
+        Ok(hash.to_string())
+    }
+}
+
+/// Converts wallet descriptor into a ranged descriptor for the receiving or
+/// change addresses, without a checksum.
+fn core_descriptor(
+    descriptor: &Descriptor<DerivationAccount>,
+    change: bool,
+) -> Result<String, Error> {
+    let len = DeriveScript::derive_pattern_len(descriptor)
+        .map_err(|err| Error::Message(err.to_string()))?;
+    let descriptor = descriptor.translate_pk(&mut CoreTerminal {
+        extra_steps: len.saturating_sub(2),
+        change,
+    })?;
+    Ok(strip_checksum(&descriptor.to_string()).to_owned())
+}
+
+/// Location of the RPC cookie file used by Bitcoin Core with default settings.
+pub fn default_cookie_file(network: PublicNetwork) -> PathBuf {
+    #[cfg(target_os = "windows")]
+    let mut path = glib::user_config_dir().join("Bitcoin");
+    #[cfg(target_os = "macos")]
+    let mut path = glib::home_dir().join("Library/Application Support/Bitcoin");
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let mut path = glib::home_dir().join(".bitcoin");
+
+    match network {
+        PublicNetwork::Mainnet => {}
+        PublicNetwork::Testnet => path.push("testnet3"),
+        PublicNetwork::Signet => path.push("signet"),
+    }
+    path.join(".cookie")
+}
+
+/// Tests running against Bitcoin Core in the regtest mode. The node RPC URL
+/// and `user:password` credentials are provided in `MYCITADEL_REGTEST_URL`
+/// (`http://127.0.0.1:18443` by default) and `MYCITADEL_REGTEST_AUTH`
+/// environment variables; the tests are ignored unless run with
+/// `cargo test -- --ignored`.
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use amplify::Wrapper;
+    use bitcoin::util::bip32::ExtendedPubKey;
+    use bpro::{
+        ElectrumSec, ElectrumServer, Signer, SigsReq, SpendingCondition, TimelockReq,
+        TimelockedSigs,
+    };
+    use wallet::descriptors::DescriptorClass;
+    use wallet::hd::{TerminalStep, UnhardenedIndex};
+
+    use super::*;
+    use crate::model::derive_script;
+
+    const TPUB: &str = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod\
+                        1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+
+    fn wallet_settings() -> WalletSettings {
+        let class = DescriptorClass::SegwitV0;
+        let xpub = ExtendedPubKey::from_str(TPUB).unwrap();
+        let signer = Signer::with_xpub(xpub, &class.bip43(1), PublicNetwork::Testnet);
+        let condition = SpendingCondition::Sigs(TimelockedSigs {
+            sigs: SigsReq::All,
+            timelock: TimelockReq::Anytime,
+        });
+        WalletSettings::with_unchecked(
+            vec![signer],
+            vec![(1, condition)],
+            bset![class],
+            [TerminalStep::range(0u8, 1u8), TerminalStep::Wildcard]
+                .into_iter()
+                .collect(),
+            PublicNetwork::Testnet,
+            ElectrumServer {
+                sec: ElectrumSec::Tls,
+                server: s!("localhost"),
+                port: 50002,
+            },
+        )
+        .unwrap()
+    }
+
+    fn script(settings: &WalletSettings, index: u16) -> Script {
+        let descriptor = &wallet_descriptors(settings).unwrap()[0];
+        derive_script(descriptor, false, UnhardenedIndex::from(index))
+            .unwrap()
+            .into_inner()
+    }
+
+    /// Returns the node URL and preferences using a new Bitcoin Core wallet.
+    fn regtest() -> (String, CorePrefs) {
+        let url =
+            env::var("MYCITADEL_REGTEST_URL").unwrap_or_else(|_| s!("http://127.0.0.1:18443"));
+        let auth = env::var("MYCITADEL_REGTEST_AUTH")
+            .expect("MYCITADEL_REGTEST_AUTH must contain RPC user:password");
+        let (user, password) = auth
+            .split_once(':')
+            .expect("MYCITADEL_REGTEST_AUTH must contain RPC user:password");
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let prefs = CorePrefs {
+            url: url.clone(),
+            user: user.to_owned(),
+            password: password.to_owned(),
+            wallet: format!("mycitadel-test-{nonce}"),
+            ..default!()
+        };
+        (url, prefs)
+    }
+
+    fn connect(url: &str, prefs: &CorePrefs) -> CoreBackend {
+        CoreBackend::connect(url, prefs, PublicNetwork::Testnet, "test", None).unwrap()
+    }
+
+    fn loaded_wallets(backend: &CoreBackend) -> Vec<String> {
+        backend.call_node("listwallets", json!([])).unwrap()
+    }
+
+    /// Mines a block paying to the script, returning the block hash.
+    fn mine(backend: &CoreBackend, script: &Script) -> BlockHash {
+        let descriptor = format!("raw({})", script.as_bytes().to_hex());
+        let hashes: Vec<BlockHash> = backend
+            .call_node("generatetodescriptor", json!([1, descriptor]))
+            .unwrap();
+        hashes[0]
+    }
+
+    #[test]
+    #[ignore]
+    fn creates_and_loads_wallet() {
+        let (url, prefs) = regtest();
+        let backend = connect(&url, &prefs);
+        assert!(loaded_wallets(&backend).contains(&prefs.wallet));
+        let info: Value = backend.call("getwalletinfo", json!([])).unwrap();
+        assert_eq!(info["descriptors"], true);
+        assert_eq!(info["private_keys_enabled"], false);
+
+        // Wallet which is already loaded
+        connect(&url, &prefs);
+
+        // Wallet which exists but is not loaded
+        backend
+            .call_node::<Value>("unloadwallet", json!([prefs.wallet]))
+            .unwrap();
+        assert!(!loaded_wallets(&backend).contains(&prefs.wallet));
+        let backend = connect(&url, &prefs);
+        assert!(loaded_wallets(&backend).contains(&prefs.wallet));
+
+        backend
+            .call_node::<Value>("unloadwallet", json!([prefs.wallet]))
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn imports_descriptors_once() {
+        let (url, prefs) = regtest();
+        let settings = wallet_settings();
+        let mut backend = connect(&url, &prefs);
+        backend.watch_wallet(&settings).unwrap();
+        assert_eq!(backend.imported.len(), 2);
+        let listed: WalletDescriptors = backend.call("listdescriptors", json!([])).unwrap();
+        assert_eq!(listed.descriptors.len(), 2);
+
+        // Imported descriptors are recognized after reconnection
+        let mut backend = connect(&url, &prefs);
+        assert_eq!(backend.imported.len(), 2);
+        backend.watch_wallet(&settings).unwrap();
+        let listed: WalletDescriptors = backend.call("listdescriptors", json!([])).unwrap();
+        assert_eq!(listed.descriptors.len(), 2);
+
+        backend
+            .call_node::<Value>("unloadwallet", json!([prefs.wallet]))
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn refreshes_since_last_block() {
+        let (url, prefs) = regtest();
+        let settings = wallet_settings();
+        let mut backend = connect(&url, &prefs);
+        backend.watch_wallet(&settings).unwrap();
+        let script = script(&settings, 0);
+
+        let block = mine(&backend, &script);
+        let tip = backend.tip().unwrap();
+        assert_eq!(tip.header.block_hash(), block);
+        let status = backend.script_statuses(&[&script]).unwrap()[0];
+        assert!(status.is_some());
+        assert_eq!(backend.refreshed_at, Some(block));
+        let history = backend.history(&[&script]).unwrap().remove(0);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].height, tip.height as i32);
+        let unspent = backend.unspent(&[&script]).unwrap().remove(0);
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].txid, history[0].txid);
+
+        // Blocks without wallet transactions do not change the status
+        let other = mine(&backend, &Script::new_op_return(&[]));
+        assert_eq!(backend.script_statuses(&[&script]).unwrap()[0], status);
+        assert_eq!(backend.refreshed_at, Some(other));
+
+        // Transactions from the blocks removed by a reorg are forgotten
+        backend
+            .call_node::<Value>("invalidateblock", json!([block]))
+            .unwrap();
+        assert_eq!(backend.script_statuses(&[&script]).unwrap()[0], None);
+        assert!(backend.unspent(&[&script]).unwrap()[0].is_empty());
+        backend
+            .call_node::<Value>("reconsiderblock", json!([block]))
+            .unwrap();
+        assert_eq!(backend.script_statuses(&[&script]).unwrap()[0], status);
+
+        backend
+            .call_node::<Value>("unloadwallet", json!([prefs.wallet]))
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn fee_estimates() {
+        let (url, prefs) = regtest();
+        let mut backend = connect(&url, &prefs);
+        let estimates = backend.fee_estimates(&[1, 6, 144]).unwrap();
+        assert_eq!(estimates.len(), 3);
+        // Missing estimates are reported as -1, like Electrum does; others are
+        // in BTC per kilo-vbyte
+        for rate in estimates {
+            assert!(rate == -1.0 || (rate > 0.0 && rate < 1.0), "{rate}");
+        }
+
+        backend
+            .call_node::<Value>("unloadwallet", json!([prefs.wallet]))
+            .unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{BlockHeader, Script, Transaction, TxMerkleNode, Txid};
+use bpro::{ElectrumSec, ElectrumServer};
+use electrum_client::raw_client::RawClient;
+use electrum_client::{Client as ElectrumClient, ElectrumApi, HeaderNotification};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, ServerName, StreamOwned};
+
+use super::{BlockTip, ChainBackend, Error, MerkleProof};
+use crate::model::{HistoryItem, UnspentItem};
+use crate::worker::proxy::{is_onion, proxy_for, Socks5};
+
+/// Returns URL for connecting to the Electrum server. Tor onion services are
+/// accessed with plain TCP, since Tor already encrypts and authenticates the
+/// connection.
+pub fn server_url(server: &ElectrumServer) -> String {
+    match server.sec {
+        ElectrumSec::Tor => format!("tcp://{}:{}", server.server, server.port),
+        _ => server.to_string(),
+    }
+}
+
+/// Returns `host:port` identifying the server certificate for TLS Electrum
+/// server URLs; `None` for non-TLS connections.
+pub fn cert_key(url: &str) -> Option<&str> { url.strip_prefix("ssl://") }
+
+impl From<electrum_client::Error> for Error {
+    fn from(err: electrum_client::Error) -> Self {
+        match err {
+            electrum_client::Error::IOError(err) => Error::Io(err),
+            electrum_client::Error::SharedIOError(err) => {
+                Error::Io(io::Error::new(err.kind(), err.to_string()))
+            }
+            electrum_client::Error::Protocol(_)
+            | electrum_client::Error::InvalidResponse(_)
+            | electrum_client::Error::JSON(_)
+            | electrum_client::Error::Hex(_)
+            | electrum_client::Error::Bitcoin(_)
+            | electrum_client::Error::AlreadySubscribed(_)
+            | electrum_client::Error::NotSubscribed(_)
+            | electrum_client::Error::Message(_) => Error::Server(err.to_string()),
+            err => Error::Connection(err.to_string()),
+        }
+    }
+}
+
+impl From<HeaderNotification> for BlockTip {
+    fn from(notification: HeaderNotification) -> Self {
+        BlockTip {
+            height: notification.height as u32,
+            header: notification.header,
+        }
+    }
+}
+
+impl From<BlockTip> for HeaderNotification {
+    fn from(tip: BlockTip) -> Self {
+        HeaderNotification {
+            height: tip.height as usize,
+            header: tip.header,
+        }
+    }
+}
+
+/// TLS stream used for connections to the servers with pinned certificates.
+pub type PinnedStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Certificate verifier implementing trust-on-first-use: it accepts only the
+/// certificate with the pinned fingerprint, or any certificate if none is
+/// pinned yet, and records the fingerprint of the certificate it was
+/// presented with.
+struct PinVerifier {
+    pin: Option<String>,
+    presented: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = cert_fingerprint(end_entity);
+        *self.presented.lock().expect("poisoned mutex") = Some(fingerprint.clone());
+        match self.pin {
+            Some(ref pin) if pin != &fingerprint => {
+                Err(rustls::Error::General(s!("server certificate has changed")))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// SHA-256 fingerprint of a certificate in a DER encoding, as a hex string.
+fn cert_fingerprint(cert: &Certificate) -> String { sha256::Hash::hash(&cert.0).to_string() }
+
+pub struct ElectrumBackend<C: ElectrumApi = ElectrumClient> {
+    client: C,
+    /// Statuses of the scripts subscribed within the current server session,
+    /// as last reported by the server.
+    subscriptions: BTreeMap<Script, Option<[u8; 32]>>,
+}
+
+impl ElectrumBackend {
+    pub fn connect(server: &ElectrumServer, proxy: Option<&Socks5>) -> Result<Self, Error> {
+        ElectrumBackend::connect_url(&server_url(server), proxy)
+    }
+
+    pub fn connect_url(url: &str, proxy: Option<&Socks5>) -> Result<Self, Error> {
+        let proxy = proxy_for(url, proxy);
+        if proxy.is_none() && is_onion(url) {
+            return Err(Error::Message(s!(
+                "connecting to Tor onion service requires SOCKS5 proxy to be configured"
+            )));
+        }
+        let config = electrum_client::ConfigBuilder::new()
+            // Connections over Tor take much longer to establish
+            .timeout(Some(if proxy.is_some() { 30 } else { 5 }))
+            .socks5(proxy.map(Socks5::electrum_config))
+            .build();
+        let client = ElectrumClient::from_config(url, config)?;
+        Ok(ElectrumBackend {
+            client,
+            subscriptions: empty!(),
+        })
+    }
+}
+
+impl ElectrumBackend<RawClient<PinnedStream>> {
+    /// Connects to a TLS Electrum server accepting only the certificate with
+    /// the given SHA-256 fingerprint, instead of validating it against the
+    /// system root certificates. With no fingerprint given, accepts any
+    /// certificate. Returns fingerprint of the server certificate together
+    /// with the connection.
+    pub fn connect_pinned(
+        url: &str,
+        proxy: Option<&Socks5>,
+        pin: Option<&str>,
+    ) -> Result<(Self, String), Error> {
+        let server = cert_key(url)
+            .ok_or_else(|| Error::Message(format!("{url} is not a TLS Electrum server URL")))?;
+        let (host, port) = server
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| Error::Message(format!("invalid Electrum server address {server}")))?;
+
+        let proxy = proxy_for(url, proxy);
+        if proxy.is_none() && is_onion(url) {
+            return Err(Error::Message(s!(
+                "connecting to Tor onion service requires SOCKS5 proxy to be configured"
+            )));
+        }
+        let timeout = Duration::from_secs(if proxy.is_some() { 30 } else { 5 });
+        let tcp = match proxy {
+            Some(proxy) => proxy.connect(host, port)?,
+            None => {
+                let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+                    Error::Message(format!("unable to resolve Electrum server {host}"))
+                })?;
+                TcpStream::connect_timeout(&addr, timeout)?
+            }
+        };
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+
+        let verifier = Arc::new(PinVerifier {
+            pin: pin.map(str::to_owned),
+            presented: Mutex::new(None),
+        });
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(host)
+            .map_err(|_| Error::Message(format!("invalid Electrum server name {host}")))?;
+        let conn = ClientConnection::new(Arc::new(config), server_name)?;
+        let mut stream = StreamOwned::new(conn, tcp);
+
+        // Completing the handshake right away, such that certificate mismatch
+        // is reported as such and not as a failure of the first request
+        let handshake = stream.conn.complete_io(&mut stream.sock);
+        let presented = verifier.presented.lock().expect("poisoned mutex").clone();
+        let fingerprint = match (handshake, presented) {
+            (Err(_), Some(fingerprint)) if matches!(pin, Some(pin) if pin != fingerprint) => {
+                return Err(Error::CertificateChanged {
+                    server: server.to_owned(),
+                    fingerprint,
+                });
+            }
+            (Err(err), _) => return Err(err.into()),
+            (Ok(_), None) => {
+                return Err(Error::Message(s!("server has not presented a certificate")))
+            }
+            (Ok(_), Some(fingerprint)) => fingerprint,
+        };
+        let backend = ElectrumBackend {
+            client: RawClient::from(stream),
+            subscriptions: empty!(),
+        };
+        Ok((backend, fingerprint))
+    }
+}
+
+impl<C: ElectrumApi> ChainBackend for ElectrumBackend<C> {
+    fn ping(&mut self) -> Result<(), Error> {
+        // Electrum client reads notifications from the socket only when it
+        // awaits a response to some request, so this also pulls notifications
+        self.client.ping().map_err(Error::from)
+    }
+
+    fn tip(&mut self) -> Result<BlockTip, Error> {
+        Ok(self.client.block_headers_subscribe()?.into())
+    }
+
+    fn tip_update(&mut self) -> Result<Option<BlockTip>, Error> {
+        Ok(self.client.block_headers_pop()?.map(BlockTip::from))
+    }
+
+    fn script_statuses(&mut self, scripts: &[&Script]) -> Result<Vec<Option<[u8; 32]>>, Error> {
+        // For the already subscribed scripts we check only the status change
+        // notifications received from the server
+        let (known, new): (Vec<_>, Vec<_>) = scripts
+            .iter()
+            .copied()
+            .partition(|script| self.subscriptions.contains_key(*script));
+
+        for script in known {
+            while let Some(status) = self.client.script_pop(script)? {
+                self.subscriptions.insert(script.clone(), Some(*status));
+            }
+        }
+
+        if !new.is_empty() {
+            let statuses = self.client.batch_script_subscribe(new.iter().copied())?;
+            for (script, status) in new.into_iter().zip(statuses) {
+                self.subscriptions
+                    .insert(script.clone(), status.map(|status| *status));
+            }
+        }
+
+        Ok(scripts
+            .iter()
+            .map(|script| self.subscriptions.get(*script).copied().flatten())
+            .collect())
+    }
+
+    fn history(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<HistoryItem>>, Error> {
+        Ok(self
+            .client
+            .batch_script_get_history(scripts.iter().copied())?
+            .iter()
+            .map(|history| history.iter().map(HistoryItem::from).collect())
+            .collect())
+    }
+
+    fn unspent(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<UnspentItem>>, Error> {
+        Ok(self
+            .client
+            .batch_script_list_unspent(scripts.iter().copied())?
+            .iter()
+            .map(|unspent| unspent.iter().map(UnspentItem::from).collect())
+            .collect())
+    }
+
+    fn headers(&mut self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error> {
+        self.client.batch_block_header(heights).map_err(Error::from)
+    }
+
+    fn fee_estimates(&mut self, targets: &[usize]) -> Result<Vec<f64>, Error> {
+        self.client
+            .batch_estimate_fee(targets.iter().copied())
+            .map_err(Error::from)
+    }
+
+    fn fee_histogram(&mut self) -> Result<Vec<(f64, u64)>, Error> {
+        let value = self.client.raw_call("mempool.get_fee_histogram", [])?;
+        serde_json::from_value(value.clone())
+            .map_err(|_| Error::Server(format!("invalid fee histogram {value}")))
+    }
+
+    fn merkle_proofs(&mut self, txs: &[(Txid, u32)]) -> Result<Vec<MerkleProof>, Error> {
+        txs.iter()
+            .map(|(txid, height)| {
+                let res = self.client.transaction_get_merkle(txid, *height as usize)?;
+                // Electrum servers provide hashes in the reversed byte order
+                let merkle = res
+                    .merkle
+                    .into_iter()
+                    .map(|mut hash| {
+                        hash.reverse();
+                        TxMerkleNode::from_inner(hash)
+                    })
+                    .collect();
+                Ok(MerkleProof::Branch {
+                    pos: res.pos,
+                    merkle,
+                })
+            })
+            .collect()
+    }
+
+    fn transactions(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        self.client
+            .batch_transaction_get(txids)
+            .map_err(Error::from)
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Error> {
+        self.client.transaction_broadcast(tx).map_err(Error::from)
+    }
+}
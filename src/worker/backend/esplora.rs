@@ -0,0 +1,567 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::{BlockHash, BlockHeader, Script, Transaction, TxMerkleNode, Txid};
+use serde_crate::Deserialize;
+
+use super::{sort_history, BlockTip, ChainBackend, Error, MerkleProof};
+use crate::model::{HistoryItem, UnspentItem};
+use crate::worker::proxy::{http_agent, is_onion, proxy_for, Socks5};
+
+/// Esplora returns this number of confirmed transactions per page.
+const CHAIN_PAGE_SIZE: usize = 25;
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Block {
+    height: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct TxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Tx {
+    txid: Txid,
+    status: TxStatus,
+    fee: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Stats {
+    funded_txo_count: u64,
+    funded_txo_sum: u64,
+    spent_txo_count: u64,
+    spent_txo_sum: u64,
+    tx_count: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct ScriptStats {
+    chain_stats: Stats,
+    mempool_stats: Stats,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Utxo {
+    txid: Txid,
+    vout: u32,
+    value: u64,
+    status: TxStatus,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Mempool {
+    fee_histogram: Vec<(f64, u64)>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct MerkleBranch {
+    merkle: Vec<TxMerkleNode>,
+    pos: usize,
+}
+
+impl TxStatus {
+    fn height(&self) -> u32 {
+        match (self.confirmed, self.block_height) {
+            (true, Some(height)) => height,
+            _ => 0,
+        }
+    }
+}
+
+pub struct EsploraBackend {
+    url: String,
+    agent: ureq::Agent,
+    last_tip: Option<BlockHash>,
+}
+
+impl EsploraBackend {
+    pub fn connect(url: &str, proxy: Option<&Socks5>) -> Result<Self, Error> {
+        if proxy_for(url, proxy).is_none() && is_onion(url) {
+            return Err(Error::Message(s!(
+                "connecting to Tor onion service requires SOCKS5 proxy to be configured"
+            )));
+        }
+        let mut backend = EsploraBackend {
+            url: url.trim_end_matches('/').to_owned(),
+            agent: http_agent(url, proxy, Duration::from_secs(30))?,
+            last_tip: None,
+        };
+        backend.ping()?;
+        Ok(backend)
+    }
+
+    fn get(&self, path: &str) -> Result<String, Error> {
+        let url = format!("{}{}", self.url, path);
+        Ok(self.agent.get(&url).call()?.into_string()?)
+    }
+
+    fn get_json<T>(&self, path: &str) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let url = format!("{}{}", self.url, path);
+        Ok(self.agent.get(&url).call()?.into_json()?)
+    }
+
+    fn header(&self, hash: &str) -> Result<BlockHeader, Error> {
+        let hex = self.get(&format!("/block/{}/header", hash))?;
+        Ok(deserialize(&Vec::<u8>::from_hex(hex.trim())?)?)
+    }
+
+    /// Computes script status from the script statistics, such that it
+    /// changes whenever a transaction spending or funding the script gets
+    /// mined or enters the mempool. Unlike the full script history, the
+    /// statistics are retrieved with a single request.
+    ///
+    /// Mempool transactions are included into the status, since replacing
+    /// one of them may leave the statistics unchanged; reorgs are detected
+    /// by the header chain and make the cached status stale.
+    fn script_status(&self, script: &Script) -> Result<Option<[u8; 32]>, Error> {
+        let scripthash = scripthash(script);
+        let stats: ScriptStats = self.get_json(&format!("/scripthash/{}", scripthash))?;
+        if stats.chain_stats.tx_count + stats.mempool_stats.tx_count == 0 {
+            return Ok(None);
+        }
+        let mut engine = sha256::Hash::engine();
+        for stats in [&stats.chain_stats, &stats.mempool_stats] {
+            for value in [
+                stats.funded_txo_count,
+                stats.funded_txo_sum,
+                stats.spent_txo_count,
+                stats.spent_txo_sum,
+                stats.tx_count,
+            ] {
+                engine.input(&value.to_le_bytes());
+            }
+        }
+        if stats.mempool_stats.tx_count > 0 {
+            let mut txs: Vec<Tx> =
+                self.get_json(&format!("/scripthash/{}/txs/mempool", scripthash))?;
+            txs.sort_by_key(|tx| tx.txid);
+            for tx in txs {
+                engine.input(&tx.txid[..]);
+            }
+        }
+        Ok(Some(sha256::Hash::from_engine(engine).into_inner()))
+    }
+
+    fn script_history(&self, script: &Script) -> Result<Vec<HistoryItem>, Error> {
+        let scripthash = scripthash(script);
+        let mut txs: Vec<Tx> = self.get_json(&format!("/scripthash/{}/txs", scripthash))?;
+        let mut page_len = txs.iter().filter(|tx| tx.status.confirmed).count();
+        while page_len >= CHAIN_PAGE_SIZE {
+            let last = txs.last().expect("non-empty page").txid;
+            let page: Vec<Tx> =
+                self.get_json(&format!("/scripthash/{}/txs/chain/{}", scripthash, last))?;
+            page_len = page.len();
+            txs.extend(page);
+        }
+
+        let mut history = txs
+            .into_iter()
+            .map(|tx| HistoryItem {
+                txid: tx.txid,
+                height: tx.status.height() as i32,
+                fee: (!tx.status.confirmed).then(|| tx.fee),
+            })
+            .collect::<Vec<_>>();
+        sort_history(&mut history);
+        Ok(history)
+    }
+}
+
+impl ChainBackend for EsploraBackend {
+    fn ping(&mut self) -> Result<(), Error> { self.get("/blocks/tip/height").map(|_| ()) }
+
+    fn tip(&mut self) -> Result<BlockTip, Error> {
+        let hash = self.get("/blocks/tip/hash")?;
+        let hash = hash.trim();
+        let header = self.header(hash)?;
+        let block: Block = self.get_json(&format!("/block/{}", hash))?;
+        self.last_tip = Some(header.block_hash());
+        Ok(BlockTip {
+            height: block.height,
+            header,
+        })
+    }
+
+    fn tip_update(&mut self) -> Result<Option<BlockTip>, Error> {
+        let hash = BlockHash::from_str(self.get("/blocks/tip/hash")?.trim())?;
+        if Some(hash) == self.last_tip {
+            return Ok(None);
+        }
+        self.tip().map(Some)
+    }
+
+    fn script_statuses(&mut self, scripts: &[&Script]) -> Result<Vec<Option<[u8; 32]>>, Error> {
+        scripts
+            .iter()
+            .map(|script| self.script_status(script))
+            .collect()
+    }
+
+    fn history(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<HistoryItem>>, Error> {
+        scripts
+            .iter()
+            .map(|script| self.script_history(script))
+            .collect()
+    }
+
+    fn unspent(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<UnspentItem>>, Error> {
+        scripts
+            .iter()
+            .map(|script| {
+                let utxos: Vec<Utxo> =
+                    self.get_json(&format!("/scripthash/{}/utxo", scripthash(script)))?;
+                Ok(utxos
+                    .into_iter()
+                    .map(|utxo| UnspentItem {
+                        txid: utxo.txid,
+                        vout: utxo.vout,
+                        value: utxo.value,
+                        height: utxo.status.height(),
+                    })
+                    .collect())
+            })
+            .collect()
+    }
+
+    fn headers(&mut self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error> {
+        heights
+            .iter()
+            .map(|height| {
+                let hash = self.get(&format!("/block-height/{}", height))?;
+                self.header(hash.trim())
+            })
+            .collect()
+    }
+
+    fn fee_estimates(&mut self, targets: &[usize]) -> Result<Vec<f64>, Error> {
+        // Esplora returns estimations in sat/vbyte for a fixed set of targets
+        let estimates: BTreeMap<String, f64> = self.get_json("/fee-estimates")?;
+        let estimates = estimates
+            .into_iter()
+            .filter_map(|(target, fee)| target.parse::<usize>().ok().map(|target| (target, fee)))
+            .collect::<BTreeMap<_, _>>();
+        Ok(targets
+            .iter()
+            .map(|target| {
+                estimates
+                    .range(..=*target)
+                    .next_back()
+                    .or_else(|| estimates.iter().next())
+                    .map(|(_, fee)| fee / 100_000.0)
+                    .unwrap_or(-1.0)
+            })
+            .collect())
+    }
+
+    fn fee_histogram(&mut self) -> Result<Vec<(f64, u64)>, Error> {
+        let mempool: Mempool = self.get_json("/mempool")?;
+        Ok(mempool.fee_histogram)
+    }
+
+    fn merkle_proofs(&mut self, txs: &[(Txid, u32)]) -> Result<Vec<MerkleProof>, Error> {
+        txs.iter()
+            .map(|(txid, _)| {
+                let proof: MerkleBranch = self.get_json(&format!("/tx/{}/merkle-proof", txid))?;
+                Ok(MerkleProof::Branch {
+                    pos: proof.pos,
+                    merkle: proof.merkle,
+                })
+            })
+            .collect()
+    }
+
+    fn transactions(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        txids
+            .iter()
+            .map(|txid| {
+                let hex = self.get(&format!("/tx/{}/hex", txid))?;
+                Ok(deserialize(&Vec::<u8>::from_hex(hex.trim())?)?)
+            })
+            .collect()
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Error> {
+        let url = format!("{}/tx", self.url);
+        let txid = self
+            .agent
+            .post(&url)
+            .send_string(&serialize(tx).to_hex())?
+            .into_string()?;
+        Ok(Txid::from_str(txid.trim())?)
+    }
+}
+
+/// Computes script hash in the form used by Esplora and Electrum APIs.
+fn scripthash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+
+    const TIP_HASH: &str = "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd";
+    const TIP_HEADER: &str = "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300\
+                              000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f\
+                              9bb0bc6649ffff001d08d2bd61";
+    const TXID_1: &str = "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098";
+    const TXID_2: &str = "9b0fc92260312ce44e74ef369f5c66bbb85848f2eddd5a7a1cde251e54ccfdd5";
+
+    /// HTTP server responding with the predefined data to requests for the
+    /// known paths, which records paths of all the requests it receives.
+    struct MockServer {
+        url: String,
+        responses: Arc<Mutex<BTreeMap<String, String>>>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let responses = Arc::new(Mutex::new(bmap! {
+                s!("/blocks/tip/height") => s!("2")
+            }));
+            let requests = Arc::new(Mutex::new(vec![]));
+            let (resp, reqs) = (responses.clone(), requests.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let mut line = s!("");
+                    let mut reader = BufReader::new(&stream);
+                    reader.read_line(&mut line).unwrap();
+                    // Skipping request headers
+                    let mut header = s!("..");
+                    while header.trim() != "" {
+                        header.clear();
+                        reader.read_line(&mut header).unwrap();
+                    }
+                    let path = line.split(' ').nth(1).unwrap_or_default().to_owned();
+                    let response = match resp.lock().unwrap().get(&path) {
+                        Some(body) => format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        ),
+                        None => s!(
+                            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: \
+                             close\r\n\r\n"
+                        ),
+                    };
+                    reqs.lock().unwrap().push(path);
+                    (&stream).write_all(response.as_bytes()).unwrap();
+                }
+            });
+            MockServer {
+                url,
+                responses,
+                requests,
+            }
+        }
+
+        fn respond(&self, path: impl ToString, body: impl ToString) {
+            self.responses
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), body.to_string());
+        }
+
+        fn requests(&self) -> Vec<String> { self.requests.lock().unwrap().drain(..).collect() }
+    }
+
+    fn stats(tx_count: u64, funded_sum: u64) -> String {
+        format!(
+            r#"{{"funded_txo_count":{tx_count},"funded_txo_sum":{funded_sum},"spent_txo_count":0,"spent_txo_sum":0,"tx_count":{tx_count}}}"#
+        )
+    }
+
+    fn script_stats(chain: String, mempool: String) -> String {
+        format!(r#"{{"scripthash":"","chain_stats":{chain},"mempool_stats":{mempool}}}"#)
+    }
+
+    fn tx(txid: &str, height: Option<u32>, fee: u64) -> String {
+        let status = match height {
+            Some(height) => format!(r#"{{"confirmed":true,"block_height":{height}}}"#),
+            None => s!(r#"{"confirmed":false}"#),
+        };
+        format!(r#"{{"txid":"{txid}","version":1,"locktime":0,"fee":{fee},"status":{status}}}"#)
+    }
+
+    #[test]
+    fn tip() {
+        let server = MockServer::start();
+        server.respond("/blocks/tip/hash", TIP_HASH);
+        server.respond(format!("/block/{}/header", TIP_HASH), TIP_HEADER);
+        server.respond(
+            format!("/block/{}", TIP_HASH),
+            r#"{"height":2,"tx_count":1}"#,
+        );
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+
+        let tip = backend.tip().unwrap();
+        assert_eq!(tip.height, 2);
+        assert_eq!(tip.header.block_hash().to_string(), TIP_HASH);
+        assert_eq!(backend.tip_update().unwrap(), None);
+    }
+
+    #[test]
+    fn script_statuses() {
+        let server = MockServer::start();
+        let unused = Script::new_op_return(&[0]);
+        let used = Script::new_op_return(&[1]);
+        server.respond(
+            format!("/scripthash/{}", scripthash(&unused)),
+            script_stats(stats(0, 0), stats(0, 0)),
+        );
+        server.respond(
+            format!("/scripthash/{}", scripthash(&used)),
+            script_stats(stats(1, 5000), stats(0, 0)),
+        );
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+        server.requests();
+
+        let statuses = backend.script_statuses(&[&unused, &used]).unwrap();
+        assert_eq!(statuses[0], None);
+        assert!(statuses[1].is_some());
+        // Statuses do not require retrieving script histories
+        assert_eq!(server.requests(), vec![
+            format!("/scripthash/{}", scripthash(&unused)),
+            format!("/scripthash/{}", scripthash(&used)),
+        ]);
+
+        // Unchanged statistics give the same status
+        assert_eq!(backend.script_statuses(&[&used]).unwrap()[0], statuses[1]);
+
+        // New mempool transaction changes the status
+        server.respond(
+            format!("/scripthash/{}", scripthash(&used)),
+            script_stats(stats(1, 5000), stats(1, 1000)),
+        );
+        server.respond(
+            format!("/scripthash/{}/txs/mempool", scripthash(&used)),
+            format!("[{}]", tx(TXID_2, None, 200)),
+        );
+        let mempool = backend.script_statuses(&[&used]).unwrap()[0];
+        assert!(mempool.is_some());
+        assert_ne!(mempool, statuses[1]);
+
+        // Replacing the mempool transaction changes the status, even if the
+        // statistics remain the same
+        server.respond(
+            format!("/scripthash/{}/txs/mempool", scripthash(&used)),
+            format!("[{}]", tx(TXID_1, None, 300)),
+        );
+        let replaced = backend.script_statuses(&[&used]).unwrap()[0];
+        assert!(replaced.is_some());
+        assert_ne!(replaced, mempool);
+    }
+
+    #[test]
+    fn history() {
+        let server = MockServer::start();
+        let script = Script::new_op_return(&[1]);
+        server.respond(
+            format!("/scripthash/{}/txs", scripthash(&script)),
+            format!("[{},{}]", tx(TXID_2, None, 200), tx(TXID_1, Some(1), 0)),
+        );
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+
+        let history = backend.history(&[&script]).unwrap();
+        assert_eq!(history, vec![vec![
+            HistoryItem {
+                txid: Txid::from_str(TXID_1).unwrap(),
+                height: 1,
+                fee: None,
+            },
+            HistoryItem {
+                txid: Txid::from_str(TXID_2).unwrap(),
+                height: 0,
+                fee: Some(200),
+            },
+        ]]);
+    }
+
+    #[test]
+    fn unspent() {
+        let server = MockServer::start();
+        let script = Script::new_op_return(&[1]);
+        server.respond(
+            format!("/scripthash/{}/utxo", scripthash(&script)),
+            format!(
+                r#"[{{"txid":"{TXID_1}","vout":0,"value":5000000000,"status":{{"confirmed":true,"block_height":1}}}},{{"txid":"{TXID_2}","vout":1,"value":1000,"status":{{"confirmed":false}}}}]"#
+            ),
+        );
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+
+        let unspent = backend.unspent(&[&script]).unwrap();
+        assert_eq!(unspent, vec![vec![
+            UnspentItem {
+                txid: Txid::from_str(TXID_1).unwrap(),
+                vout: 0,
+                value: 5_000_000_000,
+                height: 1,
+            },
+            UnspentItem {
+                txid: Txid::from_str(TXID_2).unwrap(),
+                vout: 1,
+                value: 1000,
+                height: 0,
+            },
+        ]]);
+    }
+
+    #[test]
+    fn fee_estimates() {
+        let server = MockServer::start();
+        server.respond("/fee-estimates", r#"{"1":20.0,"6":10.0,"144":1.0}"#);
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+
+        let estimates = backend.fee_estimates(&[1, 3, 6, 1008]).unwrap();
+        assert_eq!(estimates, vec![0.0002, 0.0002, 0.0001, 0.00001]);
+    }
+
+    #[test]
+    fn missing_data() {
+        let server = MockServer::start();
+        let mut backend = EsploraBackend::connect(&server.url, None).unwrap();
+        assert!(backend.fee_histogram().is_err());
+        assert!(backend.tip().is_err());
+    }
+}
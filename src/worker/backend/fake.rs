@@ -0,0 +1,144 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+
+use bitcoin::{BlockHash, BlockHeader, Script, Transaction, Txid};
+
+use super::{script_status, BlockTip, ChainBackend, Error, MerkleProof};
+use crate::model::{HistoryItem, UnspentItem};
+
+/// In-memory backend serving predefined blockchain data, which records the
+/// requests made to it.
+///
+/// Blocks are assumed to contain a single transaction each, such that the
+/// merkle root of a block is the id of the transaction mined in it.
+#[derive(Default)]
+pub struct FakeBackend {
+    /// Block headers by their height; the last one is the chain tip.
+    pub headers: BTreeMap<u32, BlockHeader>,
+    pub history: BTreeMap<Script, Vec<HistoryItem>>,
+    pub unspent: BTreeMap<Script, Vec<UnspentItem>>,
+    pub transactions: BTreeMap<Txid, Transaction>,
+    /// Scripts which history was requested, in the order of the requests.
+    pub history_requests: Vec<Script>,
+    /// Transactions which were requested, in the order of the requests.
+    pub tx_requests: Vec<Txid>,
+    last_tip: Option<BlockHash>,
+}
+
+impl FakeBackend {
+    fn current_tip(&self) -> Result<BlockTip, Error> {
+        self.headers
+            .iter()
+            .next_back()
+            .map(|(height, header)| BlockTip {
+                height: *height,
+                header: *header,
+            })
+            .ok_or_else(|| Error::Message(s!("no blocks")))
+    }
+}
+
+impl ChainBackend for FakeBackend {
+    fn ping(&mut self) -> Result<(), Error> { Ok(()) }
+
+    fn tip(&mut self) -> Result<BlockTip, Error> {
+        let tip = self.current_tip()?;
+        self.last_tip = Some(tip.header.block_hash());
+        Ok(tip)
+    }
+
+    fn tip_update(&mut self) -> Result<Option<BlockTip>, Error> {
+        let tip = self.current_tip()?;
+        if Some(tip.header.block_hash()) == self.last_tip {
+            return Ok(None);
+        }
+        self.tip().map(Some)
+    }
+
+    fn script_statuses(&mut self, scripts: &[&Script]) -> Result<Vec<Option<[u8; 32]>>, Error> {
+        Ok(scripts
+            .iter()
+            .map(|script| {
+                script_status(
+                    self.history
+                        .get(*script)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    fn history(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<HistoryItem>>, Error> {
+        self.history_requests
+            .extend(scripts.iter().map(|script| (*script).clone()));
+        Ok(scripts
+            .iter()
+            .map(|script| self.history.get(*script).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    fn unspent(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<UnspentItem>>, Error> {
+        Ok(scripts
+            .iter()
+            .map(|script| self.unspent.get(*script).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    fn headers(&mut self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error> {
+        heights
+            .iter()
+            .map(|height| {
+                self.headers
+                    .get(height)
+                    .copied()
+                    .ok_or_else(|| Error::Message(format!("no block at height {height}")))
+            })
+            .collect()
+    }
+
+    fn fee_estimates(&mut self, targets: &[usize]) -> Result<Vec<f64>, Error> {
+        Ok(targets.iter().map(|_| 0.00001).collect())
+    }
+
+    fn fee_histogram(&mut self) -> Result<Vec<(f64, u64)>, Error> { Ok(empty!()) }
+
+    fn merkle_proofs(&mut self, txs: &[(Txid, u32)]) -> Result<Vec<MerkleProof>, Error> {
+        Ok(txs
+            .iter()
+            .map(|_| MerkleProof::Branch {
+                pos: 0,
+                merkle: empty!(),
+            })
+            .collect())
+    }
+
+    fn transactions(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, Error> {
+        self.tx_requests.extend(txids);
+        txids
+            .iter()
+            .map(|txid| {
+                self.transactions
+                    .get(txid)
+                    .cloned()
+                    .ok_or_else(|| Error::Message(format!("unknown transaction {txid}")))
+            })
+            .collect()
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid = tx.txid();
+        self.transactions.insert(txid, tx.clone());
+        Ok(txid)
+    }
+}
@@ -0,0 +1,369 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+mod bitcoind;
+mod electrum;
+mod esplora;
+#[cfg(test)]
+pub mod fake;
+
+use std::{io, iter};
+
+use bitcoin::hashes::{hex, sha256, Hash, HashEngine};
+use bitcoin::util::merkleblock::MerkleBlock;
+use bitcoin::{consensus, BlockHeader, Script, Transaction, TxMerkleNode, Txid};
+pub use bitcoind::{default_cookie_file, CoreBackend};
+use bpro::{ElectrumServer, WalletSettings};
+pub use electrum::{cert_key, server_url, ElectrumBackend, PinnedStream};
+pub use esplora::EsploraBackend;
+use wallet::onchain::PublicNetwork;
+
+use crate::model::{sidecar, BackendType, ChainError, HistoryItem, UnspentItem, WalletPrefs};
+use crate::worker::proxy::{ProxyError, Socks5};
+
+#[derive(Debug, Display, From, Error)]
+#[display(inner)]
+pub enum Error {
+    /// Connection to the server has failed or was broken.
+    Connection(String),
+
+    /// Server has rejected the request or responded with malformed data.
+    Server(String),
+
+    Http(Box<ureq::Error>),
+
+    #[from]
+    Io(io::Error),
+
+    #[from]
+    Hex(hex::Error),
+
+    #[from]
+    Encoding(consensus::encode::Error),
+
+    #[from]
+    Cache(sidecar::Error),
+
+    #[from]
+    Tls(rustls::Error),
+
+    #[from]
+    Proxy(ProxyError),
+
+    /// Backend has provided block headers which failed verification.
+    #[from]
+    Chain(ChainError),
+
+    /// Electrum server has presented a certificate different from the one
+    /// trusted for it before.
+    #[display(
+        "certificate of Electrum server {server} has changed, new SHA-256 fingerprint is \
+         {fingerprint}. If the change is expected, test the connection in the wallet settings to \
+         trust the new certificate"
+    )]
+    CertificateChanged {
+        server: String,
+        fingerprint: String,
+    },
+
+    /// Error returned by Bitcoin Core JSON-RPC.
+    #[display("Bitcoin Core RPC error {code}: {message}")]
+    Rpc {
+        code: i64,
+        message: String,
+    },
+
+    /// Backend-specific failure which does not have a dedicated error type.
+    Message(String),
+
+    /// Synchronization was cancelled by the user.
+    #[display("synchronization was cancelled")]
+    Cancelled,
+}
+
+impl From<ureq::Error> for Error {
+    fn from(err: ureq::Error) -> Self { Error::Http(Box::new(err)) }
+}
+
+impl Error {
+    /// Detects errors caused by a broken or unavailable server connection,
+    /// which may be resolved by connecting to the same or other server again.
+    /// Errors reported by a reachable server do not drop the connection.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Error::Connection(_) | Error::Io(_) => true,
+            Error::Http(err) => matches!(**err, ureq::Error::Transport(_)),
+            _ => false,
+        }
+    }
+}
+
+/// Most recent block of the blockchain known to the backend.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BlockTip {
+    pub height: u32,
+    pub header: BlockHeader,
+}
+
+/// Returns servers for the backend selected in the wallet preferences, in the
+/// order of their priority.
+pub fn servers(
+    electrum: &ElectrumServer,
+    prefs: &WalletPrefs,
+    network: PublicNetwork,
+) -> Vec<String> {
+    match prefs.backend {
+        BackendType::Electrum => iter::once(server_url(electrum))
+            .chain(prefs.electrum_fallback.iter().cloned())
+            .collect(),
+        BackendType::Esplora => vec![prefs.esplora_url(network).to_owned()],
+        BackendType::BitcoinCore => vec![prefs.core.url(network).to_owned()],
+    }
+}
+
+/// Connects to one of the servers returned by [`servers`] using the backend
+/// selected in the wallet preferences. Connections to remote servers go
+/// through the proxy, if one is given.
+///
+/// The `wallet_id` is the wallet file name without the extension, which
+/// distinguishes data kept for different wallets by the backend.
+pub fn connect(
+    server: &str,
+    prefs: &WalletPrefs,
+    network: PublicNetwork,
+    wallet_id: &str,
+    proxy: Option<&Socks5>,
+) -> Result<Box<dyn ChainBackend>, Error> {
+    Ok(match prefs.backend {
+        BackendType::Electrum => {
+            match cert_key(server).and_then(|key| prefs.electrum_certs.get(key)) {
+                Some(pin) => Box::new(ElectrumBackend::connect_pinned(server, proxy, Some(pin))?.0),
+                None => Box::new(ElectrumBackend::connect_url(server, proxy)?),
+            }
+        }
+        BackendType::Esplora => Box::new(EsploraBackend::connect(server, proxy)?),
+        BackendType::BitcoinCore => Box::new(CoreBackend::connect(
+            server,
+            &prefs.core,
+            network,
+            wallet_id,
+            proxy,
+        )?),
+    })
+}
+
+/// Checks that the server is reachable with the given preferences. Unlike
+/// [`connect`], does not create a wallet on a Bitcoin Core node.
+pub fn test(
+    server: &str,
+    prefs: &WalletPrefs,
+    network: PublicNetwork,
+    wallet_id: &str,
+    proxy: Option<&Socks5>,
+) -> Result<(), Error> {
+    match prefs.backend {
+        BackendType::BitcoinCore => {
+            CoreBackend::connect_node(server, &prefs.core, network, wallet_id, proxy)?.ping()
+        }
+        _ => connect(server, prefs, network, wallet_id, proxy)?.ping(),
+    }
+}
+
+/// Orders script history in the same way as Electrum servers do: confirmed
+/// transactions go first, by their height, followed by mempool transactions.
+fn sort_history(history: &mut [HistoryItem]) {
+    history.sort_by_key(|item| (item.height <= 0, item.height, item.txid));
+}
+
+/// Computes script status from its history in the same way as Electrum
+/// servers do.
+fn script_status(history: &[HistoryItem]) -> Option<[u8; 32]> {
+    if history.is_empty() {
+        return None;
+    }
+    let status = history
+        .iter()
+        .map(|item| format!("{}:{}:", item.txid, item.height))
+        .collect::<String>();
+    Some(sha256::Hash::hash(status.as_bytes()).into_inner())
+}
+
+/// Proof of a transaction inclusion into a block.
+pub enum MerkleProof {
+    /// Merkle branch, as provided by Electrum and Esplora servers: hashes of
+    /// the sibling nodes from the leaf up to the root, together with the
+    /// transaction position in the block.
+    Branch {
+        pos: usize,
+        merkle: Vec<TxMerkleNode>,
+    },
+
+    /// Partial merkle tree, as provided by Bitcoin Core.
+    Block(MerkleBlock),
+}
+
+impl MerkleProof {
+    /// Checks that the proof commits to the transaction being included into
+    /// the block with the given header.
+    pub fn verify(&self, txid: Txid, header: &BlockHeader) -> bool {
+        match self {
+            MerkleProof::Branch { pos, merkle } => {
+                let mut index = *pos;
+                let mut node = TxMerkleNode::from_hash(txid.as_hash());
+                for sibling in merkle {
+                    let mut engine = TxMerkleNode::engine();
+                    if index % 2 == 0 {
+                        engine.input(&node[..]);
+                        engine.input(&sibling[..]);
+                    } else {
+                        engine.input(&sibling[..]);
+                        engine.input(&node[..]);
+                    }
+                    node = TxMerkleNode::from_engine(engine);
+                    index /= 2;
+                }
+                node == header.merkle_root
+            }
+            MerkleProof::Block(block) => {
+                let mut matches = vec![];
+                let mut indexes = vec![];
+                block.header.block_hash() == header.block_hash()
+                    && block.extract_matches(&mut matches, &mut indexes).is_ok()
+                    && matches.contains(&txid)
+            }
+        }
+    }
+}
+
+/// Source of the blockchain data used by the wallet.
+///
+/// All methods working with lists of scripts, heights or transaction ids
+/// return results in the same order as the items were provided.
+pub trait ChainBackend {
+    /// Checks that the backend is still reachable.
+    fn ping(&mut self) -> Result<(), Error>;
+
+    /// Prepares the backend for tracking scripts of the wallet. Backends which
+    /// do not index the blockchain by scripts require the wallet descriptors
+    /// to be imported before the scripts can be queried.
+    fn watch_wallet(&mut self, _settings: &WalletSettings) -> Result<(), Error> { Ok(()) }
+
+    /// Returns the most recent block known to the backend.
+    fn tip(&mut self) -> Result<BlockTip, Error>;
+
+    /// Returns new most recent block, if it has changed since the last call to
+    /// [`ChainBackend::tip`] or [`ChainBackend::tip_update`].
+    fn tip_update(&mut self) -> Result<Option<BlockTip>, Error>;
+
+    /// Returns status of each of the scripts: a hash which changes each time
+    /// the script history changes; `None` for scripts without history. Backends
+    /// supporting notifications subscribe to the script status changes, such
+    /// that subsequent calls do not require network requests.
+    fn script_statuses(&mut self, scripts: &[&Script]) -> Result<Vec<Option<[u8; 32]>>, Error>;
+
+    fn history(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<HistoryItem>>, Error>;
+
+    fn unspent(&mut self, scripts: &[&Script]) -> Result<Vec<Vec<UnspentItem>>, Error>;
+
+    fn headers(&mut self, heights: &[u32]) -> Result<Vec<BlockHeader>, Error>;
+
+    /// Returns fee rate estimations, in BTC per kilobyte, for the transaction
+    /// to be confirmed within each of the given number of blocks.
+    fn fee_estimates(&mut self, targets: &[usize]) -> Result<Vec<f64>, Error>;
+
+    /// Returns mempool fee histogram as a list of fee rates, in sat/vbyte, and
+    /// total virtual size of the mempool transactions paying that rate, in
+    /// descending order of the fee rates. Empty if the backend does not
+    /// provide the histogram.
+    fn fee_histogram(&mut self) -> Result<Vec<(f64, u64)>, Error>;
+
+    /// Returns proofs of inclusion of each of the transactions into the block
+    /// at the given height.
+    fn merkle_proofs(&mut self, txs: &[(Txid, u32)]) -> Result<Vec<MerkleProof>, Error>;
+
+    fn transactions(&mut self, txids: &[Txid]) -> Result<Vec<Transaction>, Error>;
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Error>;
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+
+    use super::*;
+
+    /// Header of the mainnet block 80000, which has two transactions.
+    const HEADER_80000: &str = "01000000ba8b9cda965dd8e536670f9ddec10e53aab14b20bacad27b91371900000\
+                                00000190760b278fe7b8565fda3b968b918d5fd997f993b23674c0af3b6fde300b3\
+                                8f33a5914ce6ed5b1b01e32f57";
+    const COINBASE_TXID: &str = "c06fbab289f723c6261d3030ddb6be121f7d2508d77862bb1e484f5cd7f92b25";
+    const TXID: &str = "5a4ebf66822b0b2d56bd9dc64ece0bc38ee7844a23ff1d7320a88c5fdb2ad3e2";
+    /// Output of `gettxoutproof` for the second transaction of the block 80000.
+    const TXOUTPROOF: &str = "01000000ba8b9cda965dd8e536670f9ddec10e53aab14b20bacad27b913719000000\
+                              0000190760b278fe7b8565fda3b968b918d5fd997f993b23674c0af3b6fde300b38f\
+                              33a5914ce6ed5b1b01e32f570200000002252bf9d75c4f481ebb6278d708257d1f12be\
+                              b6dd30301d26c623f789b2ba6fc0e2d32adb5f8ca820731dff234a84e78ec30bce4ec6\
+                              9dbd562d0b2b8266bf4e5a0105";
+
+    fn header() -> BlockHeader { deserialize(&Vec::<u8>::from_hex(HEADER_80000).unwrap()).unwrap() }
+
+    fn txid() -> Txid { Txid::from_hex(TXID).unwrap() }
+
+    fn branch(pos: usize) -> MerkleProof {
+        MerkleProof::Branch {
+            pos,
+            merkle: vec![TxMerkleNode::from_hex(COINBASE_TXID).unwrap()],
+        }
+    }
+
+    fn txoutproof() -> MerkleProof {
+        MerkleProof::Block(deserialize(&Vec::<u8>::from_hex(TXOUTPROOF).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn verifies_branch() {
+        assert!(branch(1).verify(txid(), &header()));
+    }
+
+    #[test]
+    fn rejects_tampered_branch() {
+        // Wrong position of the transaction in the block
+        assert!(!branch(0).verify(txid(), &header()));
+        // Proof for a different transaction
+        let other = Txid::from_hex(COINBASE_TXID).unwrap();
+        assert!(!branch(1).verify(other, &header()));
+        // Tampered sibling hash
+        let merkle = vec![TxMerkleNode::from_hex(TXID).unwrap()];
+        let proof = MerkleProof::Branch { pos: 1, merkle };
+        assert!(!proof.verify(txid(), &header()));
+        // Header of a different block
+        let mut header = header();
+        header.nonce += 1;
+        assert!(!branch(1).verify(txid(), &header));
+    }
+
+    #[test]
+    fn verifies_txoutproof() {
+        assert!(txoutproof().verify(txid(), &header()));
+    }
+
+    #[test]
+    fn rejects_tampered_txoutproof() {
+        // Transaction which is not matched by the proof
+        let other = Txid::from_hex(COINBASE_TXID).unwrap();
+        assert!(!txoutproof().verify(other, &header()));
+        // Header of a different block
+        let mut header = header();
+        header.merkle_root = TxMerkleNode::from_hex(COINBASE_TXID).unwrap();
+        assert!(!txoutproof().verify(txid(), &header));
+    }
+}
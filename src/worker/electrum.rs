@@ -10,297 +10,1203 @@
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use amplify::Wrapper;
-use bitcoin::Transaction;
+use bitcoin::{Address, BlockHeader, Script, Transaction, Txid};
 use bitcoin_scripts::PubkeyScript;
 use bpro::{AddressSource, ElectrumServer, OnchainStatus, TxidMeta, UtxoTxid, WalletSettings};
 use chrono::{DateTime, NaiveDateTime, Utc};
-use electrum_client::{Client as ElectrumClient, ElectrumApi, HeaderNotification};
+use electrum_client::{GetHistoryRes, ListUnspentRes};
 use relm::Sender;
-use wallet::hd::{SegmentIndexes, UnhardenedIndex};
+use wallet::hd::SegmentIndexes;
+use wallet::onchain::PublicNetwork;
+
+use super::backend::{self, BlockTip, ChainBackend};
+use super::Socks5;
+use crate::model::{
+    script_pubkeys, validate_pow, wallet_descriptors, FeeEstimates, ScriptCache, Sidecar,
+    SyncCache, WalletPrefs, CHAIN_DEPTH, FEE_TARGETS,
+};
+
+/// Delay before the first reconnection attempt after all servers have failed;
+/// it doubles with each next failed attempt, up to 64 times the initial
+/// delay.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 enum Cmd {
     Sync,
     Pull,
+    Shutdown,
     Update(ElectrumServer),
+    Prefs(WalletPrefs),
+    /// Re-establishes connection with the current application settings.
+    Reconnect,
+}
+
+/// Progress of the wallet synchronization.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct SyncProgress {
+    /// Number of wallet scripts which history was checked.
+    pub scripts_scanned: usize,
+    /// Whether all scripts within the gap limit were checked, i.e. the total
+    /// number of transactions is known.
+    pub scan_complete: bool,
+    /// Number of transactions retrieved from the backend or the cache.
+    pub txs_fetched: usize,
+    /// Number of wallet transactions known so far.
+    pub txs_total: usize,
 }
 
 pub enum Msg {
     Connecting,
     Connected,
     Complete,
-    LastBlock(HeaderNotification),
-    LastBlockUpdate(HeaderNotification),
-    FeeEstimate(f64, f64, f64),
-    TxidBatch(BTreeMap<AddressSource, BTreeSet<TxidMeta>>, u16),
-    UtxoBatch(BTreeSet<UtxoTxid>, u16),
-    TxBatch(Vec<Transaction>, f32),
-    ChannelDisconnected,
-    Error(electrum_client::Error),
+    LastBlock(BlockTip),
+    LastBlockUpdate(BlockTip),
+    /// Chain reorganization replaced blocks starting from the given height;
+    /// the wallet is re-synchronized after this message.
+    Reorg(u32),
+    /// Fee estimations received during the synchronization.
+    FeeEstimate(FeeEstimates),
+    /// Fee estimations known from the last synchronization, sent when no
+    /// synchronization is running.
+    CachedFees(FeeEstimates),
+    TxidBatch(BTreeMap<AddressSource, BTreeSet<TxidMeta>>),
+    UtxoBatch(BTreeSet<UtxoTxid>),
+    TxBatch(Vec<Transaction>),
+    Progress(SyncProgress),
+    /// Synchronization was cancelled; data sent since the last
+    /// [`Msg::Connecting`] must be discarded.
+    Cancelled,
+    /// Server has notified about new activity on some of the wallet
+    /// addresses; the wallet is re-synchronized right after this message.
+    AddressActivity(Vec<Address>),
+    /// Connecting to the server; provides number of failed attempts to
+    /// connect to any of the servers.
+    ServerConnecting(String, u32),
+    /// Connection to the server is established after the provided number of
+    /// failed attempts.
+    ServerConnected(String, u32),
+    /// Confirmed transactions which inclusion into a block was not verified,
+    /// followed by the transactions for which the server has provided invalid
+    /// proofs. Sent before [`Msg::Complete`].
+    Verification(BTreeSet<Txid>, BTreeSet<Txid>),
+    /// Fees of the unconfirmed wallet transactions which fee is known. Sent
+    /// before [`Msg::Complete`].
+    MempoolFees(BTreeMap<Txid, u64>),
+    /// Synchronization was requested for the wallet in the offline mode;
+    /// provides time of the last completed synchronization, if any.
+    Offline(Option<DateTime<Utc>>),
+    Error(backend::Error),
 }
 
 pub struct ElectrumWorker {
-    worker_thread: JoinHandle<()>,
-    watcher_thread: JoinHandle<()>,
+    worker_thread: Option<JoinHandle<()>>,
+    watcher_thread: Option<JoinHandle<()>>,
+    watcher_stop: Option<mpsc::Sender<()>>,
     tx: mpsc::Sender<Cmd>,
+    cancel: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
 }
 
 impl ElectrumWorker {
     pub fn with(
         sender: Sender<Msg>,
         mut wallet_settings: WalletSettings,
-        interval: u64,
+        mut wallet_prefs: WalletPrefs,
+        wallet_path: PathBuf,
     ) -> Result<Self, io::Error> {
         let (tx, rx) = mpsc::channel::<Cmd>();
+        // Commands are processed only once the current synchronization is
+        // complete, so the cancellation is signalled with a shared flag
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+        // Commands queued before the shutdown are skipped
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        // Depends on the backend, which may be changed by the user
+        let interval = Arc::new(AtomicU64::new(wallet_prefs.backend.pull_interval()));
+        let pull_interval = interval.clone();
         let worker_thread = thread::Builder::new().name(s!("electrum")).spawn(move || {
-            let mut client = electrum_init(wallet_settings.electrum(), &sender);
+            let mut cache = SyncCache::read_for(&wallet_path).unwrap_or_default();
+            if !cache.fees.is_empty() {
+                let _ = sender.send(Msg::CachedFees(cache.fees.clone()));
+            }
+            let mut watched = bset! {};
+            // Wallets use separate Tor circuits when stream isolation is on
+            let isolation_key = wallet_path
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut connection = Connection::default();
+            connection.connect(&wallet_settings, &wallet_prefs, &isolation_key, &sender);
 
             loop {
-                let _ = match (&client, rx.recv()) {
-                    (Some(_), Ok(Cmd::Update(electrum))) => {
+                let cmd = rx.recv();
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let res = match cmd {
+                    Ok(Cmd::Update(electrum)) => {
                         wallet_settings.update_electrum(electrum);
-                        client = electrum_init(wallet_settings.electrum(), &sender);
+                        connection = Connection::default();
+                        connection.connect(
+                            &wallet_settings,
+                            &wallet_prefs,
+                            &isolation_key,
+                            &sender,
+                        );
                         Ok(())
                     }
-                    (Some(client), Ok(Cmd::Sync)) => {
-                        electrum_sync(&client, &wallet_settings, &sender)
-                    }
-                    (Some(client), Ok(Cmd::Pull)) => client.block_headers_pop().map(|res| {
-                        if let Some(last_block) = res {
-                            sender
-                                .send(Msg::LastBlockUpdate(last_block))
-                                .expect("electrum watcher channel is broken");
+                    Ok(Cmd::Prefs(prefs)) => {
+                        let reconnect = prefs.backend != wallet_prefs.backend
+                            || prefs.esplora_url != wallet_prefs.esplora_url
+                            || prefs.core != wallet_prefs.core
+                            || prefs.electrum_fallback != wallet_prefs.electrum_fallback
+                            || prefs.offline != wallet_prefs.offline;
+                        wallet_prefs = prefs;
+                        pull_interval.store(wallet_prefs.backend.pull_interval(), Ordering::SeqCst);
+                        if reconnect {
+                            connection = Connection::default();
+                            connection.connect(
+                                &wallet_settings,
+                                &wallet_prefs,
+                                &isolation_key,
+                                &sender,
+                            );
                         }
-                    }),
-                    (None, Ok(_)) => {
-                        /* Can't handle since no client available */
                         Ok(())
                     }
-                    (_, Err(_)) => {
-                        sender
-                            .send(Msg::ChannelDisconnected)
-                            .expect("electrum channel is broken");
+                    Ok(Cmd::Reconnect) => {
+                        connection = Connection::default();
+                        connection.connect(
+                            &wallet_settings,
+                            &wallet_prefs,
+                            &isolation_key,
+                            &sender,
+                        );
+                        Ok(())
+                    }
+                    Ok(cmd @ (Cmd::Sync | Cmd::Pull)) if wallet_prefs.offline => {
+                        if matches!(cmd, Cmd::Sync) {
+                            // Fee estimations are shown as they were known
+                            // during the last synchronization
+                            let _ = sender.send(Msg::CachedFees(cache.fees.clone()));
+                            let _ = sender.send(Msg::Offline(cache.synced_at));
+                        }
                         Ok(())
                     }
+                    Ok(cmd @ (Cmd::Sync | Cmd::Pull)) => {
+                        // Cancellation applies only to the synchronization
+                        // which was running when it was requested
+                        cancelled.store(false, Ordering::SeqCst);
+                        let mut full_sync = matches!(cmd, Cmd::Sync);
+                        if connection.backend.is_none() {
+                            // Automatic reconnection attempts are rate-limited,
+                            // while user-requested sync reconnects immediately
+                            if !full_sync && Instant::now() < connection.retry_at {
+                                continue;
+                            }
+                            // Subscriptions are lost with the connection
+                            full_sync = connection.connect(
+                                &wallet_settings,
+                                &wallet_prefs,
+                                &isolation_key,
+                                &sender,
+                            );
+                        }
+                        match connection.backend.as_deref_mut() {
+                            None => Ok(()),
+                            Some(backend) if full_sync => electrum_sync(
+                                backend,
+                                &wallet_settings,
+                                &wallet_prefs,
+                                &mut cache,
+                                &mut watched,
+                                &cancelled,
+                                &sender,
+                            )
+                            .and_then(|_| save_cache(&cache, &wallet_path, &stopped)),
+                            Some(backend) => electrum_pull(
+                                backend,
+                                &wallet_settings,
+                                &wallet_prefs,
+                                &mut cache,
+                                &mut watched,
+                                &cancelled,
+                                &sender,
+                            )
+                            .and_then(|synced| match synced {
+                                true => save_cache(&cache, &wallet_path, &stopped),
+                                false => Ok(()),
+                            }),
+                        }
+                    }
+                    Ok(Cmd::Shutdown) | Err(_) => break,
+                };
+                if let Err(err) = res {
+                    if err.is_connection_error() {
+                        connection.failover();
+                    }
+                    let msg = match err {
+                        backend::Error::Cancelled => Msg::Cancelled,
+                        err => Msg::Error(err),
+                    };
+                    // The wallet was closed
+                    if sender.send(msg).is_err() {
+                        break;
+                    }
                 }
-                .map_err(|err| {
-                    sender
-                        .send(Msg::Error(err))
-                        .expect("electrum channel is broken");
-                });
             }
         })?;
 
         let sender = tx.clone();
+        let (watcher_stop, watcher_stopped) = mpsc::channel::<()>();
         let watcher_thread = thread::Builder::new()
             .name(s!("blockwatcher"))
-            .spawn(move || loop {
-                thread::sleep(Duration::from_secs(interval));
-                sender.send(Cmd::Pull).expect("Electrum thread is dead")
+            .spawn(move || {
+                // Runs until the worker drops the stop channel or terminates
+                while watcher_stopped
+                    .recv_timeout(Duration::from_secs(interval.load(Ordering::SeqCst)))
+                    == Err(mpsc::RecvTimeoutError::Timeout)
+                {
+                    if sender.send(Cmd::Pull).is_err() {
+                        break;
+                    }
+                }
             })
             .expect("unable to start blockchain watching thread");
 
         Ok(ElectrumWorker {
             tx,
-            worker_thread,
-            watcher_thread,
+            worker_thread: Some(worker_thread),
+            watcher_thread: Some(watcher_thread),
+            watcher_stop: Some(watcher_stop),
+            cancel,
+            stop,
         })
     }
 
+    /// Stops all network activity. The worker threads are not waited for, so
+    /// the UI is not blocked by the network requests they may be running; the
+    /// threads terminate once the current request is complete and are joined
+    /// when the worker is dropped. Commands sent after the shutdown are
+    /// ignored.
+    pub fn shutdown(&mut self) {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.cancel.store(true, Ordering::SeqCst);
+        self.watcher_stop = None;
+        let _ = self.tx.send(Cmd::Shutdown);
+    }
+
     pub fn sync(&self) { self.cmd(Cmd::Sync) }
 
     pub fn pull(&self) { self.cmd(Cmd::Pull) }
 
+    /// Stops ongoing synchronization, if any. The data already retrieved are
+    /// kept in the cache and are not requested again by the next sync.
+    pub fn cancel(&self) { self.cancel.store(true, Ordering::SeqCst) }
+
     pub fn update(&self, server: ElectrumServer) { self.cmd(Cmd::Update(server)) }
 
-    fn cmd(&self, cmd: Cmd) { self.tx.send(cmd).expect("Electrum thread is dead") }
+    pub fn update_prefs(&self, prefs: WalletPrefs) { self.cmd(Cmd::Prefs(prefs)) }
+
+    /// Reconnects to the server, applying changes in the proxy settings.
+    pub fn reconnect(&self) { self.cmd(Cmd::Reconnect) }
+
+    fn cmd(&self, cmd: Cmd) {
+        if !self.stop.load(Ordering::SeqCst) {
+            self.tx.send(cmd).expect("Electrum thread is dead")
+        }
+    }
 }
 
-pub fn electrum_connect(url: &str) -> Result<ElectrumClient, electrum_client::Error> {
-    let config = electrum_client::ConfigBuilder::new()
-        .timeout(Some(5))
-        .build();
-    ElectrumClient::from_config(url, config)
+impl Drop for ElectrumWorker {
+    fn drop(&mut self) {
+        self.shutdown();
+        for thread in [self.watcher_thread.take(), self.worker_thread.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = thread.join();
+        }
+    }
 }
 
-fn electrum_init(electrum: &ElectrumServer, sender: &Sender<Msg>) -> Option<ElectrumClient> {
-    electrum_connect(&electrum.to_string())
-        .map_err(|err| {
-            sender
-                .send(Msg::Error(err))
-                .expect("electrum channel is broken");
-        })
-        .ok()
+/// Connection to the blockchain backend which is re-established with an
+/// exponential backoff and switches to the next server in the list on failures.
+struct Connection {
+    backend: Option<Box<dyn ChainBackend>>,
+    /// Index of the server which is tried first on the next connection attempt.
+    server_no: usize,
+    /// Number of connection attempts failed for all of the servers.
+    retries: u32,
+    /// Time before which no automatic reconnection attempts are made.
+    retry_at: Instant,
+    /// Proxy used by the last connection attempt.
+    proxy: Option<Socks5>,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Connection {
+            backend: None,
+            server_no: 0,
+            retries: 0,
+            retry_at: Instant::now(),
+            proxy: None,
+        }
+    }
+}
+
+impl Connection {
+    /// Tries to connect to each of the servers in turn, starting with the
+    /// current one. Returns whether the connection was established.
+    /// Wallets in the offline mode never connect.
+    fn connect(
+        &mut self,
+        wallet_settings: &WalletSettings,
+        wallet_prefs: &WalletPrefs,
+        isolation_key: &str,
+        sender: &Sender<Msg>,
+    ) -> bool {
+        if wallet_prefs.offline {
+            return false;
+        }
+        let network = wallet_settings.network();
+        // Proxy settings are re-read, so they apply on the next reconnection
+        match Socks5::configured(isolation_key) {
+            Ok(proxy) => self.proxy = proxy,
+            Err(err) => {
+                let _ = sender.send(Msg::Error(err.into()));
+                self.schedule_retry();
+                return false;
+            }
+        }
+        let servers = backend::servers(wallet_settings.electrum(), wallet_prefs, network);
+        for _ in 0..servers.len() {
+            let server = &servers[self.server_no % servers.len()];
+            let _ = sender.send(Msg::ServerConnecting(server.clone(), self.retries));
+            let res = backend::connect(
+                server,
+                wallet_prefs,
+                network,
+                isolation_key,
+                self.proxy.as_ref(),
+            )
+            .and_then(|mut backend| {
+                backend.watch_wallet(wallet_settings)?;
+                Ok(backend)
+            });
+            match res {
+                Ok(backend) => {
+                    let _ = sender.send(Msg::ServerConnected(server.clone(), self.retries));
+                    self.backend = Some(backend);
+                    self.retries = 0;
+                    return true;
+                }
+                Err(err) => {
+                    let _ = sender.send(Msg::Error(err));
+                    self.server_no = (self.server_no + 1) % servers.len();
+                }
+            }
+        }
+
+        self.schedule_retry();
+        false
+    }
+
+    /// Delays the next connection attempt, increasing the delay exponentially
+    /// with the number of failed attempts.
+    fn schedule_retry(&mut self) {
+        self.retries += 1;
+        self.retry_at = Instant::now() + RECONNECT_DELAY * 2u32.pow((self.retries - 1).min(6));
+    }
+
+    /// Drops broken connection; the next connection attempt starts with the
+    /// next server.
+    fn failover(&mut self) {
+        self.backend = None;
+        self.server_no += 1;
+        self.retry_at = Instant::now();
+    }
+}
+
+/// Saves the cache unless the worker was shut down: the wallet may be already
+/// reopened, and its new worker uses the same cache file.
+fn save_cache(
+    cache: &SyncCache,
+    wallet_path: &Path,
+    stopped: &AtomicBool,
+) -> Result<(), backend::Error> {
+    if stopped.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    cache.write_for(wallet_path).map_err(backend::Error::from)
+}
+
+/// Processes notifications received from the server since the last call.
+///
+/// Returns whether the wallet was re-synchronized due to a change in the status
+/// of some of its scripts.
+fn electrum_pull(
+    backend: &mut dyn ChainBackend,
+    wallet_settings: &WalletSettings,
+    wallet_prefs: &WalletPrefs,
+    cache: &mut SyncCache,
+    watched: &mut BTreeSet<Script>,
+    cancel: &AtomicBool,
+    sender: &Sender<Msg>,
+) -> Result<bool, backend::Error> {
+    check_cancelled(cancel)?;
+    backend.ping()?;
+
+    if let Some(last_block) = backend.tip_update()? {
+        // On reorganization affected scripts are marked as stale, so the wallet
+        // gets re-synchronized below
+        update_chain(
+            backend,
+            cache,
+            &last_block,
+            wallet_settings.network(),
+            sender,
+        )?;
+        notify(sender, Msg::LastBlockUpdate(last_block))?;
+    }
+
+    check_cancelled(cancel)?;
+    let scripts = watched.iter().collect::<Vec<_>>();
+    let changes = script_changes(backend, &scripts, cache)?;
+    if changes.is_empty() {
+        return Ok(false);
+    }
+
+    let network = bitcoin::Network::from(wallet_settings.network());
+    let addresses = changes
+        .iter()
+        .filter_map(|(script, _)| Address::from_script(script, network).ok())
+        .collect();
+    notify(sender, Msg::AddressActivity(addresses))?;
+
+    electrum_sync(
+        backend,
+        wallet_settings,
+        wallet_prefs,
+        cache,
+        watched,
+        cancel,
+        sender,
+    )?;
+    Ok(true)
 }
 
 fn electrum_sync(
-    client: &ElectrumClient,
+    backend: &mut dyn ChainBackend,
     wallet_settings: &WalletSettings,
+    wallet_prefs: &WalletPrefs,
+    cache: &mut SyncCache,
+    watched: &mut BTreeSet<Script>,
+    cancel: &AtomicBool,
     sender: &Sender<Msg>,
-) -> Result<(), electrum_client::Error> {
-    sender
-        .send(Msg::Connecting)
-        .expect("electrum watcher channel is broken");
+) -> Result<(), backend::Error> {
+    notify(sender, Msg::Connecting)?;
 
-    sender
-        .send(Msg::Connected)
-        .expect("electrum watcher channel is broken");
+    notify(sender, Msg::Connected)?;
 
-    let last_block = client.block_headers_subscribe()?;
-    sender
-        .send(Msg::LastBlock(last_block))
-        .expect("electrum watcher channel is broken");
+    let last_block = backend.tip()?;
+    update_chain(
+        backend,
+        cache,
+        &last_block,
+        wallet_settings.network(),
+        sender,
+    )?;
+    notify(sender, Msg::LastBlock(last_block))?;
 
-    let fee = client.batch_estimate_fee([1, 2, 3])?;
-    sender
-        .send(Msg::FeeEstimate(fee[0], fee[1], fee[2]))
-        .expect("electrum watcher channel is broken");
+    let estimates = backend.fee_estimates(&FEE_TARGETS)?;
+    // Fee histogram is optional for fee estimation, and servers may not
+    // provide it
+    let histogram = backend.fee_histogram().unwrap_or_default();
+    cache.fees = FeeEstimates::with(&FEE_TARGETS, estimates, histogram);
+    notify(sender, Msg::FeeEstimate(cache.fees.clone()))?;
 
     let network = bitcoin::Network::from(wallet_settings.network());
 
+    // Scanning stops once we see `gap_limit` consecutive unused addresses
+    let gap = wallet_prefs.gap_limit.max(1);
     let mut txids = bset![];
-    let mut block_heights = bset![];
-    let mut headers = bmap![];
-    let mut upto_index = map! { true => UnhardenedIndex::zero(), false => UnhardenedIndex::zero() };
-    for change in [false, true] {
+    let mut progress = SyncProgress::default();
+    // Multi-class wallets have a separate set of addresses for each class
+    let descriptors = wallet_descriptors(wallet_settings)
+        .map_err(|err| backend::Error::Message(err.to_string()))?;
+    let terminals = descriptors
+        .iter()
+        .flat_map(|descriptor| [(descriptor, false), (descriptor, true)]);
+    for (descriptor, change) in terminals {
         let mut offset = 0u16;
-        let mut upto = UnhardenedIndex::zero();
-        *upto_index.entry(change).or_default() = loop {
-            let spk = wallet_settings
-                .script_pubkeys(change, offset..=(offset + 19))
-                .map_err(|err| electrum_client::Error::Message(err.to_string()))?;
-            let batch =
-                client.batch_script_get_history(spk.values().map(PubkeyScript::as_inner))?;
+        let mut last_used: Option<u16> = None;
+        loop {
+            check_cancelled(cancel)?;
+            let spk = script_pubkeys(descriptor, change, offset..=offset.saturating_add(gap - 1))
+                .map_err(|err| backend::Error::Message(err.to_string()))?;
+
+            // Query history only for the scripts which status has changed
+            let scripts = spk.values().map(PubkeyScript::as_inner).collect::<Vec<_>>();
+            watched.extend(scripts.iter().copied().cloned());
+            let changes = script_changes(backend, &scripts, cache)?;
+            if !changes.is_empty() {
+                let scripts = changes.iter().map(|(script, _)| script).collect::<Vec<_>>();
+                let history = backend.history(&scripts)?;
+                let unspent = backend.unspent(&scripts)?;
+                for (((script, status), history), unspent) in
+                    changes.into_iter().zip(history).zip(unspent)
+                {
+                    cache.scripts.insert(script, ScriptCache {
+                        status,
+                        history,
+                        unspent,
+                    });
+                }
+            }
 
             // Retrieve unknown headers
-            let heights = batch
-                .iter()
-                .flatten()
-                .map(|res| res.height as u32)
-                .collect::<BTreeSet<_>>();
-            let diff = heights
-                .difference(&block_heights)
-                .copied()
+            let heights = spk
+                .values()
+                .filter_map(|script| cache.scripts.get(script.as_inner()))
+                .flat_map(|script_cache| {
+                    script_cache
+                        .history
+                        .iter()
+                        .map(|item| item.height)
+                        .chain(script_cache.unspent.iter().map(|item| item.height as i32))
+                })
+                .filter(|height| *height > 0)
+                .map(|height| height as u32)
+                .filter(|height| !cache.headers.contains_key(height))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
                 .collect::<Vec<_>>();
-            let new_headers = client.batch_block_header(&diff)?;
-            headers.extend(diff.iter().copied().zip(new_headers));
-            block_heights.extend(diff);
+            if !heights.is_empty() {
+                let network = wallet_settings.network();
+                // Headers older than the chain are checked against the
+                // difficulty of their periods, so they can't be forged cheaply
+                let boundaries = match network {
+                    PublicNetwork::Mainnet => cache.chain.missing_boundaries(&heights),
+                    _ => vec![],
+                };
+                if !boundaries.is_empty() {
+                    let boundary_headers = backend.headers(&boundaries)?;
+                    let boundaries = boundaries.into_iter().zip(boundary_headers).collect();
+                    cache.chain.verify_periods(&boundaries, network)?;
+                }
+                let new_headers = backend.headers(&heights)?;
+                for (height, header) in heights.iter().zip(&new_headers) {
+                    validate_pow(header, *height, network)?;
+                    cache.chain.check(*height, header)?;
+                    cache.chain.check_bits(*height, header, network)?;
+                }
+                cache.headers.extend(heights.into_iter().zip(new_headers));
+            }
 
-            let batch = batch
-                .into_iter()
-                .zip(&spk)
-                .map(|(history, (index, script))| {
-                    let addr_src = AddressSource::with(script, *index, change, network);
-                    let txids = history
-                        .into_iter()
-                        .map(|item| {
-                            let mut meta = TxidMeta::from(item);
-                            if let OnchainStatus::Blockchain(height) = meta.onchain.status {
-                                meta.onchain.date_time = headers
-                                    .get(&height)
-                                    .and_then(|header| {
-                                        NaiveDateTime::from_timestamp_opt(header.time as i64, 0)
-                                    })
-                                    .map(|naive| {
-                                        DateTime::<Utc>::from_naive_utc_and_offset(naive, Utc)
-                                    });
-                            }
-                            meta
-                        })
-                        .collect::<BTreeSet<_>>();
-                    (addr_src, txids)
-                })
-                .collect::<BTreeMap<_, _>>();
+            let mut batch = bmap! {};
+            let mut utxos = bset! {};
+            for (index, script) in &spk {
+                let script_cache = cache
+                    .scripts
+                    .get(script.as_inner())
+                    .cloned()
+                    .unwrap_or_default();
+                let history = script_cache
+                    .history
+                    .iter()
+                    .map(|item| {
+                        let mut meta = TxidMeta::from(GetHistoryRes::from(*item));
+                        if let OnchainStatus::Blockchain(height) = meta.onchain.status {
+                            meta.onchain.date_time = block_time(&cache.headers, height);
+                        }
+                        meta
+                    })
+                    .collect::<BTreeSet<_>>();
+                batch.insert(
+                    AddressSource::with(script, *index, change, network),
+                    history,
+                );
+                utxos.extend(script_cache.unspent.iter().map(|item| {
+                    let mut utxo = UtxoTxid::with(
+                        ListUnspentRes::from(*item),
+                        AddressSource::with(script, *index, change, network),
+                    );
+                    if let OnchainStatus::Blockchain(height) = utxo.onchain.status {
+                        utxo.onchain.date_time = block_time(&cache.headers, height);
+                    }
+                    utxo
+                }));
+            }
 
             let new_txids = batch
                 .values()
                 .flat_map(|item| item.iter().map(|meta| meta.onchain.txid))
                 .collect::<Vec<_>>();
-            if new_txids.is_empty() {
-                break upto;
-            } else {
-                upto = batch
-                    .keys()
-                    .map(|item| item.index)
+            if !new_txids.is_empty() {
+                last_used = batch
+                    .iter()
+                    .filter(|(_, history)| !history.is_empty())
+                    .map(|(source, _)| source.index.first_index() as u16)
                     .max()
-                    .unwrap_or_default();
+                    .max(last_used);
+
+                txids.extend(new_txids);
+                notify(sender, Msg::TxidBatch(batch))?;
+
+                txids.extend(utxos.iter().map(|item| item.onchain.txid));
+                notify(sender, Msg::UtxoBatch(utxos))?;
             }
-            txids.extend(new_txids);
-            sender
-                .send(Msg::TxidBatch(batch, offset))
-                .expect("electrum watcher channel is broken");
 
-            // Get transactions
-            let utxos =
-                client.batch_script_list_unspent(spk.values().map(PubkeyScript::as_inner))?;
+            progress.scripts_scanned += spk.len();
+            progress.txs_total = txids.len();
+            notify(sender, Msg::Progress(progress))?;
 
-            // Retrieve unknown headers
-            let heights = utxos
-                .iter()
-                .flatten()
-                .map(|res| res.height as u32)
-                .collect::<BTreeSet<_>>();
-            let diff = heights
-                .difference(&block_heights)
-                .copied()
-                .collect::<Vec<_>>();
-            let new_headers = client.batch_block_header(&diff)?;
-            headers.extend(diff.iter().copied().zip(new_headers));
-            block_heights.extend(diff);
+            offset = match offset.checked_add(gap) {
+                Some(offset) => offset,
+                None => break,
+            };
+            match last_used {
+                Some(last_used) if last_used.saturating_add(gap) >= offset => {}
+                _ => break,
+            }
+        }
+    }
 
-            // Construct UTXO information
-            let utxos = utxos
-                .into_iter()
-                .zip(spk)
-                .flat_map(|(utxo, (index, script))| {
-                    utxo.into_iter()
-                        .map(move |res| {
-                            UtxoTxid::with(
-                                res,
-                                AddressSource::with(&script, index, change, network),
-                            )
-                        })
-                        .map(|mut utxo| {
-                            if let OnchainStatus::Blockchain(height) = utxo.onchain.status {
-                                utxo.onchain.date_time = headers
-                                    .get(&height)
-                                    .and_then(|header| {
-                                        NaiveDateTime::from_timestamp_opt(header.time as i64, 0)
-                                    })
-                                    .map(|naive| {
-                                        DateTime::<Utc>::from_naive_utc_and_offset(naive, Utc)
-                                    });
-                            }
-                            utxo
-                        })
-                })
-                .collect::<BTreeSet<_>>();
-            txids.extend(utxos.iter().map(|item| item.onchain.txid));
-            sender
-                .send(Msg::UtxoBatch(utxos, offset))
-                .expect("electrum watcher channel is broken");
+    // Get transactions which were not retrieved before
+    let (known, missing): (Vec<_>, Vec<_>) = txids
+        .into_iter()
+        .partition(|txid| cache.transactions.contains_key(txid));
+    let tx_list = known
+        .iter()
+        .filter_map(|txid| cache.transactions.get(txid))
+        .cloned()
+        .collect::<Vec<_>>();
+    progress.scan_complete = true;
+    progress.txs_fetched = tx_list.len();
+    notify(sender, Msg::TxBatch(tx_list))?;
+    notify(sender, Msg::Progress(progress))?;
+    for chunk in missing.chunks(20) {
+        check_cancelled(cancel)?;
+        let tx_list = backend.transactions(chunk)?;
+        cache
+            .transactions
+            .extend(tx_list.iter().map(|tx| (tx.txid(), tx.clone())));
+        progress.txs_fetched += tx_list.len();
+        notify(sender, Msg::TxBatch(tx_list))?;
+        notify(sender, Msg::Progress(progress))?;
+    }
+    cache.prune_transactions();
 
-            offset += 20;
-        };
+    check_cancelled(cancel)?;
+    compute_fees(backend, cache)?;
+    notify(sender, Msg::MempoolFees(cache.mempool_fees()))?;
+
+    // Verifying inclusion of confirmed transactions into the blocks which
+    // headers we have, instead of trusting the server
+    let confirmed = cache.confirmed_txids();
+    let pending = confirmed
+        .iter()
+        .filter(|(txid, height)| {
+            cache.headers.contains_key(height) && !cache.is_verified(*txid, *height)
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    let mut invalid = bset! {};
+    if !pending.is_empty() {
+        check_cancelled(cancel)?;
+        let proofs = backend.merkle_proofs(&pending)?;
+        for ((txid, height), proof) in pending.into_iter().zip(proofs) {
+            let header = cache.headers[&height];
+            if proof.verify(txid, &header) {
+                cache.proofs.insert(txid, header.block_hash());
+            } else {
+                invalid.insert(txid);
+            }
+        }
     }
+    let unverified = confirmed
+        .into_iter()
+        .filter(|(txid, height)| !cache.is_verified(*txid, *height))
+        .map(|(txid, _)| txid)
+        .collect();
+    notify(sender, Msg::Verification(unverified, invalid))?;
+
+    cache.synced_at = Some(Utc::now());
+    notify(sender, Msg::Complete)?;
+
+    Ok(())
+}
 
-    let txids = txids.into_iter().collect::<Vec<_>>();
-    for (no, chunk) in txids.chunks(20).enumerate() {
-        let tx_list = client.batch_transaction_get(chunk)?;
-        let progress = (no + 1) as f32 / txids.len() as f32 / 20.0;
-        sender
-            .send(Msg::TxBatch(tx_list, progress))
-            .expect("electrum watcher channel is broken");
+/// Computes fees of the unconfirmed wallet transactions which were not
+/// reported by the server from the values of the spent outputs, retrieving the
+/// transactions which created them. Fees remain unknown if these transactions
+/// can't be retrieved, which happens with Bitcoin Core without the transaction
+/// index.
+fn compute_fees(
+    backend: &mut dyn ChainBackend,
+    cache: &mut SyncCache,
+) -> Result<(), backend::Error> {
+    let txs = cache
+        .unknown_fee_txids()
+        .into_iter()
+        .filter_map(|txid| cache.transactions.get(&txid).cloned())
+        .collect::<Vec<_>>();
+    let missing = txs
+        .iter()
+        .flat_map(|tx| tx.input.iter().map(|txin| txin.previous_output.txid))
+        .filter(|txid| !cache.transactions.contains_key(txid))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut prev_txs = BTreeMap::new();
+    for chunk in missing.chunks(20) {
+        match backend.transactions(chunk) {
+            Ok(list) => prev_txs.extend(list.into_iter().map(|tx| (tx.txid(), tx))),
+            Err(err) if err.is_connection_error() => return Err(err),
+            Err(_) => {}
+        }
     }
+    for tx in txs {
+        let input_value = tx
+            .input
+            .iter()
+            .map(|txin| {
+                let prev = txin.previous_output;
+                cache
+                    .transactions
+                    .get(&prev.txid)
+                    .or_else(|| prev_txs.get(&prev.txid))
+                    .and_then(|prev_tx| prev_tx.output.get(prev.vout as usize))
+                    .map(|txout| txout.value)
+            })
+            .sum::<Option<u64>>();
+        let output_value = tx.output.iter().map(|txout| txout.value).sum::<u64>();
+        if let Some(fee) = input_value.and_then(|value| value.checked_sub(output_value)) {
+            cache.set_fee(tx.txid(), fee);
+        }
+    }
+    Ok(())
+}
 
-    sender
-        .send(Msg::Complete)
-        .expect("electrum watcher channel is broken");
+/// Verifies the new blockchain tip and the headers leading to it, extending
+/// the header chain kept in the cache. On chain reorganization reverts the
+/// data which were confirmed in the replaced blocks.
+fn update_chain(
+    backend: &mut dyn ChainBackend,
+    cache: &mut SyncCache,
+    tip: &BlockTip,
+    network: PublicNetwork,
+    sender: &Sender<Msg>,
+) -> Result<(), backend::Error> {
+    let tip_height = tip.height;
+    let chain = &mut cache.chain;
+    if chain.tip_height() == Some(tip_height) && chain.check(tip_height, &tip.header).is_ok() {
+        return Ok(());
+    }
+
+    let mut start = match chain.tip_height() {
+        Some(height) if height >= tip_height => tip_height,
+        Some(height) if tip_height - height <= CHAIN_DEPTH => height + 1,
+        // The chain is empty or too old, so we start it over
+        _ => tip_height.saturating_sub(CHAIN_DEPTH - 1),
+    };
+    let heights = (start..tip_height).collect::<Vec<_>>();
+    let mut headers = match heights.is_empty() {
+        true => vec![],
+        false => backend.headers(&heights)?,
+    };
+    headers.push(tip.header);
+    // Looking for the fork point, if the new headers do not connect to the
+    // chain tip
+    while chain.connects(start, &headers[0]) == Some(false) {
+        start -= 1;
+        headers.insert(0, backend.headers(&[start])?.remove(0));
+    }
+    // If the new headers do not connect to the chain, it is started over; but
+    // before that we check whether the old chain was reorganized, so the data
+    // confirmed in its blocks are not kept unverified
+    let mut fork = None;
+    if !chain.is_empty() && chain.connects(start, &headers[0]).is_none() {
+        let heights = chain.heights();
+        let current = backend.headers(&heights)?;
+        fork = chain.fork_height(heights.into_iter().zip(current));
+    }
 
+    let replaced = chain.extend(start, headers, network)?;
+    if let Some(height) = replaced.into_iter().chain(fork).min() {
+        cache.revert(height);
+        notify(sender, Msg::Reorg(height))?;
+    }
     Ok(())
 }
+
+/// Sends the message to the wallet. Fails if the wallet was closed, which stops
+/// the synchronization.
+fn notify(sender: &Sender<Msg>, msg: Msg) -> Result<(), backend::Error> {
+    sender.send(msg).map_err(|_| backend::Error::Cancelled)
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> Result<(), backend::Error> {
+    match cancel.load(Ordering::SeqCst) {
+        true => Err(backend::Error::Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Scripts together with their new Electrum-style status.
+type ScriptChanges = Vec<(Script, Option<[u8; 32]>)>;
+
+/// Detects scripts which status has changed since the last synchronization.
+fn script_changes(
+    backend: &mut dyn ChainBackend,
+    scripts: &[&Script],
+    cache: &SyncCache,
+) -> Result<ScriptChanges, backend::Error> {
+    let statuses = backend.script_statuses(scripts)?;
+    Ok(scripts
+        .iter()
+        .zip(statuses)
+        .filter_map(|(script, status)| {
+            let cached = cache
+                .scripts
+                .get(*script)
+                .and_then(|script_cache| script_cache.status);
+            (status != cached).then(|| ((*script).clone(), status))
+        })
+        .collect())
+}
+
+fn block_time(headers: &BTreeMap<u32, BlockHeader>, height: u32) -> Option<DateTime<Utc>> {
+    headers
+        .get(&height)
+        .and_then(|header| NaiveDateTime::from_timestamp_opt(header.time as i64, 0))
+        .map(|naive| DateTime::<Utc>::from_naive_utc_and_offset(naive, Utc))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::util::bip32::ExtendedPubKey;
+    use bitcoin::{OutPoint, Sequence, TxIn, TxOut};
+    use bpro::{ElectrumSec, Signer, SigsReq, SpendingCondition, TimelockReq, TimelockedSigs};
+    use gtk::glib;
+    use relm::Channel;
+    use wallet::descriptors::DescriptorClass;
+    use wallet::hd::{TerminalStep, UnhardenedIndex};
+
+    use super::*;
+    use crate::model::{derive_script, HistoryItem, UnspentItem};
+    use crate::worker::backend::fake::FakeBackend;
+
+    /// Headers of the first mainnet blocks.
+    const HEADERS: [&str; 3] = [
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27a\
+         c72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bb\
+         be680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a\
+         5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+    ];
+
+    /// Coinbase transaction of the mainnet block 1, which is the only
+    /// transaction in that block.
+    const COINBASE_1: &str = "01000000010000000000000000000000000000000000000000000000000000000000\
+                              000000ffffffff0704ffff001d0104ffffffff0100f2052a0100000043410496b538\
+                              e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da75893795\
+                              15d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000";
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoC\
+                        u1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    fn wallet_settings() -> WalletSettings {
+        let class = DescriptorClass::SegwitV0;
+        let xpub = ExtendedPubKey::from_str(XPUB).unwrap();
+        let signer = Signer::with_xpub(xpub, &class.bip43(1), PublicNetwork::Mainnet);
+        let condition = SpendingCondition::Sigs(TimelockedSigs {
+            sigs: SigsReq::All,
+            timelock: TimelockReq::Anytime,
+        });
+        WalletSettings::with_unchecked(
+            vec![signer],
+            vec![(1, condition)],
+            bset![class],
+            [TerminalStep::range(0u8, 1u8), TerminalStep::Wildcard]
+                .into_iter()
+                .collect(),
+            PublicNetwork::Mainnet,
+            ElectrumServer {
+                sec: ElectrumSec::Tls,
+                server: s!("localhost"),
+                port: 50002,
+            },
+        )
+        .unwrap()
+    }
+
+    fn script(settings: &WalletSettings, change: bool, index: u16) -> Script {
+        let descriptor = &wallet_descriptors(settings).unwrap()[0];
+        derive_script(descriptor, change, UnhardenedIndex::from(index))
+            .unwrap()
+            .into_inner()
+    }
+
+    fn coinbase() -> Transaction { deserialize(&Vec::<u8>::from_hex(COINBASE_1).unwrap()).unwrap() }
+
+    /// Backend with three mainnet blocks, where the wallet receives coinbase
+    /// of the block 1 on the first receiving address.
+    fn backend(settings: &WalletSettings) -> FakeBackend {
+        let mut backend = FakeBackend::default();
+        for (height, header) in HEADERS.iter().enumerate() {
+            let header = deserialize(&Vec::<u8>::from_hex(header).unwrap()).unwrap();
+            backend.headers.insert(height as u32, header);
+        }
+        let tx = coinbase();
+        let script = script(settings, false, 0);
+        backend.history.insert(script.clone(), vec![HistoryItem {
+            txid: tx.txid(),
+            height: 1,
+            fee: None,
+        }]);
+        backend.unspent.insert(script, vec![UnspentItem {
+            txid: tx.txid(),
+            vout: 0,
+            value: tx.output[0].value,
+            height: 1,
+        }]);
+        backend.transactions.insert(tx.txid(), tx);
+        backend
+    }
+
+    /// Runs synchronization collecting all the messages it sends.
+    fn sync(
+        backend: &mut FakeBackend,
+        settings: &WalletSettings,
+        prefs: &WalletPrefs,
+        cache: &mut SyncCache,
+        cancel: bool,
+    ) -> (Result<(), backend::Error>, Vec<Msg>) {
+        let context = glib::MainContext::new();
+        context
+            .with_thread_default(|| {
+                let messages = Rc::new(RefCell::new(vec![]));
+                let received = messages.clone();
+                let (_channel, sender) = Channel::new(move |msg| received.borrow_mut().push(msg));
+                let res = electrum_sync(
+                    backend,
+                    settings,
+                    prefs,
+                    cache,
+                    &mut bset! {},
+                    &AtomicBool::new(cancel),
+                    &sender,
+                );
+                while context.iteration(false) {}
+                let messages = messages.replace(vec![]);
+                (res, messages)
+            })
+            .expect("main context is acquired by other thread")
+    }
+
+    #[test]
+    fn sync_reports_wallet_data() {
+        let settings = wallet_settings();
+        let mut backend = backend(&settings);
+        let mut cache = SyncCache::default();
+        let (res, messages) = sync(
+            &mut backend,
+            &settings,
+            &WalletPrefs::default(),
+            &mut cache,
+            false,
+        );
+        res.unwrap();
+
+        let txid = coinbase().txid();
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, Msg::LastBlock(tip) if tip.height == 2)));
+        assert!(messages.iter().any(|msg| matches!(
+            msg,
+            Msg::TxidBatch(batch) if batch.iter().any(|(source, history)| {
+                source.index.first_index() == 0
+                    && source.change.first_index() == 0
+                    && history.iter().any(|meta| meta.onchain.txid == txid)
+            })
+        )));
+        assert!(messages.iter().any(|msg| matches!(
+            msg,
+            Msg::UtxoBatch(utxos) if utxos.iter().any(|utxo| utxo.onchain.txid == txid)
+        )));
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, Msg::TxBatch(txs) if txs.iter().any(|tx| tx.txid() == txid))));
+        assert!(messages.iter().any(|msg| matches!(
+            msg,
+            Msg::Verification(unverified, invalid) if unverified.is_empty() && invalid.is_empty()
+        )));
+        assert!(matches!(messages.last(), Some(Msg::Complete)));
+
+        assert!(cache.transactions.contains_key(&txid));
+        assert!(cache.is_verified(txid, 1));
+        assert!(cache.synced_at.is_some());
+    }
+
+    #[test]
+    fn resync_queries_changed_scripts_only() {
+        let settings = wallet_settings();
+        let mut backend = backend(&settings);
+        let mut cache = SyncCache::default();
+        let prefs = WalletPrefs::default();
+        sync(&mut backend, &settings, &prefs, &mut cache, false)
+            .0
+            .unwrap();
+        assert_eq!(backend.history_requests, vec![script(&settings, false, 0)]);
+        assert_eq!(backend.tx_requests, vec![coinbase().txid()]);
+
+        // Nothing has changed, so all data come from the cache
+        backend.history_requests.clear();
+        backend.tx_requests.clear();
+        let (res, messages) = sync(&mut backend, &settings, &prefs, &mut cache, false);
+        res.unwrap();
+        assert!(backend.history_requests.is_empty());
+        assert!(backend.tx_requests.is_empty());
+        assert!(messages.iter().any(
+            |msg| matches!(msg, Msg::TxBatch(txs) if txs.iter().any(|tx| tx.txid() == coinbase().txid()))
+        ));
+
+        // New transaction on a change address
+        let change = script(&settings, true, 0);
+        backend.history.insert(change.clone(), vec![HistoryItem {
+            txid: coinbase().txid(),
+            height: 1,
+            fee: None,
+        }]);
+        sync(&mut backend, &settings, &prefs, &mut cache, false)
+            .0
+            .unwrap();
+        assert_eq!(backend.history_requests, vec![change]);
+    }
+
+    #[test]
+    fn sync_scans_within_gap_limit() {
+        let settings = wallet_settings();
+        let mut backend = backend(&settings);
+        let tx = coinbase();
+        backend
+            .history
+            .insert(script(&settings, false, 7), vec![HistoryItem {
+                txid: tx.txid(),
+                height: 1,
+                fee: None,
+            }]);
+        let prefs = WalletPrefs {
+            gap_limit: 5,
+            ..default!()
+        };
+        let (res, messages) = sync(
+            &mut backend,
+            &settings,
+            &prefs,
+            &mut SyncCache::default(),
+            false,
+        );
+        res.unwrap();
+
+        // Receiving addresses 0..15 (address 7 is used, so the scan continues
+        // for the next 5 addresses) and change addresses 0..5
+        let scanned = messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::Progress(progress) => Some(progress.scripts_scanned),
+                _ => None,
+            })
+            .max();
+        assert_eq!(scanned, Some(20));
+    }
+
+    #[test]
+    fn sync_computes_unreported_mempool_fees() {
+        let settings = wallet_settings();
+        let mut backend = backend(&settings);
+        backend.history.clear();
+        backend.unspent.clear();
+        let coinbase = coinbase();
+        let tx = Transaction {
+            version: 2,
+            lock_time: bitcoin::PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                script_sig: none!(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: none!(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: script(&settings, false, 0),
+                value: coinbase.output[0].value - 1000,
+            }],
+        };
+        let txid = tx.txid();
+        backend
+            .history
+            .insert(script(&settings, false, 0), vec![HistoryItem {
+                txid,
+                height: 0,
+                fee: None,
+            }]);
+        backend.transactions.insert(txid, tx);
+
+        let mut cache = SyncCache::default();
+        let (res, messages) = sync(
+            &mut backend,
+            &settings,
+            &WalletPrefs::default(),
+            &mut cache,
+            false,
+        );
+        res.unwrap();
+
+        // The spent transaction is not a wallet one, so it is requested only
+        // for computing the fee
+        assert_eq!(backend.tx_requests, vec![txid, coinbase.txid()]);
+        assert!(!cache.transactions.contains_key(&coinbase.txid()));
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, Msg::MempoolFees(fees) if fees.get(&txid) == Some(&1000))));
+        assert_eq!(cache.mempool_fees().get(&txid), Some(&1000));
+    }
+
+    #[test]
+    fn cancelled_sync() {
+        let settings = wallet_settings();
+        let mut backend = backend(&settings);
+        let mut cache = SyncCache::default();
+        let (res, messages) = sync(
+            &mut backend,
+            &settings,
+            &WalletPrefs::default(),
+            &mut cache,
+            true,
+        );
+        assert!(matches!(res, Err(backend::Error::Cancelled)));
+        assert!(!messages.iter().any(|msg| matches!(msg, Msg::Complete)));
+        assert!(backend.history_requests.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::{fs, io};
+
+use chrono::{DateTime, Duration, Utc};
+use gtk::glib;
+use once_cell::sync::Lazy;
+use serde_crate::{Deserialize, Serialize};
+
+use super::Quotes;
+use crate::model::sidecar::{self, Error};
+use crate::model::{Fiat, RateSource};
+
+/// Exchange rate retrieved from the rate source.
+#[derive(Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct Rate {
+    pub fiat: Fiat,
+    pub source: RateSource,
+    pub rate: f64,
+    pub quotes: Quotes,
+    /// Time when the rate was retrieved.
+    pub time: DateTime<Utc>,
+}
+
+impl Rate {
+    /// Checks whether the rate was retrieved more than `period` minutes ago.
+    pub fn is_stale(&self, period: u32) -> bool {
+        Utc::now().signed_duration_since(self.time) > Duration::minutes(period as i64)
+    }
+}
+
+/// Serializes cache updates by the exchange workers of different wallets.
+///
+/// The lock works only within the application process. Another running
+/// instance of the application may update the cache at the same time; since
+/// the cache file is replaced atomically, this may only lose one of the rates,
+/// which is retrieved again by the next refresh, but never corrupts the file.
+static UPDATE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Last known exchange rates for each fiat currency and rate source, which are
+/// cached on disk and shared by all wallets.
+#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct RateCache {
+    pub rates: Vec<Rate>,
+}
+
+impl RateCache {
+    pub fn path() -> PathBuf { glib::user_cache_dir().join("mycitadel").join("rates.json") }
+
+    /// Reads cached rates; returns empty cache if there is no cache file.
+    pub fn read() -> Result<Self, Error> { Self::read_from(&Self::path()) }
+
+    fn read_from(path: &Path) -> Result<Self, Error> {
+        match fs::File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self) -> Result<(), Error> { sidecar::write_atomic(&Self::path(), self) }
+
+    /// Adds the rate to the cache on disk. The cache is re-read before the
+    /// update, since it is shared by all wallets; if it can't be read, it is
+    /// left intact.
+    pub fn update(rate: Rate) -> Result<(), Error> { Self::update_at(&Self::path(), rate) }
+
+    fn update_at(path: &Path, rate: Rate) -> Result<(), Error> {
+        let _lock = UPDATE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut cache = Self::read_from(path)?;
+        cache.insert(rate);
+        sidecar::write_atomic(path, &cache)
+    }
+
+    pub fn get(&self, fiat: Fiat, source: &RateSource) -> Option<&Rate> {
+        self.rates
+            .iter()
+            .find(|rate| rate.fiat == fiat && &rate.source == source)
+    }
+
+    /// Adds the rate to the cache, replacing previously known rate for the same
+    /// fiat currency and rate source.
+    pub fn insert(&mut self, rate: Rate) {
+        self.rates
+            .retain(|known| known.fiat != rate.fiat || known.source != rate.source);
+        self.rates.push(rate);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process;
+
+    use super::*;
+    use crate::model::Exchange;
+
+    fn rate(fiat: Fiat, exchange: Exchange, rate: f64, minutes_ago: i64) -> Rate {
+        Rate {
+            fiat,
+            source: RateSource::Exchange(exchange),
+            rate,
+            quotes: default!(),
+            time: Utc::now() - Duration::minutes(minutes_ago),
+        }
+    }
+
+    fn cache_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mycitadel-{}-{name}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn stale() {
+        let rate = rate(Fiat::USD, Exchange::Kraken, 25000.0, 30);
+        assert!(rate.is_stale(10));
+        assert!(!rate.is_stale(60));
+    }
+
+    #[test]
+    fn update() {
+        let path = cache_path("update");
+        let usd = rate(Fiat::USD, Exchange::Kraken, 25000.0, 30);
+        RateCache::update_at(&path, usd.clone()).unwrap();
+        // Another wallet updates the cache in between
+        let mut cache = RateCache::read_from(&path).unwrap();
+        let eur = rate(Fiat::EUR, Exchange::Kraken, 23000.0, 20);
+        cache.insert(eur.clone());
+        sidecar::write_atomic(&path, &cache).unwrap();
+
+        let fresh = rate(Fiat::USD, Exchange::Kraken, 26000.0, 0);
+        RateCache::update_at(&path, fresh.clone()).unwrap();
+        let cache = RateCache::read_from(&path).unwrap();
+        let source = RateSource::Exchange(Exchange::Kraken);
+        assert_eq!(cache.rates.len(), 2);
+        assert_eq!(cache.get(Fiat::EUR, &source), Some(&eur));
+        assert_eq!(cache.get(Fiat::USD, &source), Some(&fresh));
+        assert!(!cache.get(Fiat::USD, &source).unwrap().is_stale(10));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unreadable() {
+        let path = cache_path("unreadable");
+        assert_eq!(RateCache::read_from(&path).unwrap(), RateCache::default());
+        fs::write(&path, "{broken").unwrap();
+        let usd = rate(Fiat::USD, Exchange::Kraken, 25000.0, 0);
+        assert!(RateCache::update_at(&path, usd).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{broken");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io};
+
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use gtk::glib;
+use serde_crate::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::model::sidecar::{self, Error};
+use crate::model::Fiat;
+use crate::worker::proxy::{http_agent, Socks5};
+
+/// Maximal number of days returned by a single historical price request.
+const MAX_DAYS_PER_REQUEST: i64 = 2000;
+
+/// Daily bitcoin price candle.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub struct Ohlc {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+/// Daily bitcoin prices in a fiat currency, which are cached on disk and shared
+/// by all wallets.
+#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct PriceHistory {
+    /// Daily candles by the UTC date.
+    pub days: BTreeMap<NaiveDate, Ohlc>,
+
+    /// Earliest date for which the API provides prices, once it is known;
+    /// earlier days are never requested again.
+    pub available_since: Option<NaiveDate>,
+}
+
+impl PriceHistory {
+    pub fn path(fiat: Fiat) -> PathBuf {
+        glib::user_cache_dir()
+            .join("mycitadel")
+            .join(format!("prices-{}.json", fiat.fiat().to_lowercase()))
+    }
+
+    /// Reads cached prices; returns empty history if there is no cache.
+    pub fn read(fiat: Fiat) -> Result<Self, Error> {
+        match fs::File::open(Self::path(fiat)) {
+            Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self, fiat: Fiat) -> Result<(), Error> {
+        sidecar::write_atomic(&Self::path(fiat), self)
+    }
+
+    pub fn is_empty(&self) -> bool { self.days.is_empty() }
+
+    /// Returns closing price of the day containing the given UNIX timestamp.
+    pub fn price_at(&self, timestamp: i64) -> Option<f64> {
+        let date = NaiveDateTime::from_timestamp_opt(timestamp, 0)?.date();
+        self.days.get(&date).map(|ohlc| ohlc.close)
+    }
+
+    /// Returns date ranges which are absent in the history and must be fetched
+    /// to cover all days from `since` till today.
+    ///
+    /// The last cached day is always fetched again, since its candle might
+    /// have been incomplete when cached. Days before the earliest date with
+    /// prices available from the API are not reported.
+    pub fn missing(&self, since: NaiveDate) -> Vec<(NaiveDate, NaiveDate)> {
+        let today = Utc::now().naive_utc().date();
+        let since = self.available_since.map_or(since, |date| since.max(date));
+        let (first, last) = match (self.days.keys().next(), self.days.keys().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ if since > today => return vec![],
+            _ => return vec![(since, today)],
+        };
+        let mut missing = vec![];
+        if since < first {
+            missing.push((since, first.pred_opt().unwrap_or(first)));
+        }
+        if last < today {
+            missing.push((last, today));
+        }
+        missing
+    }
+
+    /// Fetches daily prices for the inclusive range of dates from the public
+    /// API and adds them to the history.
+    pub fn fetch(
+        &mut self,
+        fiat: Fiat,
+        from: NaiveDate,
+        till: NaiveDate,
+        proxy: Option<&Socks5>,
+    ) -> Result<(), String> {
+        let last = till;
+        let mut till = till;
+        while till >= from {
+            let days = till
+                .signed_duration_since(from)
+                .num_days()
+                .min(MAX_DAYS_PER_REQUEST - 1);
+            let candles = fetch_candles(fiat, till, days, proxy)?;
+            let earliest = match candles.keys().next() {
+                Some(earliest) if *earliest <= till => *earliest,
+                _ => {
+                    self.exhausted(last);
+                    break;
+                }
+            };
+            self.days.extend(candles);
+            if earliest > till - chrono::Duration::days(days) {
+                self.exhausted(last);
+                break;
+            }
+            till = match earliest.pred_opt() {
+                Some(date) => date,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+
+    /// Records that the API has no prices for dates before the ones already
+    /// known, or up to and including `till` if no prices are known.
+    fn exhausted(&mut self, till: NaiveDate) {
+        self.available_since = self.days.keys().next().copied().or_else(|| till.succ_opt());
+    }
+}
+
+/// Requests `days + 1` daily candles ending at `till` date from CryptoCompare,
+/// which provides history for all supported fiat currencies.
+fn fetch_candles(
+    fiat: Fiat,
+    till: NaiveDate,
+    days: i64,
+    proxy: Option<&Socks5>,
+) -> Result<BTreeMap<NaiveDate, Ohlc>, String> {
+    let timestamp = till
+        .and_hms_opt(23, 59, 59)
+        .expect("valid time")
+        .timestamp();
+    let url = format!(
+        "https://min-api.cryptocompare.com/data/v2/histoday?fsym=BTC&tsym={}&limit={}&toTs={}",
+        fiat.fiat(),
+        days,
+        timestamp
+    );
+    let data: Value = http_agent(&url, proxy, Duration::from_secs(60))
+        .map_err(|err| err.to_string())?
+        .get(&url)
+        .call()
+        .map_err(|err| err.to_string())?
+        .into_json()
+        .map_err(|err| err.to_string())?;
+    if data.get("Response").and_then(Value::as_str) != Some("Success") {
+        return Err(data
+            .get("Message")
+            .and_then(Value::as_str)
+            .unwrap_or("unrecognized price history API response")
+            .to_owned());
+    }
+    let candles = data
+        .get("Data")
+        .and_then(|data| data.get("Data"))
+        .and_then(Value::as_array)
+        .ok_or_else(|| s!("unrecognized price history API response"))?;
+    Ok(candles
+        .iter()
+        .filter_map(|candle| {
+            let date = NaiveDateTime::from_timestamp_opt(candle.get("time")?.as_i64()?, 0)?.date();
+            let ohlc = Ohlc {
+                open: candle.get("open")?.as_f64()?,
+                high: candle.get("high")?.as_f64()?,
+                low: candle.get("low")?.as_f64()?,
+                close: candle.get("close")?.as_f64()?,
+            };
+            // Days before the currency was traded are returned with zero prices
+            (ohlc.close > 0.0).then(|| (date, ohlc))
+        })
+        .collect())
+}
@@ -0,0 +1,645 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+mod cache;
+mod history;
+mod providers;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{io, thread};
+
+use chrono::{NaiveDate, Utc};
+use relm::Sender;
+use serde_crate::{Deserialize, Serialize};
+
+pub use self::cache::{Rate, RateCache};
+pub use self::history::{Ohlc, PriceHistory};
+pub use self::providers::{Bitfinex, Bitstamp, CoinGecko, Coinbase, Kraken, RateProvider};
+use super::proxy::{http_agent, Socks5};
+use crate::model::{sidecar, Exchange, Fiat, RateSource};
+
+/// Exchange quotes from which the rate was computed.
+#[derive(Clone, PartialEq, Debug, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "serde_crate", default)]
+pub struct Quotes {
+    /// Quotes which contributed to the rate.
+    pub used: BTreeMap<Exchange, f64>,
+
+    /// Quotes rejected as outliers.
+    pub outliers: BTreeMap<Exchange, f64>,
+
+    /// Exchanges which failed to provide a quote, with the failure reason.
+    pub failed: BTreeMap<Exchange, String>,
+}
+
+impl Quotes {
+    /// Difference between the highest and the lowest of the used quotes,
+    /// relative to the rate, in percent.
+    pub fn spread(&self, rate: f64) -> f64 {
+        if self.used.is_empty() || rate <= 0.0 {
+            return 0.0;
+        }
+        let max = self.used.values().copied().fold(f64::MIN, f64::max);
+        let min = self.used.values().copied().fold(f64::MAX, f64::min);
+        (max - min) / rate * 100.0
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+enum Cmd {
+    Refresh,
+    SetSource(RateSource),
+    SetFiat(Fiat),
+    SetOffline(bool),
+    History(NaiveDate),
+    Shutdown,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Msg {
+    Rate(Rate),
+    History(Fiat, PriceHistory),
+    Error(String),
+}
+
+pub struct ExchangeWorker {
+    worker_thread: Option<JoinHandle<()>>,
+    watcher_thread: Option<JoinHandle<()>>,
+    watcher_stop: Option<mpsc::Sender<()>>,
+    tx: mpsc::Sender<Cmd>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ExchangeWorker {
+    pub fn with(
+        sender: Sender<Msg>,
+        mut source: RateSource,
+        mut fiat: Fiat,
+        mut offline: bool,
+        wallet_id: &str,
+        interval: u64,
+    ) -> Result<Self, io::Error> {
+        let (tx, rx) = mpsc::channel::<Cmd>();
+        // Exchanges must not be able to link wallets by their Tor circuits
+        let isolation_key = format!("exchange-{}", wallet_id);
+        // Commands queued before the shutdown are skipped
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let worker_thread = thread::Builder::new().name(s!("exchange")).spawn(move || {
+            // Price history is not loaded until the wallet requests it
+            let mut since = None;
+            let mut history = PriceHistory::default();
+            // Last known rate is shown until the rate is retrieved
+            cached_rate(&source, fiat, &sender);
+            loop {
+                let cmd = rx.recv();
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let res = match cmd {
+                    // Wallets in the offline mode never access the network; the cached rate
+                    // is sent again so the wallet re-checks whether it is outdated
+                    Ok(Cmd::Refresh) if offline => {
+                        cached_rate(&source, fiat, &sender);
+                        Ok(())
+                    }
+                    Ok(Cmd::Refresh) => exchange_refresh(&source, fiat, &isolation_key, &sender)
+                        .and_then(|_| match since {
+                            Some(since) if !history.missing(since).is_empty() => history_update(
+                                &mut history,
+                                fiat,
+                                since,
+                                offline,
+                                &isolation_key,
+                                &sender,
+                            ),
+                            _ => Ok(()),
+                        }),
+                    Ok(Cmd::SetSource(s)) => {
+                        source = s;
+                        cached_rate(&source, fiat, &sender);
+                        match offline {
+                            true => Ok(()),
+                            false => exchange_refresh(&source, fiat, &isolation_key, &sender),
+                        }
+                    }
+                    Ok(Cmd::SetFiat(f)) => {
+                        fiat = f;
+                        cached_rate(&source, fiat, &sender);
+                        if let Some(since) = since {
+                            history = read_history(fiat, &sender);
+                            let _ = history_update(
+                                &mut history,
+                                fiat,
+                                since,
+                                offline,
+                                &isolation_key,
+                                &sender,
+                            );
+                        }
+                        match offline {
+                            true => Ok(()),
+                            false => exchange_refresh(&source, fiat, &isolation_key, &sender),
+                        }
+                    }
+                    Ok(Cmd::SetOffline(o)) => {
+                        offline = o;
+                        match offline {
+                            true => Ok(()),
+                            false => exchange_refresh(&source, fiat, &isolation_key, &sender),
+                        }
+                    }
+                    Ok(Cmd::History(date)) => {
+                        if since.is_none() {
+                            history = read_history(fiat, &sender);
+                        }
+                        since = Some(date);
+                        history_update(&mut history, fiat, date, offline, &isolation_key, &sender)
+                    }
+                    Ok(Cmd::Shutdown) | Err(_) => break,
+                };
+                if let Err(err) = res {
+                    // The wallet was closed
+                    if sender.send(Msg::Error(err)).is_err() {
+                        break;
+                    }
+                }
+            }
+        })?;
+
+        let sender = tx.clone();
+        let (watcher_stop, watcher_stopped) = mpsc::channel::<()>();
+        let watcher_thread = thread::Builder::new()
+            .name(s!("xchng-timer"))
+            .spawn(move || {
+                // Runs until the worker drops the stop channel or terminates
+                while watcher_stopped.recv_timeout(Duration::from_secs(interval))
+                    == Err(mpsc::RecvTimeoutError::Timeout)
+                {
+                    if sender.send(Cmd::Refresh).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("unable to start exchange rate refreshing thread");
+
+        Ok(ExchangeWorker {
+            tx,
+            worker_thread: Some(worker_thread),
+            watcher_thread: Some(watcher_thread),
+            watcher_stop: Some(watcher_stop),
+            stop,
+        })
+    }
+
+    /// Stops exchange rate updates. The worker threads are not waited for, so
+    /// the UI is not blocked by a pending request; they terminate once it is
+    /// complete and are joined when the worker is dropped. Commands sent after
+    /// the shutdown are ignored.
+    pub fn shutdown(&mut self) {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.watcher_stop = None;
+        let _ = self.tx.send(Cmd::Shutdown);
+    }
+
+    pub fn refresh(&self) { self.cmd(Cmd::Refresh) }
+
+    pub fn set_source(&self, source: RateSource) { self.cmd(Cmd::SetSource(source)) }
+
+    pub fn set_fiat(&self, fiat: Fiat) { self.cmd(Cmd::SetFiat(fiat)) }
+
+    pub fn set_offline(&self, offline: bool) { self.cmd(Cmd::SetOffline(offline)) }
+
+    /// Requests daily prices for all days starting from the given date. The
+    /// prices are updated once a day afterwards.
+    pub fn fetch_history(&self, since: NaiveDate) { self.cmd(Cmd::History(since)) }
+
+    fn cmd(&self, cmd: Cmd) {
+        if !self.stop.load(Ordering::SeqCst) {
+            self.tx.send(cmd).expect("Exchange thread is dead")
+        }
+    }
+}
+
+impl Drop for ExchangeWorker {
+    fn drop(&mut self) {
+        self.shutdown();
+        for thread in [self.watcher_thread.take(), self.worker_thread.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Retrieves exchange rate from the rate source and saves it to the cache.
+fn exchange_refresh(
+    source: &RateSource,
+    fiat: Fiat,
+    isolation_key: &str,
+    sender: &Sender<Msg>,
+) -> Result<(), String> {
+    let proxy = Socks5::configured(isolation_key).map_err(|err| err.to_string())?;
+    let (rate, quotes) = match source {
+        RateSource::Exchange(exchange) => single_rate(*exchange, fiat, proxy.as_ref())?,
+        RateSource::Median {
+            exchanges,
+            max_deviation,
+        } => median_rate(exchanges, *max_deviation, fiat, proxy.as_ref())?,
+    };
+    let rate = Rate {
+        fiat,
+        source: source.clone(),
+        rate,
+        quotes,
+        time: Utc::now(),
+    };
+    let cached = RateCache::update(rate.clone());
+    sender
+        .send(Msg::Rate(rate))
+        .map_err(|err| err.to_string())?;
+    cached.map_err(|err| format!("Unable to cache exchange rate: {err}"))
+}
+
+/// Sends the last known rate from the cache, if there is one, or reports
+/// failure to read the cache.
+fn cached_rate(source: &RateSource, fiat: Fiat, sender: &Sender<Msg>) {
+    if let Some(msg) = cached_rate_msg(RateCache::read(), source, fiat) {
+        let _ = sender.send(msg);
+    }
+}
+
+fn cached_rate_msg(
+    cache: Result<RateCache, sidecar::Error>,
+    source: &RateSource,
+    fiat: Fiat,
+) -> Option<Msg> {
+    match cache {
+        Ok(cache) => cache.get(fiat, source).cloned().map(Msg::Rate),
+        Err(err) => Some(Msg::Error(format!(
+            "Unable to read cached exchange rates: {err}"
+        ))),
+    }
+}
+
+/// Reads cached price history, reporting failure to the wallet; the history
+/// is fetched again in this case.
+fn read_history(fiat: Fiat, sender: &Sender<Msg>) -> PriceHistory {
+    PriceHistory::read(fiat).unwrap_or_else(|err| {
+        let _ = sender.send(Msg::Error(format!(
+            "Unable to read cached price history: {err}"
+        )));
+        PriceHistory::default()
+    })
+}
+
+/// Retrieves exchange rate from the selected exchange, falling back to other
+/// exchanges if it fails or does not support the currency.
+fn single_rate(
+    exchange: Exchange,
+    fiat: Fiat,
+    proxy: Option<&Socks5>,
+) -> Result<(f64, Quotes), String> {
+    let mut errors = vec![];
+    for exchange in exchange.with_fallbacks() {
+        let provider = exchange.provider();
+        if !provider.supports(fiat) {
+            continue;
+        }
+        match fetch_rate(provider, fiat, proxy) {
+            Ok(rate) => {
+                let quotes = Quotes {
+                    used: bmap! { exchange => rate },
+                    ..default!()
+                };
+                return Ok((rate, quotes));
+            }
+            Err(err) => errors.push(format!("{}: {}", exchange, err)),
+        }
+    }
+    if errors.is_empty() {
+        return Err(format!("no exchange provides {} rate", fiat.pair()));
+    }
+    Err(errors.join("; "))
+}
+
+/// Fetches days missing in the price history, unless the wallet is offline,
+/// updates the cache on disk and sends the history to the wallet.
+fn history_update(
+    history: &mut PriceHistory,
+    fiat: Fiat,
+    since: NaiveDate,
+    offline: bool,
+    isolation_key: &str,
+    sender: &Sender<Msg>,
+) -> Result<(), String> {
+    let missing = history.missing(since);
+    if !offline && !missing.is_empty() {
+        // Cached history is still sent to the wallet if the proxy is unknown
+        let proxy = Socks5::configured(isolation_key).map_err(|err| err.to_string());
+        for (from, till) in missing {
+            let res = proxy
+                .as_ref()
+                .map_err(String::clone)
+                .and_then(|proxy| history.fetch(fiat, from, till, proxy.as_ref()));
+            if let Err(err) = res {
+                sender
+                    .send(Msg::Error(format!(
+                        "Unable to retrieve {} price history: {}",
+                        fiat, err
+                    )))
+                    .map_err(|err| err.to_string())?;
+                break;
+            }
+        }
+        if let Err(err) = history.write(fiat) {
+            sender
+                .send(Msg::Error(format!("Unable to cache price history: {err}")))
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    sender
+        .send(Msg::History(fiat, history.clone()))
+        .map_err(|err| err.to_string())
+}
+
+/// Retrieves exchange rates from all exchanges in parallel and computes their
+/// median, ignoring outliers.
+fn median_rate(
+    exchanges: &BTreeSet<Exchange>,
+    max_deviation: u16,
+    fiat: Fiat,
+    proxy: Option<&Socks5>,
+) -> Result<(f64, Quotes), String> {
+    let exchanges = match exchanges.is_empty() {
+        true => Exchange::ALL.into_iter().collect(),
+        false => exchanges.clone(),
+    };
+    let requests = exchanges
+        .into_iter()
+        .filter(|exchange| exchange.provider().supports(fiat))
+        .map(|exchange| {
+            let proxy = proxy.cloned();
+            let request = thread::Builder::new()
+                .name(format!("xchng-{}", exchange).to_lowercase())
+                .spawn(move || fetch_rate(exchange.provider(), fiat, proxy.as_ref()));
+            (exchange, request)
+        })
+        .collect::<Vec<_>>();
+
+    let mut quotes = Quotes::default();
+    let mut rates = bmap! {};
+    for (exchange, request) in requests {
+        let result = request.map_err(|err| err.to_string()).and_then(|thread| {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(s!("request thread has panicked")))
+        });
+        match result {
+            Ok(rate) => {
+                rates.insert(exchange, rate);
+            }
+            Err(err) => {
+                quotes.failed.insert(exchange, err);
+            }
+        }
+    }
+    combine_quotes(rates, quotes, max_deviation, fiat)
+}
+
+/// Computes median of the exchange rates, excluding the outliers which deviate
+/// from the median of all rates by more than `max_deviation` basis points.
+/// Quotes of the exchanges which have failed are expected to be already
+/// listed in `quotes`.
+fn combine_quotes(
+    rates: BTreeMap<Exchange, f64>,
+    mut quotes: Quotes,
+    max_deviation: u16,
+    fiat: Fiat,
+) -> Result<(f64, Quotes), String> {
+    let center = match median(rates.values().copied()) {
+        Some(center) => center,
+        None if quotes.failed.is_empty() => {
+            return Err(format!("no exchange provides {} rate", fiat.pair()))
+        }
+        None => {
+            return Err(quotes
+                .failed
+                .iter()
+                .map(|(exchange, err)| format!("{}: {}", exchange, err))
+                .collect::<Vec<_>>()
+                .join("; "))
+        }
+    };
+    for (exchange, rate) in rates {
+        let deviation = (rate - center).abs() / center * 10_000.0;
+        match deviation > max_deviation as f64 {
+            true => quotes.outliers.insert(exchange, rate),
+            false => quotes.used.insert(exchange, rate),
+        };
+    }
+    // With an even number of quotes the median lies between two of them, which
+    // both may be rejected
+    let rate = median(quotes.used.values().copied()).unwrap_or(center);
+    Ok((rate, quotes))
+}
+
+fn median(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut values = values.collect::<Vec<_>>();
+    values.sort_by(|a, b| a.partial_cmp(b).expect("exchange rates are finite numbers"));
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((values[mid - 1] + values[mid]) / 2.0),
+        _ => Some(values[mid]),
+    }
+}
+
+fn fetch_rate(
+    provider: &dyn RateProvider,
+    fiat: Fiat,
+    proxy: Option<&Socks5>,
+) -> Result<f64, String> {
+    let url = provider.url(fiat);
+    let data: serde_json::Value = http_agent(&url, proxy, Duration::from_secs(30))
+        .map_err(|err| err.to_string())?
+        .get(&url)
+        .call()
+        .map_err(|err| err.to_string())?
+        .into_json()
+        .map_err(|err| err.to_string())?;
+    provider
+        .parse_rate(fiat, &data)
+        .ok_or_else(|| s!("unrecognized exchange response API"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+
+    use super::*;
+
+    fn rate(fiat: Fiat, exchange: Exchange, minutes_ago: i64) -> Rate {
+        Rate {
+            fiat,
+            source: RateSource::Exchange(exchange),
+            rate: 25000.0,
+            quotes: default!(),
+            time: Utc::now() - chrono::Duration::minutes(minutes_ago),
+        }
+    }
+
+    fn combine(rates: &[(Exchange, f64)], max_deviation: u16) -> (f64, Quotes) {
+        let rates = rates.iter().copied().collect();
+        combine_quotes(rates, Quotes::default(), max_deviation, Fiat::USD).unwrap()
+    }
+
+    #[test]
+    fn median_of_values() {
+        assert_eq!(median([3.0, 1.0, 2.0].into_iter()), Some(2.0));
+        assert_eq!(median([4.0, 1.0, 3.0, 2.0].into_iter()), Some(2.5));
+        assert_eq!(median([5.0].into_iter()), Some(5.0));
+        assert_eq!(median(iter::empty()), None);
+    }
+
+    #[test]
+    fn odd_number_of_quotes() {
+        let (rate, quotes) = combine(
+            &[
+                (Exchange::Kraken, 101.0),
+                (Exchange::Bitstamp, 99.0),
+                (Exchange::Coinbase, 100.0),
+            ],
+            200,
+        );
+        assert_eq!(rate, 100.0);
+        assert_eq!(quotes.used.len(), 3);
+        assert!(quotes.outliers.is_empty());
+    }
+
+    #[test]
+    fn even_number_of_quotes() {
+        let (rate, quotes) = combine(
+            &[
+                (Exchange::Kraken, 101.0),
+                (Exchange::Bitstamp, 99.0),
+                (Exchange::Coinbase, 100.0),
+                (Exchange::Bitfinex, 102.0),
+            ],
+            200,
+        );
+        assert_eq!(rate, 100.5);
+        assert_eq!(quotes.used.len(), 4);
+    }
+
+    #[test]
+    fn outliers() {
+        // Deviations from the median of 100 are 200 and 300 basis points
+        let (rate, quotes) = combine(
+            &[
+                (Exchange::Kraken, 100.0),
+                (Exchange::Bitstamp, 102.0),
+                (Exchange::Coinbase, 97.0),
+            ],
+            200,
+        );
+        assert_eq!(rate, 101.0);
+        assert_eq!(
+            quotes.used,
+            bmap! { Exchange::Kraken => 100.0, Exchange::Bitstamp => 102.0 }
+        );
+        assert_eq!(quotes.outliers, bmap! { Exchange::Coinbase => 97.0 });
+    }
+
+    #[test]
+    fn middle_quotes_rejected() {
+        // The median of 105 deviates from each of the quotes by more than 4%
+        let (rate, quotes) = combine(
+            &[
+                (Exchange::Kraken, 90.0),
+                (Exchange::Bitstamp, 100.0),
+                (Exchange::Coinbase, 110.0),
+                (Exchange::Bitfinex, 120.0),
+            ],
+            400,
+        );
+        assert_eq!(rate, 105.0);
+        assert!(quotes.used.is_empty());
+        assert_eq!(quotes.outliers.len(), 4);
+    }
+
+    #[test]
+    fn failed_exchanges() {
+        let quotes = Quotes {
+            failed: bmap! {
+                Exchange::Kraken => s!("timeout"),
+                Exchange::Bitstamp => s!("connection refused")
+            },
+            ..default!()
+        };
+        let (rate, quotes) = combine_quotes(
+            bmap! { Exchange::Coinbase => 100.0 },
+            quotes,
+            200,
+            Fiat::USD,
+        )
+        .unwrap();
+        assert_eq!(rate, 100.0);
+        assert_eq!(quotes.failed.len(), 2);
+
+        let quotes = Quotes {
+            failed: bmap! {
+                Exchange::Kraken => s!("timeout"),
+                Exchange::Bitstamp => s!("connection refused")
+            },
+            ..default!()
+        };
+        assert_eq!(
+            combine_quotes(bmap! {}, quotes, 200, Fiat::USD),
+            Err(s!("Kraken: timeout; Bitstamp: connection refused"))
+        );
+        assert!(combine_quotes(bmap! {}, Quotes::default(), 200, Fiat::USD).is_err());
+    }
+
+    #[test]
+    fn offline_recheck() {
+        // Offline wallets receive the cached rate as it is, so the rate gets
+        // stale with time
+        let cached = rate(Fiat::USD, Exchange::Kraken, 90);
+        let mut cache = RateCache::default();
+        cache.insert(cached.clone());
+        let source = RateSource::Exchange(Exchange::Kraken);
+        match cached_rate_msg(Ok(cache.clone()), &source, Fiat::USD) {
+            Some(Msg::Rate(rate)) => {
+                assert_eq!(rate, cached);
+                assert!(rate.is_stale(60));
+                assert!(!rate.is_stale(120));
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert_eq!(cached_rate_msg(Ok(cache.clone()), &source, Fiat::EUR), None);
+        let source = RateSource::Exchange(Exchange::Bitstamp);
+        assert_eq!(cached_rate_msg(Ok(cache), &source, Fiat::USD), None);
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(matches!(
+            cached_rate_msg(Err(err.into()), &source, Fiat::USD),
+            Some(Msg::Error(_))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use serde_json::Value;
+
+use crate::model::{Exchange, Fiat};
+
+/// Public API providing bitcoin exchange rates.
+pub trait RateProvider {
+    /// Checks whether the provider quotes bitcoin price in the currency.
+    fn supports(&self, _fiat: Fiat) -> bool { true }
+
+    /// URL of the API endpoint returning bitcoin price in the currency.
+    fn url(&self, fiat: Fiat) -> String;
+
+    /// Extracts bitcoin price from the API response; returns `None` if the
+    /// response has an unexpected format.
+    fn parse_rate(&self, fiat: Fiat, data: &Value) -> Option<f64>;
+}
+
+impl Exchange {
+    /// API provider of the exchange.
+    pub fn provider(self) -> &'static dyn RateProvider {
+        match self {
+            Exchange::Kraken => &Kraken,
+            Exchange::Bitstamp => &Bitstamp,
+            Exchange::Coinbase => &Coinbase,
+            Exchange::CoinGecko => &CoinGecko,
+            Exchange::Bitfinex => &Bitfinex,
+        }
+    }
+}
+
+pub struct Kraken;
+pub struct Bitstamp;
+pub struct Coinbase;
+pub struct CoinGecko;
+pub struct Bitfinex;
+
+impl RateProvider for Kraken {
+    fn supports(&self, fiat: Fiat) -> bool {
+        matches!(
+            fiat,
+            Fiat::USD | Fiat::EUR | Fiat::CHF | Fiat::GBP | Fiat::JPY | Fiat::CAD | Fiat::AUD
+        )
+    }
+
+    fn url(&self, fiat: Fiat) -> String {
+        format!(
+            "https://api.kraken.com/0/public/Ticker?pair=XBT{}",
+            fiat.fiat()
+        )
+    }
+
+    fn parse_rate(&self, _fiat: Fiat, data: &Value) -> Option<f64> {
+        // Kraken uses its own pair names in the response, like `XXBTZUSD`,
+        // but there is always a single pair for a single pair request
+        let ticker = data.get("result")?.as_object()?.values().next()?;
+        number(ticker.get("c")?.get(0)?)
+    }
+}
+
+impl RateProvider for Bitstamp {
+    fn supports(&self, fiat: Fiat) -> bool { matches!(fiat, Fiat::USD | Fiat::EUR | Fiat::GBP) }
+
+    fn url(&self, fiat: Fiat) -> String {
+        format!(
+            "https://www.bitstamp.net/api/v2/ticker/btc{}/",
+            fiat.fiat().to_lowercase()
+        )
+    }
+
+    fn parse_rate(&self, _fiat: Fiat, data: &Value) -> Option<f64> { number(data.get("last")?) }
+}
+
+impl RateProvider for Coinbase {
+    fn url(&self, fiat: Fiat) -> String {
+        format!(
+            "https://api.coinbase.com/v2/prices/BTC-{}/spot",
+            fiat.fiat()
+        )
+    }
+
+    fn parse_rate(&self, _fiat: Fiat, data: &Value) -> Option<f64> {
+        number(data.get("data")?.get("amount")?)
+    }
+}
+
+impl RateProvider for CoinGecko {
+    fn url(&self, fiat: Fiat) -> String {
+        format!(
+            "https://api.coingecko.com/api/v3/simple/price?ids=bitcoin&vs_currencies={}",
+            fiat.fiat().to_lowercase()
+        )
+    }
+
+    fn parse_rate(&self, fiat: Fiat, data: &Value) -> Option<f64> {
+        number(data.get("bitcoin")?.get(fiat.fiat().to_lowercase())?)
+    }
+}
+
+impl RateProvider for Bitfinex {
+    fn supports(&self, fiat: Fiat) -> bool {
+        matches!(fiat, Fiat::USD | Fiat::EUR | Fiat::GBP | Fiat::JPY)
+    }
+
+    fn url(&self, fiat: Fiat) -> String {
+        format!("https://api-pub.bitfinex.com/v2/ticker/tBTC{}", fiat.fiat())
+    }
+
+    fn parse_rate(&self, _fiat: Fiat, data: &Value) -> Option<f64> {
+        // Ticker is an array, where the seventh element is the last price
+        number(data.get(6)?)
+    }
+}
+
+/// Reads a number which APIs provide either as a JSON number or as a string.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .filter(|rate| rate.is_finite() && *rate > 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(provider: &dyn RateProvider, fiat: Fiat, json: &str) -> Option<f64> {
+        provider.parse_rate(fiat, &serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn kraken() {
+        let json = r#"{"error":[],"result":{"XXBTZEUR":{
+            "a":["26001.50000","1","1.000"],"b":["26001.40000","2","2.000"],
+            "c":["26001.50000","0.00100000"],"v":["512.1","1400.2"],
+            "p":["26010.1","25980.3"],"t":[5120,14002],"l":["25800.0","25700.1"],
+            "h":["26200.0","26300.0"],"o":"25950.20000"}}}"#;
+        assert_eq!(parse(&Kraken, Fiat::EUR, json), Some(26001.5));
+        assert_eq!(
+            parse(
+                &Kraken,
+                Fiat::EUR,
+                r#"{"error":["EQuery:Unknown asset pair"]}"#
+            ),
+            None
+        );
+        assert!(Kraken.supports(Fiat::CHF));
+        assert!(!Kraken.supports(Fiat::CZK));
+    }
+
+    #[test]
+    fn bitstamp() {
+        let json = r#"{"timestamp":"1694000000","open":"25700","high":"26100","low":"25650",
+            "last":"25812","volume":"1530.31830271","vwap":"25800","bid":"25811",
+            "ask":"25813","open_24":"25740","percent_change_24":"0.28"}"#;
+        assert_eq!(parse(&Bitstamp, Fiat::USD, json), Some(25812.0));
+        assert_eq!(parse(&Bitstamp, Fiat::USD, r#"{"last":"0"}"#), None);
+        assert_eq!(parse(&Bitstamp, Fiat::USD, r#"{"last":"NaN"}"#), None);
+        assert_eq!(parse(&Bitstamp, Fiat::USD, r#"{"last":"inf"}"#), None);
+        assert!(!Bitstamp.supports(Fiat::JPY));
+    }
+
+    #[test]
+    fn coinbase() {
+        let json = r#"{"data":{"amount":"21830.995","base":"BTC","currency":"GBP"}}"#;
+        assert_eq!(parse(&Coinbase, Fiat::GBP, json), Some(21830.995));
+        let json = r#"{"errors":[{"id":"not_found","message":"Invalid currency"}]}"#;
+        assert_eq!(parse(&Coinbase, Fiat::GBP, json), None);
+    }
+
+    #[test]
+    fn coingecko() {
+        let json = r#"{"bitcoin":{"chf":23105.42}}"#;
+        assert_eq!(parse(&CoinGecko, Fiat::CHF, json), Some(23105.42));
+        assert_eq!(parse(&CoinGecko, Fiat::EUR, json), None);
+        assert_eq!(parse(&CoinGecko, Fiat::CHF, r#"{"bitcoin":{}}"#), None);
+    }
+
+    #[test]
+    fn bitfinex() {
+        let json = r#"[25810,8.1,25811,9.4,-95,-0.0037,25812,1321.5,26100,25650]"#;
+        assert_eq!(parse(&Bitfinex, Fiat::USD, json), Some(25812.0));
+        assert_eq!(
+            parse(&Bitfinex, Fiat::USD, r#"["error",10020,"symbol: invalid"]"#),
+            None
+        );
+        assert_eq!(parse(&Bitfinex, Fiat::USD, r#"[25810,8.1]"#), None);
+    }
+}
@@ -9,8 +9,12 @@
 // a copy of the AGPL-3.0 License along with this software. If not, see
 // <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
 
+pub mod backend;
 pub mod electrum;
 pub mod exchange;
+pub mod proxy;
 
+pub use backend::{ChainBackend, ElectrumBackend};
 pub use electrum::ElectrumWorker;
 pub use exchange::ExchangeWorker;
+pub use proxy::{ProxyError, Socks5};
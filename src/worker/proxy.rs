@@ -0,0 +1,153 @@
+// MyCitadel desktop wallet: bitcoin & RGB wallet based on GTK framework.
+//
+// Written in 2022 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoraprime.ch>
+//
+// Copyright (C) 2022 by Pandora Prime SA, Switzerland.
+//
+// This software is distributed without any warranty. You should have received
+// a copy of the AGPL-3.0 License along with this software. If not, see
+// <https://www.gnu.org/licenses/agpl-3.0-standalone.html>.
+
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use electrum_client::Socks5Config;
+use socks::Socks5Stream;
+
+use crate::model::{sidecar, AppConfig, ProxyConfig};
+
+/// Tor does not check SOCKS5 credentials, it only isolates streams by them, so
+/// the password is the same for all isolation groups.
+const ISOLATION_PASSWORD: &str = "mycitadel";
+
+/// Application settings can't be read, so it is unknown whether connections
+/// must go through the proxy. Such connections are refused instead of being
+/// made directly, which could reveal the user IP address.
+#[derive(Debug, Display, Error)]
+#[display("unable to read proxy settings, network connections are disabled: {0}")]
+pub struct ProxyError(sidecar::Error);
+
+/// SOCKS5 proxy to use for a network connection.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Socks5 {
+    addr: String,
+    /// User name identifying the stream isolation group; `None` if streams
+    /// are not isolated.
+    isolation: Option<String>,
+}
+
+impl Socks5 {
+    /// Constructs proxy from the application settings; returns `None` if the
+    /// proxy is disabled. Connections sharing the same `isolation_key` use the
+    /// same Tor circuits when stream isolation is enabled.
+    pub fn with(config: &ProxyConfig, isolation_key: &str) -> Option<Socks5> {
+        if !config.enabled {
+            return None;
+        }
+        Some(Socks5 {
+            addr: config.addr(),
+            isolation: if config.isolation {
+                Some(isolation_key.to_owned())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Reads the proxy from the current application settings. Fails if the
+    /// settings can't be read, so the caller never connects directly when
+    /// the user has configured a proxy.
+    pub fn configured(isolation_key: &str) -> Result<Option<Socks5>, ProxyError> {
+        let config = AppConfig::read().map_err(ProxyError)?;
+        Ok(Socks5::with(&config.proxy, isolation_key))
+    }
+
+    pub fn electrum_config(&self) -> Socks5Config {
+        match self.isolation {
+            Some(ref user) => Socks5Config::with_credentials(
+                &self.addr,
+                user.clone(),
+                ISOLATION_PASSWORD.to_owned(),
+            ),
+            None => Socks5Config::new(&self.addr),
+        }
+    }
+
+    /// Opens TCP connection to the host through the proxy.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let stream = match self.isolation {
+            Some(ref user) => Socks5Stream::connect_with_password(
+                &self.addr,
+                (host, port),
+                user,
+                ISOLATION_PASSWORD,
+            )?,
+            None => Socks5Stream::connect(&self.addr, (host, port))?,
+        };
+        Ok(stream.into_inner())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn ureq_proxy(&self) -> Result<ureq::Proxy, ureq::Error> {
+        let url = match self.isolation {
+            Some(ref user) => {
+                format!(
+                    "socks5://{}:{}@{}",
+                    urlencoding::encode(user),
+                    ISOLATION_PASSWORD,
+                    self.addr
+                )
+            }
+            None => format!("socks5://{}", self.addr),
+        };
+        ureq::Proxy::new(url)
+    }
+}
+
+/// Returns the proxy which has to be used for connecting to the given URL.
+/// Connections to the local host are always direct, since Tor refuses to
+/// connect to loopback addresses.
+pub fn proxy_for<'proxy>(url: &str, proxy: Option<&'proxy Socks5>) -> Option<&'proxy Socks5> {
+    let host = url_host(url);
+    if host == "localhost" || host == "[::1]" || host.starts_with("127.") {
+        return None;
+    }
+    proxy
+}
+
+/// Checks whether the URL points to a Tor onion service.
+pub fn is_onion(url: &str) -> bool { url_host(url).ends_with(".onion") }
+
+/// Constructs HTTP agent which routes requests through the proxy, if any.
+#[allow(clippy::result_large_err)]
+pub fn http_agent(
+    url: &str,
+    proxy: Option<&Socks5>,
+    timeout: Duration,
+) -> Result<ureq::Agent, ureq::Error> {
+    let mut builder = ureq::AgentBuilder::new().timeout(timeout);
+    if let Some(proxy) = proxy_for(url, proxy) {
+        builder = builder.proxy(proxy.ureq_proxy()?);
+    }
+    Ok(builder.build())
+}
+
+fn url_host(url: &str) -> &str {
+    let authority = url
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(url)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map(|(_, host)| host)
+        .unwrap_or(authority);
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}